@@ -1,9 +1,24 @@
 fn main() {
     linker_be_nice();
+    embed_mqtt_ca();
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
     println!("cargo:rustc-link-arg=-Tlinkall.x");
 }
 
+/// Copy the DER certificate pinned for MQTT over TLS, empty when `MQTT_CA` is unset
+fn embed_mqtt_ca() {
+    println!("cargo:rerun-if-env-changed=MQTT_CA");
+    let out = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("mqtt_ca.der");
+    let ca = match std::env::var("MQTT_CA") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            std::fs::read(&path).unwrap_or_else(|e| panic!("Can't read MQTT_CA {}: {}", path, e))
+        }
+        Err(_) => Vec::new(),
+    };
+    std::fs::write(out, ca).unwrap();
+}
+
 fn linker_be_nice() {
     let args: Vec<String> = std::env::args().collect();
     if args.len() > 1 {
@@ -10,24 +10,29 @@ use esp_hal::{
     clock::CpuClock, interrupt::software::SoftwareInterruptControl, rng::Rng,
     timer::timg::TimerGroup,
 };
-use esp_println::println;
+use log::{error, info, warn};
 use water::appcore::start_appcore;
+use water::command::reboot_requested;
 use water::display::{display_task, update_status};
 use water::io::gpio::{
     adc_task, btn_init, compressor_init, get_battery_value, get_sensor_value, led_init,
 };
 use water::io::led::{HEARTBEAT_DEFAULT, heartbeat, set_heartbeat};
 use water::io::rtc;
-use water::io::wifi::wifi_hw_init;
+use water::io::wifi::{request_portal, wifi_hw_init};
+use water::net::discovery::mdns_task;
 use water::net::mqtt::mqtt_task;
 use water::net::ntp::{NtpClient, ntp_task};
-use water::net::stack::{init_net, wait_for_ip, wait_for_link};
-use water::watering::watering_task;
+use water::net::rest::{HTTP_CONNECTIONS, http_task};
+use water::net::stack::{dhcp_retry_task, init_net, wait_for_ip, wait_for_link};
+use water::provision::portal::portal_task;
+use water::provision::{console_init, console_task};
+use water::watering::{BOARD_ZONES, watering_task};
 esp_bootloader_esp_idf::esp_app_desc!();
 
 #[esp_rtos::main]
 async fn main(spawner: Spawner) -> ! {
-    esp_println::logger::init_logger_from_env();
+    water::logger::init();
 
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
@@ -42,8 +47,8 @@ async fn main(spawner: Spawner) -> ! {
     let watchdog = timer_group1.wdt;
 
     match water::watchdog::init_watchdog(watchdog) {
-        Ok(()) => println!("Watchdog initialized successfully"),
-        Err(e) => println!("Failed to initialize watchdog: {:?}", e),
+        Ok(()) => info!("Watchdog initialized successfully"),
+        Err(e) => error!("Failed to initialize watchdog: {:?}", e),
     }
 
     esp_rtos::start(embassy_timer);
@@ -52,11 +57,26 @@ async fn main(spawner: Spawner) -> ! {
     let software_interrupt = SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
 
     let led = led_init(peripherals.GPIO2).await;
-    let compressor = compressor_init(peripherals.GPIO25).await;
+    // Pumps of the two zones, see `BOARD_ZONES`
+    let compressors = [
+        compressor_init(peripherals.GPIO25).await,
+        compressor_init(peripherals.GPIO26).await,
+    ];
     let button = btn_init(peripherals.GPIO0).await;
 
     rtc::init(peripherals.LPWR).await;
 
+    if let Err(e) = water::config::init(peripherals.FLASH).await {
+        warn!("Config store unavailable, using defaults: {:?}", e);
+    }
+
+    match console_init(peripherals.UART0, peripherals.GPIO3) {
+        Ok(rx) => {
+            spawner.spawn(console_task(rx)).ok();
+        }
+        Err(e) => error!("Failed to start console: {:?}", e),
+    }
+
     update_status("App core starting").await.unwrap();
 
     start_appcore(
@@ -77,7 +97,7 @@ async fn main(spawner: Spawner) -> ! {
                 spawner
                     .spawn(adc_task(
                         peripherals.GPIO36,
-                        peripherals.GPIO34,
+                        (peripherals.GPIO34, peripherals.GPIO35),
                         peripherals.ADC1,
                     ))
                     .ok();
@@ -88,33 +108,52 @@ async fn main(spawner: Spawner) -> ! {
 
     update_status("WiFi init").await.unwrap();
 
-    let wifi = wifi_hw_init(wifi_timer, rng, peripherals.WIFI, &spawner)
+    // Holding the button while WiFi starts opens the setup portal
+    if button.is_low() {
+        request_portal();
+    }
+
+    // Automatic watering supervisor with button override, it doesn't need
+    // the network so an outage never stops it
+    spawner.spawn(watering_task(compressors, Some(button))).ok();
+
+    let (wifi, ap) = wifi_hw_init(wifi_timer, rng, peripherals.WIFI, &spawner)
         .await
         .unwrap();
 
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
+    spawner.spawn(portal_task(ap, seed)).ok();
 
     // Init network stack
     let stack = init_net(wifi, seed, &spawner).await.unwrap();
 
     wait_for_link(stack).await;
     wait_for_ip(stack).await;
+    spawner.spawn(dhcp_retry_task(stack)).ok();
     set_heartbeat(HEARTBEAT_DEFAULT);
 
-    // Automatic watering supervisor with button override
-    spawner.spawn(watering_task(compressor, Some(button))).ok();
+    for _ in 0..HTTP_CONNECTIONS {
+        spawner.spawn(http_task(*stack)).ok();
+    }
+    spawner.spawn(mdns_task(*stack)).ok();
 
     let ntp = NtpClient::new(stack);
     spawner.spawn(ntp_task(ntp)).ok();
     spawner.spawn(mqtt_task(rng, stack)).ok();
 
     loop {
-        let sens_val = get_sensor_value().await;
-        let bat_val = get_battery_value().await;
-        println!("Sensor: {}, Battery: {}", sens_val, bat_val);
+        for zone in 0..BOARD_ZONES {
+            info!("Sensor #{}: {}", zone, get_sensor_value(zone).await);
+        }
+        info!("Battery: {}", get_battery_value().await);
 
         Timer::after(Duration::from_millis(2000)).await;
 
+        // Requested remotely, by now the acknowledgement is out
+        if reboot_requested() {
+            esp_hal::system::software_reset();
+        }
+
         // We're still alive
         water::watchdog::feed_watchdog();
     }
@@ -0,0 +1,90 @@
+//! Status samples taken while the broker is unreachable
+//!
+//! They are replayed oldest first to the history topic after reconnecting,
+//! so long-term graphs don't get gaps when the router reboots. Samples need a
+//! valid clock, without one they couldn't be placed on a graph anyway.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use heapless::{Deque, Vec};
+use jiff::Timestamp;
+use serde::Serialize;
+
+use crate::command::status::{ZoneStatus, get_zone_status};
+use crate::io::gpio::get_battery_value;
+use crate::power::charge_level;
+use crate::time::now;
+use crate::watering::BOARD_ZONES;
+
+/// 40 minutes at the default interval, longer intervals cover more
+pub const HISTORY_LEN: usize = 256;
+pub const SAMPLE_LEN: usize = 320;
+
+#[derive(Serialize)]
+pub struct Sample {
+    pub timestamp: Timestamp,
+    pub zones: Vec<ZoneStatus, BOARD_ZONES>,
+    pub charge: u32,
+    pub charge_raw: u16,
+}
+
+static HISTORY: Mutex<CriticalSectionRawMutex, Deque<Sample, HISTORY_LEN>> =
+    Mutex::new(Deque::new());
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// `None` until the clock is set
+pub async fn get_sample() -> Option<Sample> {
+    let timestamp = now()
+        .await
+        .ok()
+        .filter(|ts| ts.as_second() > 1_000_000_000)?;
+    let mut zones = Vec::new();
+    for zone in 0..BOARD_ZONES {
+        zones.push(get_zone_status(zone).await).ok();
+    }
+    Some(Sample {
+        timestamp,
+        zones,
+        charge: charge_level().await,
+        charge_raw: get_battery_value().await,
+    })
+}
+
+/// A full buffer gives up its oldest sample
+pub async fn record(sample: Sample) {
+    let mut history = HISTORY.lock().await;
+    if history.is_full() {
+        history.pop_front();
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    history.push_back(sample).ok();
+}
+
+/// Serialize the oldest sample into `out`, returning its length
+pub async fn peek_oldest(out: &mut [u8]) -> Option<usize> {
+    let mut history = HISTORY.lock().await;
+    loop {
+        match serde_json_core::to_slice(history.front()?, out) {
+            Ok(len) => return Some(len),
+            // Doesn't fit `out`, don't let it block the rest
+            Err(_) => {
+                history.pop_front();
+            }
+        }
+    }
+}
+
+pub async fn remove_oldest() {
+    HISTORY.lock().await.pop_front();
+}
+
+/// Samples waiting for the broker
+pub async fn backlog() -> usize {
+    HISTORY.lock().await.len()
+}
+
+/// Samples lost to a full buffer since boot
+pub fn dropped() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
@@ -1,32 +1,273 @@
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::Duration;
 use heapless::String;
-use serde::{Deserialize, Serialize};
+use jiff::Timestamp;
+use log::warn;
 
+use crate::config::{self, Key};
 use crate::display::STATUS_LEN;
 use crate::display::update_status;
-use crate::watering::set_low_humidity_limit;
+use crate::logger;
+use crate::net::mqtt::{
+    get_socket_timeout, get_telemetry_interval, request_publish, set_socket_timeout,
+    set_telemetry_interval, socket_timeout_setting,
+};
+use crate::time::{now, set_timezone, timezone_name};
+use crate::watering::{
+    get_low_humidity_limits, get_schedule, get_tunables, pause_until, request_watering,
+    set_low_humidity_limit, set_schedule_window, set_tunables, stop_watering,
+};
+pub use water_core::command::{ack, auth, message, settings};
+
+pub mod history;
 pub mod status;
 
-#[derive(Serialize, Deserialize)]
-pub enum Command {
-    SetMqttTimeout(u32),
-    SetHumidityTrigger(u16),
+use ack::{Ack, AckValue, Rejection};
+use auth::{CommandKey, NonceGuard};
+pub use message::Command;
+use settings::Settings;
+
+static REBOOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+static COMMAND_KEY: Mutex<CriticalSectionRawMutex, Option<CommandKey>> = Mutex::new(None);
+static NONCES: Mutex<CriticalSectionRawMutex, NonceGuard> = Mutex::new(NonceGuard::new());
+static AUTH_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+/// With a key set only signed commands are accepted
+pub async fn set_command_key(key: Option<CommandKey>) {
+    *COMMAND_KEY.lock().await = key;
+}
+
+pub async fn has_command_key() -> bool {
+    COMMAND_KEY.lock().await.is_some()
+}
+
+/// Continue from the nonce stored before the restart
+pub async fn restore_nonce(saved: u64) {
+    *NONCES.lock().await = NonceGuard::restore(saved);
 }
 
-impl Command {
-    pub async fn process(&self) {
-        let mut status: String<STATUS_LEN> = String::new();
+/// Messages rejected for a missing or bad signature or a reused nonce
+pub fn auth_failures() -> u32 {
+    AUTH_FAILURES.load(Ordering::Relaxed)
+}
 
-        match self {
-            Command::SetMqttTimeout(secs) => {
-                write!(status, "MQTT t/o: {}s", secs).ok();
-                update_status(&status).await.ok();
+/// Returns the command JSON, stripping the signature or request id
+///
+/// `target` is what the signature must name, this unit's id or `all` for
+/// a broadcast.
+pub async fn authenticate<'a>(target: &str, payload: &'a [u8]) -> Result<&'a [u8], Rejection> {
+    let Some(key) = *COMMAND_KEY.lock().await else {
+        return Ok(auth::request_id(payload).1);
+    };
+    let (verified, store) = {
+        let mut nonces = NONCES.lock().await;
+        match auth::verify(&key, target, payload, nonces.last()) {
+            Ok(verified) => {
+                let store = nonces.accept(verified.nonce);
+                (verified, store)
             }
-            Command::SetHumidityTrigger(hum) => {
-                set_low_humidity_limit(*hum).await;
-                write!(status, "Hum. lim: {}", hum).ok();
-                update_status(&status).await.ok();
+            Err(reason) => {
+                AUTH_FAILURES.fetch_add(1, Ordering::Relaxed);
+                warn!("Command: rejected, {:?}", reason);
+                update_status("Bad signature").await.ok();
+                return Err(reason);
             }
         }
+    };
+    // Survive reboots, otherwise old messages could be replayed
+    if let Some(nonce) = store {
+        config::save(Key::CommandNonce, &nonce).await.ok();
     }
+    Ok(verified.command)
+}
+
+/// Authenticate, parse and run a control message, whichever way it came in
+///
+/// The acknowledgement echoes the request id, see [`auth::request_id`].
+pub async fn handle(target: &str, payload: &[u8]) -> Ack {
+    let (id, _) = auth::request_id(payload);
+    let ack = match authenticate(target, payload).await {
+        Ok(payload) => match serde_json_core::from_slice::<Command>(payload) {
+            Ok((command, _)) => process(&command).await,
+            Err(_) => Ack::malformed(),
+        },
+        Err(reason) => Ack::new("", Err(reason)),
+    };
+    ack.with_id(id)
+}
+
+pub async fn get_settings() -> Settings {
+    Settings {
+        low_humidity_limits: Some(get_low_humidity_limits().await),
+        schedule: Some(get_schedule().await),
+        tunables: Some(get_tunables().await.into()),
+        reporting_interval_secs: Some(get_telemetry_interval().await.as_secs() as u32),
+        socket_timeout_secs: Some(
+            socket_timeout_setting()
+                .await
+                .map_or(0, |timeout| timeout.as_secs() as u32),
+        ),
+        timezone: timezone_name().await,
+        log_level: Some(logger::remote_level()),
+    }
+}
+
+/// All fields are checked before the first one is applied
+pub async fn apply_settings(settings: &Settings) -> Ack {
+    if let Err((name, reason)) = settings.validate() {
+        return Ack::new(name, Err(reason));
+    }
+    for command in settings.commands() {
+        let ack = process(&command).await;
+        if !ack.accepted {
+            return ack;
+        }
+    }
+    Ack::new("Settings", Ok(None))
+}
+
+/// Set once a reboot was acknowledged, the main loop performs it
+pub fn reboot_requested() -> bool {
+    REBOOT_REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Validate and run a single command
+pub async fn process(command: &Command) -> Ack {
+    let mut status: String<STATUS_LEN> = String::new();
+
+    if let Err(reason) = command.validate() {
+        write!(status, "Bad {}", command.name()).ok();
+        update_status(&status).await.ok();
+        return Ack::new(command.name(), Err(reason));
+    }
+
+    let outcome = match command {
+        Command::SetHumidityTrigger { zone, value } => {
+            if set_low_humidity_limit(*zone as usize, *value).await {
+                write!(status, "Hum. lim #{}: {}", zone, value).ok();
+                config::save(Key::HumidityLimits, &get_low_humidity_limits().await)
+                    .await
+                    .ok();
+                Ok(Some(AckValue::Number(*value as u32)))
+            } else {
+                write!(status, "Bad zone #{}", zone).ok();
+                Err(Rejection::BadZone)
+            }
+        }
+        Command::SetSchedule { slot, window } => {
+            if !set_schedule_window(*slot as usize, *window).await {
+                write!(status, "Bad sched #{}", slot).ok();
+                Err(Rejection::BadSlot)
+            } else {
+                if let Some(w) = window {
+                    write!(status, "Sched #{}: {:02}:{:02}", slot, w.hour, w.minute).ok();
+                } else {
+                    write!(status, "Sched #{}: off", slot).ok();
+                }
+                config::save(Key::Schedule, &get_schedule().await)
+                    .await
+                    .ok();
+                Ok(Some(AckValue::Window(*window)))
+            }
+        }
+        Command::StartWatering { zone, secs } => {
+            if request_watering(*zone as usize, *secs).await {
+                write!(status, "Water #{}: {}s", zone, secs).ok();
+                Ok(Some(AckValue::Number(*secs)))
+            } else {
+                write!(status, "Bad water #{}", zone).ok();
+                Err(Rejection::BadDuration)
+            }
+        }
+        Command::StopWatering => {
+            stop_watering().await;
+            write!(status, "Watering stopped").ok();
+            Ok(None)
+        }
+        Command::PauseUntil { timestamp: 0 } => {
+            pause_until(Timestamp::UNIX_EPOCH).await;
+            write!(status, "Auto resumed").ok();
+            Ok(None)
+        }
+        Command::PauseUntil { timestamp } => {
+            match (now().await, Timestamp::from_second(*timestamp)) {
+                (Err(_), _) => {
+                    write!(status, "Pause: no clock").ok();
+                    Err(Rejection::NoTime)
+                }
+                (_, Err(_)) => {
+                    write!(status, "Pause out of range").ok();
+                    Err(Rejection::OutOfRange)
+                }
+                (Ok(now), Ok(until)) if until <= now => {
+                    write!(status, "Pause in the past").ok();
+                    Err(Rejection::OutOfRange)
+                }
+                (Ok(_), Ok(until)) => {
+                    pause_until(until).await;
+                    write!(status, "Auto paused").ok();
+                    Ok(Some(AckValue::Timestamp(until.as_second())))
+                }
+            }
+        }
+        Command::Reboot => {
+            write!(status, "Rebooting").ok();
+            REBOOT_REQUESTED.store(true, Ordering::Relaxed);
+            Ok(None)
+        }
+        Command::PublishNow => {
+            request_publish();
+            write!(status, "Publish requested").ok();
+            Ok(None)
+        }
+        Command::SetMqttTimeout(secs) | Command::SetReportingInterval { secs } => {
+            set_telemetry_interval(Duration::from_secs(*secs as u64)).await;
+            config::save(Key::ReportingInterval, secs).await.ok();
+            write!(status, "Report every {}s", secs).ok();
+            Ok(Some(AckValue::Number(*secs)))
+        }
+        Command::SetSocketTimeout { secs: 0 } => {
+            set_socket_timeout(None).await;
+            config::remove(Key::SocketTimeout).await.ok();
+            write!(status, "Timeout: auto").ok();
+            Ok(Some(AckValue::Number(
+                get_socket_timeout().await.as_secs() as u32
+            )))
+        }
+        Command::SetSocketTimeout { secs } => {
+            set_socket_timeout(Some(Duration::from_secs(*secs as u64))).await;
+            config::save(Key::SocketTimeout, secs).await.ok();
+            write!(status, "Timeout {}s", secs).ok();
+            Ok(Some(AckValue::Number(*secs)))
+        }
+        Command::SetTunables(settings) => {
+            set_tunables(settings.apply(get_tunables().await)).await;
+            config::save(Key::Tunables, settings).await.ok();
+            write!(status, "Tunables set").ok();
+            Ok(None)
+        }
+        Command::SetTimezone { tz } => match set_timezone(tz).await {
+            Ok(()) => {
+                config::save(Key::Timezone, tz).await.ok();
+                write!(status, "Time zone set").ok();
+                Ok(None)
+            }
+            Err(_) => {
+                write!(status, "Bad time zone").ok();
+                Err(Rejection::BadTimezone)
+            }
+        },
+        Command::SetLogLevel { level } => {
+            logger::set_remote_level(*level);
+            config::save(Key::LogLevel, level).await.ok();
+            write!(status, "Log level {}", level).ok();
+            Ok(None)
+        }
+    };
+
+    update_status(&status).await.ok();
+    Ack::new(command.name(), outcome)
 }
@@ -1,36 +1,96 @@
+use heapless::{String, Vec};
 use jiff::Timestamp;
+use log::LevelFilter;
 use serde::Serialize;
 
+use crate::command::auth_failures;
+use crate::command::history;
 use crate::io::gpio::get_battery_value;
 use crate::io::gpio::get_sensor_value;
-use crate::net::mqtt::latency;
+use crate::io::networks::WifiStatus;
+use crate::io::wifi::wifi_status;
+use crate::logger;
+use crate::net::connection::ConnectionStatus;
+use crate::net::mqtt::{
+    connection_status, get_socket_timeout, get_telemetry_interval, latency, topics,
+};
+use crate::net::ntp::sync_stats;
+use crate::net::sntp::SyncStats;
+use crate::net::topics::TOPIC_LEN;
 use crate::power::charge_level;
 use crate::power::humidity_level;
 use crate::time::get_last_watered;
 use crate::time::now;
-use crate::watering::get_low_humidity_limit;
+use crate::watering::{BOARD_ZONES, get_low_humidity_limit, is_pumping};
+
+/// Room for a serialized `Status`
+pub const STATUS_JSON_LEN: usize = 3072;
+
+#[derive(Clone, Serialize)]
+pub struct ZoneStatus {
+    pub humidity: u32,
+    pub humidity_raw: u16,
+    pub low_humidity_limit: u16,
+    pub pumping: bool,
+}
 
 #[derive(Serialize)]
 pub struct Status {
     pub latency_ms: u64,
-    pub humidity: u32,
-    pub humidity_raw: u16,
+    pub zones: Vec<ZoneStatus, BOARD_ZONES>,
     pub charge: u32,
     pub charge_raw: u16,
-    pub low_humidity_limit: u16,
     pub last_watered_timestamp: Timestamp,
     pub report_timestamp: Timestamp,
+    /// Topic holding `online` or `offline`, tells whether this report is current
+    pub availability_topic: String<TOPIC_LEN>,
+    pub mqtt: ConnectionStatus,
+    pub wifi: WifiStatus,
+    pub ntp: SyncStats,
+    pub reporting_interval_secs: u64,
+    pub socket_timeout_secs: u64,
+    /// Control messages dropped by signature or replay checks since boot
+    pub auth_failures: u32,
+    /// Offline samples not yet replayed to the history topic
+    pub history_backlog: usize,
+    pub history_dropped: u32,
+    pub log_level: LevelFilter,
+    /// Log records lost to a full queue
+    pub log_dropped: u32,
+}
+
+pub async fn get_zone_status(zone: usize) -> ZoneStatus {
+    ZoneStatus {
+        humidity: humidity_level(zone).await,
+        humidity_raw: get_sensor_value(zone).await,
+        low_humidity_limit: get_low_humidity_limit(zone).await,
+        pumping: is_pumping(zone).await,
+    }
 }
 
 pub async fn get_status() -> Status {
+    let mut zones = Vec::new();
+    for zone in 0..BOARD_ZONES {
+        zones.push(get_zone_status(zone).await).ok();
+    }
+
     Status {
         latency_ms: latency().await.unwrap_or(0),
-        humidity: humidity_level().await,
-        humidity_raw: get_sensor_value().await,
+        zones,
         charge: charge_level().await,
         charge_raw: get_battery_value().await,
-        low_humidity_limit: get_low_humidity_limit().await,
         last_watered_timestamp: get_last_watered().await,
         report_timestamp: now().await.unwrap_or(Timestamp::constant(0, 0)),
+        availability_topic: topics().await.availability,
+        mqtt: connection_status().await,
+        wifi: wifi_status().await,
+        ntp: sync_stats().await,
+        reporting_interval_secs: get_telemetry_interval().await.as_secs(),
+        socket_timeout_secs: get_socket_timeout().await.as_secs(),
+        auth_failures: auth_failures(),
+        history_backlog: history::backlog().await,
+        history_dropped: history::dropped(),
+        log_level: logger::remote_level(),
+        log_dropped: logger::dropped(),
     }
 }
@@ -0,0 +1,189 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::Duration;
+use esp_hal::peripherals::FLASH;
+use esp_storage::FlashStorage;
+use log::warn;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::command::{
+    self,
+    message::{REPORTING_INTERVAL_SECS, SOCKET_TIMEOUT_SECS},
+};
+use crate::error::StorageError;
+use crate::io::wifi;
+use crate::logger;
+use crate::net::ipconfig::{HOSTNAME_LEN, StaticIp, is_valid_hostname};
+use crate::net::topics::{PREFIX_LEN, is_valid_prefix};
+use crate::net::{mqtt, ntp, stack};
+use crate::provision::PORTAL_PASSWORD_LEN;
+use crate::time::{self, TZ_LEN};
+use crate::watering::controller::{TunableSettings, Tunables};
+use crate::watering::{BOARD_ZONES, set_low_humidity_limit, set_schedule, set_tunables};
+
+pub use water_core::config::store;
+
+use store::{MAX_VALUE_LEN, Store};
+
+// Must match the `config` entry in partitions.csv
+const PARTITION_OFFSET: u32 = 0x10000;
+const PARTITION_SIZE: u32 = 0x4000;
+
+/// Identifiers of persisted settings, never reuse a retired value
+#[derive(Debug, Copy, Clone)]
+#[repr(u16)]
+pub enum Key {
+    HumidityLimits = 1,
+    Schedule = 2,
+    WifiCredentials = 3,
+    MqttCredentials = 4,
+    MqttServer = 5,
+    Timezone = 6,
+    MqttTls = 7,
+    Tunables = 8,
+    ReportingInterval = 9,
+    CommandKey = 10,
+    CommandNonce = 11,
+    TopicPrefix = 12,
+    LogLevel = 13,
+    StaticIp = 14,
+    DhcpTimeout = 15,
+    Hostname = 16,
+    WifiNetworks = 17,
+    NtpServers = 18,
+    WifiJoined = 19,
+    PortalPassword = 20,
+    SocketTimeout = 21,
+}
+
+static STORE: Mutex<CriticalSectionRawMutex, Option<Store<FlashStorage<'static>>>> =
+    Mutex::new(None);
+
+/// Mount the config partition and apply the stored settings
+pub async fn init(flash: FLASH<'static>) -> Result<(), StorageError> {
+    let store = Store::mount(FlashStorage::new(flash), PARTITION_OFFSET, PARTITION_SIZE)?;
+    STORE.lock().await.replace(store);
+
+    if let Some(limits) = load::<[u16; BOARD_ZONES]>(Key::HumidityLimits).await {
+        for (zone, limit) in limits.iter().enumerate() {
+            set_low_humidity_limit(zone, *limit).await;
+        }
+    }
+    if let Some(schedule) = load(Key::Schedule).await {
+        set_schedule(schedule).await;
+    }
+    if let Some(settings) = load::<TunableSettings>(Key::Tunables).await
+        && settings.is_valid()
+    {
+        set_tunables(settings.apply(Tunables::default())).await;
+    }
+    if let Some(password) = load::<heapless::String<PORTAL_PASSWORD_LEN>>(Key::PortalPassword).await
+        && password.len() == PORTAL_PASSWORD_LEN
+    {
+        wifi::set_portal_password(Some(password)).await;
+    }
+    if let Some(joined) = load(Key::WifiJoined).await {
+        wifi::set_joined_before(joined);
+    }
+    if let Some(credentials) = load(Key::WifiCredentials).await {
+        wifi::set_credentials(Some(credentials)).await;
+    }
+    if let Some(networks) = load(Key::WifiNetworks).await {
+        wifi::set_networks(networks).await;
+    }
+    if let Some(credentials) = load(Key::MqttCredentials).await {
+        mqtt::set_credentials(Some(credentials)).await;
+    }
+    if let Some(server) = load(Key::MqttServer).await {
+        mqtt::set_server(Some(server)).await;
+    }
+    if let Some(tls) = load(Key::MqttTls).await {
+        mqtt::set_tls(tls).await;
+    }
+    if let Some(prefix) = load::<heapless::String<PREFIX_LEN>>(Key::TopicPrefix).await
+        && is_valid_prefix(&prefix)
+    {
+        mqtt::set_topic_prefix(Some(prefix)).await;
+    }
+    if let Some(secs) = load::<u32>(Key::ReportingInterval).await
+        && REPORTING_INTERVAL_SECS.contains(&secs)
+    {
+        mqtt::set_telemetry_interval(Duration::from_secs(secs as u64)).await;
+    }
+    if let Some(secs) = load::<u32>(Key::SocketTimeout).await
+        && SOCKET_TIMEOUT_SECS.contains(&secs)
+    {
+        mqtt::set_socket_timeout(Some(Duration::from_secs(secs as u64))).await;
+    }
+    if let Some(ip) = load::<StaticIp>(Key::StaticIp).await
+        && ip.is_valid()
+    {
+        stack::set_static_ip(Some(ip)).await;
+    }
+    if let Some(secs) = load(Key::DhcpTimeout).await {
+        stack::set_dhcp_timeout(secs).await;
+    }
+    if let Some(name) = load::<heapless::String<HOSTNAME_LEN>>(Key::Hostname).await
+        && is_valid_hostname(&name)
+    {
+        stack::set_hostname(Some(name)).await;
+    }
+    if let Some(servers) = load(Key::NtpServers).await {
+        ntp::set_servers(Some(servers)).await;
+    }
+    if let Some(level) = load(Key::LogLevel).await {
+        logger::set_remote_level(level);
+    }
+    if let Some(key) = load(Key::CommandKey).await {
+        command::set_command_key(Some(key)).await;
+    }
+    if let Some(nonce) = load(Key::CommandNonce).await {
+        command::restore_nonce(nonce).await;
+    }
+    if let Some(tz) = load::<heapless::String<TZ_LEN>>(Key::Timezone).await
+        && let Err(e) = time::set_timezone(&tz).await
+    {
+        warn!("Config: bad time zone {}: {:?}", tz, e);
+    }
+
+    Ok(())
+}
+
+/// Read a setting, `None` if it is missing or can't be decoded
+pub async fn load<T: DeserializeOwned>(key: Key) -> Option<T> {
+    let mut buf = [0u8; MAX_VALUE_LEN];
+    let len = {
+        let mut store = STORE.lock().await;
+        match store.as_mut()?.read(key as u16, &mut buf) {
+            Ok(len) => len?,
+            Err(e) => {
+                warn!("Config: can't read {:?}: {:?}", key, StorageError::from(e));
+                return None;
+            }
+        }
+    };
+
+    match serde_json_core::from_slice::<T>(&buf[..len]) {
+        Ok((value, _)) => Some(value),
+        Err(_) => {
+            warn!("Config: ignoring malformed {:?}", key);
+            None
+        }
+    }
+}
+
+pub async fn save<T: Serialize>(key: Key, value: &T) -> Result<(), StorageError> {
+    let mut buf = [0u8; MAX_VALUE_LEN];
+    let len = serde_json_core::to_slice(value, &mut buf).map_err(|_| StorageError::TooLarge)?;
+
+    let mut store = STORE.lock().await;
+    let store = store.as_mut().ok_or(StorageError::NotMounted)?;
+    Ok(store.write(key as u16, &buf[..len])?)
+}
+
+pub async fn remove(key: Key) -> Result<(), StorageError> {
+    let mut store = STORE.lock().await;
+    let store = store.as_mut().ok_or(StorageError::NotMounted)?;
+    Ok(store.remove(key as u16)?)
+}
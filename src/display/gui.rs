@@ -1,10 +1,11 @@
-use crate::error::ConversionError;
-use crate::net::mqtt::{latency, mqtt_status};
+use crate::io::networks::{SIGNAL_BARS, signal_bars};
+use crate::net::mqtt::{connection_status, latency};
 use crate::power::humidity_level;
 use crate::time::get_next_watering_time;
-use crate::watering::get_low_humidity_limit;
-use crate::{error::UIError, io::wifi::is_wifi_connected, power::charge_level, time::localtime};
+use crate::watering::{BOARD_ZONES, get_low_humidity_limit};
+use crate::{error::UIError, io::wifi::signal, power::charge_level, time::localtime};
 use core::fmt::Write;
+use embassy_time::Instant;
 use embedded_graphics::{
     Drawable,
     image::{Image, ImageRaw},
@@ -32,8 +33,9 @@ pub(crate) async fn draw_markup(
 }
 
 const WIFI_LOGO_SIZE: u32 = 16;
-const WIFI_IMAGE: ImageRaw<BinaryColor> =
-    ImageRaw::new(include_bytes!("../../icons/wifi.raw"), WIFI_LOGO_SIZE);
+// Signal bars fill the logo square, each one taller than the last
+const BAR_PITCH: u32 = WIFI_LOGO_SIZE / SIGNAL_BARS as u32;
+const BAR_WIDTH: u32 = BAR_PITCH - 1;
 const NOWIFI_IMAGE: ImageRaw<BinaryColor> =
     ImageRaw::new(include_bytes!("../../icons/nowifi.raw"), WIFI_LOGO_SIZE);
 const NONET_IMAGE: ImageRaw<BinaryColor> =
@@ -56,6 +58,7 @@ const BATTERY_HEIGHT: u32 = STATUS_BAR_HEIGHT as u32 - 1;
 
 const MAIN_WINDOW_TOP: u32 = STATUS_BAR_HEIGHT as u32;
 const MQTT_STATUS_LEFT: i32 = BIG_ICON_SIZE as i32 + MAIN_FONT.character_size.width as i32 * 8;
+const ZONE_DISPLAY_SECS: u64 = 5;
 
 async fn draw_battery(target: &mut impl DrawTarget<Color = BinaryColor>) -> Result<(), UIError> {
     Rectangle::new(
@@ -126,12 +129,32 @@ async fn draw_clock(target: &mut impl DrawTarget<Color = BinaryColor>) -> Result
 }
 
 async fn draw_wifi(target: &mut impl DrawTarget<Color = BinaryColor>) -> Result<(), UIError> {
-    let image = if is_wifi_connected().await {
-        Image::new(&WIFI_IMAGE, Point::zero())
-    } else {
-        Image::new(&NOWIFI_IMAGE, Point::zero())
+    let Some(rssi) = signal().await else {
+        let image = Image::new(&NOWIFI_IMAGE, Point::zero());
+        image.draw(&mut *target).map_err(|_| UIError::DrawError)?;
+        return Ok(());
     };
-    image.draw(&mut *target).map_err(|_| UIError::DrawError)?;
+
+    let bars = signal_bars(rssi);
+    for bar in 0..SIGNAL_BARS {
+        let height = BAR_PITCH * (bar as u32 + 1);
+        // Missing bars are outlined
+        let style = if bar < bars {
+            PrimitiveStyle::with_fill(BinaryColor::On)
+        } else {
+            PrimitiveStyle::with_stroke(BinaryColor::On, 1)
+        };
+        Rectangle::new(
+            Point::new(
+                (BAR_PITCH * bar as u32) as i32,
+                (WIFI_LOGO_SIZE - height) as i32,
+            ),
+            Size::new(BAR_WIDTH, height),
+        )
+        .into_styled(style)
+        .draw(&mut *target)
+        .map_err(|_| UIError::DrawError)?;
+    }
     Ok(())
 }
 
@@ -146,7 +169,7 @@ async fn draw_mqtt(target: &mut impl DrawTarget<Color = BinaryColor>) -> Result<
     );
     image.draw(&mut *target).map_err(|_| UIError::DrawError)?;
 
-    let status = mqtt_status().await.map_err(|_| ConversionError::RawBuf)?;
+    let state = connection_status().await.state;
 
     let text_style = MonoTextStyleBuilder::new()
         .font(&CLOCK_FONT)
@@ -154,7 +177,7 @@ async fn draw_mqtt(target: &mut impl DrawTarget<Color = BinaryColor>) -> Result<
         .build();
 
     Text::with_baseline(
-        &status,
+        state.label(),
         Point::new(
             MQTT_STATUS_LEFT + WIFI_LOGO_SIZE as i32 + 1,
             MAIN_WINDOW_TOP as i32 + 1,
@@ -202,15 +225,21 @@ async fn draw_main(target: &mut impl DrawTarget<Color = BinaryColor>) -> Result<
     let image = Image::new(&DROP_IMAGE, Point::new(0, MAIN_WINDOW_TOP as i32));
     image.draw(&mut *target).map_err(|_| UIError::DrawError)?;
 
+    // Zones take turns on the display, the zone number replaces the `~`
+    let zone = (Instant::now().as_secs() / ZONE_DISPLAY_SECS) as usize % BOARD_ZONES;
+
     let mut waterstr: String<10> = String::new(); // 000%
-    write!(waterstr, "~{:3}%", humidity_level().await)?;
+    write!(waterstr, "{}{:3}%", zone, humidity_level(zone).await)?;
 
     let mut waterlimstr: String<10> = String::new(); // 000%
-    write!(waterlimstr, ">{:3}%", get_low_humidity_limit().await)?;
+    write!(waterlimstr, ">{:3}%", get_low_humidity_limit(zone).await)?;
 
     let mut nextwaterstr: String<10> = String::new(); // 000%
-    let time = get_next_watering_time().await;
-    write!(nextwaterstr, "{:02}:{:02}", time.hour(), time.minute())?;
+    if let Some(time) = get_next_watering_time().await {
+        write!(nextwaterstr, "{:02}:{:02}", time.hour(), time.minute())?;
+    } else {
+        write!(nextwaterstr, "--:--")?;
+    }
 
     let text_style = MonoTextStyleBuilder::new()
         .font(&CLOCK_FONT)
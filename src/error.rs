@@ -18,6 +18,12 @@ pub enum GpioError {
     InitializationFailed,
 }
 
+#[derive(Debug, Error)]
+pub enum UartError {
+    #[error("Can't initialize UART")]
+    InitializationFailed,
+}
+
 #[derive(Debug, Error)]
 pub enum ConversionError {
     Utf(#[from] Utf8Error),
@@ -33,6 +39,7 @@ pub enum HwError {
     WifiInit(#[from] InitializationError),
     Wifi(#[from] WifiError),
     Gpio(#[from] GpioError),
+    Uart(#[from] UartError),
 }
 
 #[derive(Debug, Error)]
@@ -50,7 +57,34 @@ pub enum NetError {
     Tcp(#[from] embassy_net::tcp::Error),
     TcpConnect(#[from] embassy_net::tcp::ConnectError),
     Mqtt,
+    Tls,
     Socket,
+    /// No majority among the NTP answers
+    Ntp,
+}
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    Flash,
+    BadPartition,
+    BadKey,
+    TooLarge,
+    Full,
+    NotMounted,
+}
+
+impl<E> From<crate::config::store::Error<E>> for StorageError {
+    fn from(err: crate::config::store::Error<E>) -> Self {
+        use crate::config::store::Error;
+
+        match err {
+            Error::Flash(_) => StorageError::Flash,
+            Error::BadPartition => StorageError::BadPartition,
+            Error::BadKey => StorageError::BadKey,
+            Error::TooLarge => StorageError::TooLarge,
+            Error::Full => StorageError::Full,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -59,9 +93,11 @@ pub enum SysError {
     Hardware(#[from] HwError),
     System(#[from] SystemError),
     Net(#[from] NetError),
+    Storage(#[from] StorageError),
     Time(#[from] jiff::Error),
     TimerSetup,
     NoTime,
+    BadTimezone,
     AppCoreStartFailed,
     WatchdogError,
 }
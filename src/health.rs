@@ -1,6 +1,6 @@
 use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use embassy_time::{Duration, Instant};
-use esp_println::println;
+use log::{info, warn};
 
 // Health check timeouts (in milliseconds)
 const WIFI_HEALTH_TIMEOUT: Duration = Duration::from_secs(120);
@@ -97,10 +97,10 @@ pub fn is_subsystem_healthy(subsystem: Subsystem) -> bool {
     if is_healthy != was_healthy {
         healthy_flag.store(is_healthy, Ordering::SeqCst);
         if is_healthy {
-            println!("{} subsystem recovered", subsystem.name());
+            info!("{} subsystem recovered", subsystem.name());
         } else {
-            println!(
-                "WARNING: {} subsystem unhealthy - no heartbeat for {}ms",
+            warn!(
+                "{} subsystem unhealthy - no heartbeat for {}ms",
                 subsystem.name(),
                 now.wrapping_sub(last_heartbeat)
             );
@@ -137,5 +137,5 @@ pub fn init_health_monitoring() {
     DISPLAY_LAST_HEARTBEAT.store(now, Ordering::SeqCst);
     ADC_LAST_HEARTBEAT.store(now, Ordering::SeqCst);
 
-    println!("Health monitoring initialized for all subsystems");
+    info!("Health monitoring initialized for all subsystems");
 }
@@ -3,15 +3,17 @@ use embassy_sync::mutex::Mutex;
 use embassy_time::{Duration, Timer};
 use esp_hal::{
     analog::adc::{Adc, AdcConfig, Attenuation},
-    gpio::{Input, InputConfig, Level, Output, OutputConfig, Pull},
-    peripherals::{ADC1, GPIO0, GPIO2, GPIO25, GPIO34, GPIO36},
+    gpio::{Input, InputConfig, Level, Output, OutputConfig, OutputPin, Pull},
+    peripherals::{ADC1, GPIO0, GPIO2, GPIO34, GPIO35, GPIO36},
 };
 
+use crate::watering::BOARD_ZONES;
+
 pub async fn led_init(gpio: GPIO2<'static>) -> Output<'static> {
     Output::new(gpio, Level::Low, OutputConfig::default()) // Start with LED off
 }
 
-pub async fn compressor_init(gpio: GPIO25<'static>) -> Output<'static> {
+pub async fn compressor_init(gpio: impl OutputPin + 'static) -> Output<'static> {
     Output::new(gpio, Level::Low, OutputConfig::default())
 }
 
@@ -21,27 +23,33 @@ pub async fn btn_init(gpio: GPIO0<'static>) -> Input<'static> {
 
 type MainAdc = ADC1<'static>;
 type BatPin = GPIO36<'static>;
-type SensPin = GPIO34<'static>;
+/// Humidity sensors of the two zones, see [`BOARD_ZONES`]
+type SensPins = (GPIO34<'static>, GPIO35<'static>);
 
 static BAT_VAL: Mutex<CriticalSectionRawMutex, u16> = Mutex::new(0);
-static SENSOR_VAL: Mutex<CriticalSectionRawMutex, u16> = Mutex::new(0);
+static SENSOR_VAL: Mutex<CriticalSectionRawMutex, [u16; BOARD_ZONES]> =
+    Mutex::new([0; BOARD_ZONES]);
 
 //const ADC_REFRESH_TIME: Duration = Duration::from_secs(60);
 
 const ADC_REFRESH_TIME: Duration = Duration::from_millis(800);
 
 #[embassy_executor::task]
-pub async fn adc_task(battery_pin: BatPin, sensor_pin: SensPin, adc: MainAdc) {
+pub async fn adc_task(battery_pin: BatPin, sensor_pins: SensPins, adc: MainAdc) {
     let mut adc1_config = AdcConfig::new();
     let mut pin_bat = adc1_config.enable_pin(battery_pin, Attenuation::_11dB);
-    let mut pin_sensor = adc1_config.enable_pin(sensor_pin, Attenuation::_11dB);
+    let mut pin_sensor0 = adc1_config.enable_pin(sensor_pins.0, Attenuation::_11dB);
+    let mut pin_sensor1 = adc1_config.enable_pin(sensor_pins.1, Attenuation::_11dB);
     let mut adc = Adc::new(adc, adc1_config);
     loop {
         let bat_value: u16 = nb::block!(adc.read_oneshot(&mut pin_bat)).unwrap_or(4096);
-        let sens_value: u16 = nb::block!(adc.read_oneshot(&mut pin_sensor)).unwrap_or(4096);
+        let sens_values: [u16; BOARD_ZONES] = [
+            nb::block!(adc.read_oneshot(&mut pin_sensor0)).unwrap_or(4096),
+            nb::block!(adc.read_oneshot(&mut pin_sensor1)).unwrap_or(4096),
+        ];
 
         *BAT_VAL.lock().await = bat_value;
-        *SENSOR_VAL.lock().await = sens_value;
+        *SENSOR_VAL.lock().await = sens_values;
         Timer::after(ADC_REFRESH_TIME).await;
     }
 }
@@ -50,6 +58,6 @@ pub async fn get_battery_value() -> u16 {
     *BAT_VAL.lock().await
 }
 
-pub async fn get_sensor_value() -> u16 {
-    *SENSOR_VAL.lock().await
+pub async fn get_sensor_value(zone: usize) -> u16 {
+    SENSOR_VAL.lock().await.get(zone).copied().unwrap_or(0)
 }
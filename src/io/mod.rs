@@ -1,3 +1,5 @@
+pub use water_core::io::networks;
+
 pub mod gpio;
 pub mod i2c;
 pub mod led;
@@ -1,34 +1,169 @@
+use crate::config::{self, Key};
 use crate::display::{STATUS_LEN, update_status};
 use crate::error::SysError;
 use crate::io::led::{HEARTBEAT_DEFAULT, HEARTBEAT_NET_AWAIT, set_heartbeat};
+use crate::io::networks::{
+    AccessPoint, Bssid, Candidate, Disconnect, KnownNetwork, MAX_CANDIDATES, MAX_NETWORKS,
+    PASSWORD_LEN, ROAM_RSSI_THRESHOLD, SCAN_LEN, SSID_LEN, WifiStatus, is_auth_failure, rank,
+    should_roam,
+};
+use crate::provision::{PORTAL_PASSWORD_LEN, portal_password};
+use crate::time::now;
+use core::cmp::Reverse;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicI8, AtomicU8, AtomicU32, Ordering};
 // use alloc::string::ToString;
 use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
-use embassy_time::{Duration, Timer};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
 use esp_hal::{peripherals::WIFI, rng::Rng, timer::timg::Timer as HalTimer};
 use esp_println::println;
-use esp_radio::wifi::{ClientConfig, ModeConfig, ScanConfig, WifiEvent, WifiStaState};
+use esp_radio::wifi::event::{EventExt, StaDisconnected};
+use esp_radio::wifi::{
+    AccessPointConfig, AuthMethod, ClientConfig, ModeConfig, ScanConfig, WifiError, WifiEvent,
+    WifiStaState,
+};
 use esp_radio::{
     Controller, init,
     wifi::{Config, WifiController, WifiDevice, new},
 };
-use heapless::String;
+use heapless::{String, Vec};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 use static_cell::StaticCell;
 
-const SSID: &str = env!("SSID");
-const PASSWORD: &str = env!("PASSWORD");
+// Optional build-time credentials used until the device is provisioned
+const DEFAULT_SSID: Option<&str> = option_env!("SSID");
+const DEFAULT_PASSWORD: Option<&str> = option_env!("PASSWORD");
 
 const RECONNECT_DELAY: Duration = Duration::from_millis(5000);
-static WIFI_CONNECTED: Mutex<CriticalSectionRawMutex, bool> = Mutex::new(false);
+/// How often a connected station polls its signal
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Weak links look for a better access point this often
+const ROAM_SCAN_INTERVAL: Duration = Duration::from_secs(60);
+/// When the link came up, `None` while disconnected
+static CONNECTED_AT: Mutex<CriticalSectionRawMutex, Option<Instant>> = Mutex::new(None);
+static WIFI_STATUS: Mutex<CriticalSectionRawMutex, WifiStatus> = Mutex::new(WifiStatus::new());
+// Set by the event handler before `connect_async` returns
+static DISCONNECT_REASON: AtomicU8 = AtomicU8::new(0);
+static DISCONNECT_RSSI: AtomicI8 = AtomicI8::new(0);
+
+/// Failed connection attempts in a row before falling back to the setup
+/// portal, only until the first successful join. After that an outage just
+/// keeps the retries going, the portal must be asked for.
+const PORTAL_AFTER_FAILURES: u32 = 10;
+pub const PORTAL_SSID: &str = "water-setup";
+const PORTAL_PARK_TIME: Duration = Duration::from_secs(3600);
+static RECONNECTS: AtomicU32 = AtomicU32::new(0);
+static PORTAL_REQUESTED: AtomicBool = AtomicBool::new(false);
+// Persisted, so a reboot during an outage doesn't bring the portal back
+static JOINED_BEFORE: AtomicBool = AtomicBool::new(false);
+static PORTAL_READY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static PORTAL_PASSWORD: Mutex<CriticalSectionRawMutex, Option<String<PORTAL_PASSWORD_LEN>>> =
+    Mutex::new(None);
+
+/// Drop the station mode and bring up the setup access point
+pub fn request_portal() {
+    PORTAL_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Whether any network was ever joined with the stored settings
+pub fn set_joined_before(joined: bool) {
+    JOINED_BEFORE.store(joined, Ordering::Relaxed);
+}
+
+pub async fn set_portal_password(password: Option<String<PORTAL_PASSWORD_LEN>>) {
+    *PORTAL_PASSWORD.lock().await = password;
+}
+
+/// WPA2 password of the setup access point, random per device
+///
+/// Made up and stored the first time it is needed, it is only ever shown
+/// on the display and the console, so joining takes physical access.
+pub async fn get_portal_password() -> String<PORTAL_PASSWORD_LEN> {
+    let mut stored = PORTAL_PASSWORD.lock().await;
+    if let Some(password) = stored.as_ref() {
+        return password.clone();
+    }
+    let mut random = [0; PORTAL_PASSWORD_LEN];
+    Rng::new().read(&mut random);
+    let password = portal_password(random);
+    config::save(Key::PortalPassword, &password).await.ok();
+    stored.replace(password.clone());
+    password
+}
+
+/// Resolves once the setup access point is up
+pub async fn wait_for_portal() {
+    PORTAL_READY.wait().await
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WifiCredentials {
+    pub ssid: String<SSID_LEN>,
+    pub password: String<PASSWORD_LEN>,
+}
+
+impl WifiCredentials {
+    fn build_default() -> Option<Self> {
+        Some(WifiCredentials {
+            ssid: DEFAULT_SSID?.try_into().ok()?,
+            password: DEFAULT_PASSWORD.unwrap_or("").try_into().ok()?,
+        })
+    }
+}
+
+static CREDENTIALS: Mutex<CriticalSectionRawMutex, Option<WifiCredentials>> = Mutex::new(None);
+
+/// Stored credentials, or the build-time ones if nothing is stored
+pub async fn get_credentials() -> Option<WifiCredentials> {
+    CREDENTIALS
+        .lock()
+        .await
+        .clone()
+        .or_else(WifiCredentials::build_default)
+}
+
+pub async fn set_credentials(credentials: Option<WifiCredentials>) {
+    *CREDENTIALS.lock().await = credentials;
+}
+
+static NETWORKS: Mutex<CriticalSectionRawMutex, Vec<KnownNetwork, MAX_NETWORKS>> =
+    Mutex::new(Vec::new());
+
+/// Networks stored besides the provisioned one
+pub async fn get_networks() -> Vec<KnownNetwork, MAX_NETWORKS> {
+    NETWORKS.lock().await.clone()
+}
+
+pub async fn set_networks(networks: Vec<KnownNetwork, MAX_NETWORKS>) {
+    *NETWORKS.lock().await = networks;
+}
+
+/// Every network worth joining, the provisioned one has priority 0
+async fn known_networks() -> Vec<KnownNetwork, { MAX_NETWORKS + 1 }> {
+    let mut known: Vec<_, { MAX_NETWORKS + 1 }> = get_networks().await.into_iter().collect();
+    if let Some(credentials) = get_credentials().await {
+        known
+            .push(KnownNetwork {
+                ssid: credentials.ssid,
+                password: credentials.password,
+                priority: 0,
+            })
+            .ok();
+    }
+    known
+}
 
 pub async fn wifi_hw_init(
     _timer: HalTimer<'static>,
     _rng: Rng,
     wifi_peripheral: WIFI<'static>,
     spawner: &Spawner,
-) -> Result<WifiDevice<'static>, SysError> {
+) -> Result<(WifiDevice<'static>, WifiDevice<'static>), SysError> {
     // Initialize ESP WiFi hardware
     // Note: timer and rng parameters are preserved for API compatibility
     // but esp-radio 0.17 handles timing and randomness internally
@@ -40,58 +175,284 @@ pub async fn wifi_hw_init(
     let config = Config::default();
     let (controller, interfaces) = new(esp_wifi_ctrl, wifi_peripheral, config)?;
 
+    StaDisconnected::update_handler(|event| {
+        DISCONNECT_REASON.store(event.reason(), Ordering::Relaxed);
+        DISCONNECT_RSSI.store(event.rssi(), Ordering::Relaxed);
+    });
+
     spawner.spawn(maintain_connection(controller))?;
 
-    Ok(interfaces.sta)
+    Ok((interfaces.sta, interfaces.ap))
 }
 
 pub async fn is_wifi_connected() -> bool {
-    *WIFI_CONNECTED.lock().await
+    CONNECTED_AT.lock().await.is_some()
+}
+
+/// Latest RSSI of the link, `None` while disconnected
+pub async fn signal() -> Option<i8> {
+    WIFI_STATUS.lock().await.selected.as_ref().map(|ap| ap.rssi)
+}
+
+/// Link quality, recent disconnects and the last scan
+pub async fn wifi_status() -> WifiStatus {
+    let mut status = WIFI_STATUS.lock().await.clone();
+    status.connected_secs = CONNECTED_AT
+        .lock()
+        .await
+        .map(|since| since.elapsed().as_secs());
+    status.reconnects = reconnect_count();
+    status
+}
+
+/// Note the reason the event handler saw for the last disconnect
+async fn record_disconnect() -> u8 {
+    let reason = DISCONNECT_REASON.load(Ordering::Relaxed);
+    let disconnect = Disconnect {
+        uptime_secs: Instant::now().as_secs(),
+        timestamp: now().await.ok(),
+        reason,
+        rssi: DISCONNECT_RSSI.load(Ordering::Relaxed),
+    };
+    WIFI_STATUS.lock().await.record(disconnect);
+    reason
+}
+
+/// Successful connections after the first one since boot
+pub fn reconnect_count() -> u32 {
+    RECONNECTS.load(Ordering::Relaxed)
+}
+
+async fn run_portal(controller: &mut WifiController<'static>) -> ! {
+    update_status("Setup AP started").await.ok();
+    set_heartbeat(HEARTBEAT_NET_AWAIT);
+    if matches!(controller.is_started(), Ok(true)) {
+        controller.stop_async().await.ok();
+    }
+
+    let password = get_portal_password().await;
+    let ap_config = ModeConfig::AccessPoint(
+        AccessPointConfig::default()
+            .with_ssid(PORTAL_SSID.into())
+            .with_auth_method(AuthMethod::Wpa2Personal)
+            .with_password(password.as_str().into()),
+    );
+    if let Err(e) = controller.set_config(&ap_config) {
+        error!("Can't configure setup AP: {:?}", e);
+        esp_hal::system::software_reset();
+    }
+    if let Err(e) = controller.start_async().await {
+        error!("Can't start setup AP: {:?}", e);
+        esp_hal::system::software_reset();
+    }
+    println!(
+        "Setup portal on WiFi \"{}\", password {}",
+        PORTAL_SSID, password
+    );
+    PORTAL_READY.signal(());
+
+    // The portal reboots the device once it is done
+    loop {
+        Timer::after(PORTAL_PARK_TIME).await;
+    }
+}
+
+/// Scan, publish the strongest access points and rank the known ones
+async fn scan(
+    controller: &mut WifiController<'static>,
+    known: &[KnownNetwork],
+) -> Vec<Candidate, MAX_CANDIDATES> {
+    let mut found = match controller
+        .scan_with_config_async(ScanConfig::default())
+        .await
+    {
+        Ok(found) => found,
+        Err(e) => {
+            error!("WiFi scan failed: {:?}", e);
+            return Vec::new();
+        }
+    };
+    found.sort_unstable_by_key(|ap| Reverse(ap.signal_strength));
+
+    let access_points = found.iter().filter_map(|ap| {
+        Some(AccessPoint {
+            ssid: ap.ssid.as_str().try_into().ok()?,
+            bssid: Bssid(ap.bssid),
+            channel: ap.channel,
+            rssi: ap.signal_strength,
+        })
+    });
+    WIFI_STATUS.lock().await.scan = access_points.clone().take(SCAN_LEN).collect();
+    rank(known, access_points)
+}
+
+/// Try the candidates in order, skipping networks which refused the password
+///
+/// Without any known network in sight the networks are tried by name, hidden
+/// ones don't show up in scans.
+async fn connect(
+    controller: &mut WifiController<'static>,
+    known: &[KnownNetwork],
+    candidates: &[Candidate],
+) -> Result<AccessPoint, WifiError> {
+    let mut blind: Vec<Candidate, { MAX_NETWORKS + 1 }> = Vec::new();
+    if candidates.is_empty() {
+        for (network, entry) in known.iter().enumerate() {
+            let ap = AccessPoint {
+                ssid: entry.ssid.clone(),
+                bssid: Bssid::default(),
+                channel: 0,
+                rssi: 0,
+            };
+            blind.push(Candidate { network, ap }).ok();
+        }
+        blind.sort_unstable_by_key(|candidate| Reverse(known[candidate.network].priority));
+    }
+
+    let mut refused = [false; MAX_NETWORKS + 1];
+    let mut error = WifiError::Disconnected;
+    for candidate in candidates.iter().chain(blind.iter()) {
+        if refused[candidate.network] {
+            continue;
+        }
+        let network = &known[candidate.network];
+        let mut client = ClientConfig::default()
+            .with_ssid(network.ssid.as_str().into())
+            .with_password(network.password.as_str().into());
+        // Scanned candidates are pinned to their access point
+        if candidate.ap.channel != 0 {
+            client = client
+                .with_bssid(candidate.ap.bssid.0)
+                .with_channel(candidate.ap.channel);
+        }
+        controller.set_config(&ModeConfig::Client(client))?;
+
+        info!(
+            "WiFi: joining {} via {} ({} dBm)",
+            network.ssid, candidate.ap.bssid, candidate.ap.rssi
+        );
+        // A failure without its own event must not inherit an older reason
+        DISCONNECT_REASON.store(0, Ordering::Relaxed);
+        if let Err(e) = controller.connect_async().await {
+            let reason = record_disconnect().await;
+            warn!("WiFi: {} failed, reason {}", network.ssid, reason);
+            refused[candidate.network] = is_auth_failure(reason);
+            error = e;
+            continue;
+        }
+
+        let mut ap = candidate.ap.clone();
+        if let Ok(rssi) = controller.rssi() {
+            ap.rssi = rssi as i8;
+        }
+        return Ok(ap);
+    }
+    Err(error)
+}
+
+/// Poll the signal and drop a weak link if a clearly stronger access point
+/// is around
+///
+/// Returns whether the station disconnected to roam.
+async fn check_signal(controller: &mut WifiController<'static>, last_scan: &mut Instant) -> bool {
+    let Ok(rssi) = controller.rssi() else {
+        return false;
+    };
+    let current = {
+        let mut status = WIFI_STATUS.lock().await;
+        let Some(selected) = status.selected.as_mut() else {
+            return false;
+        };
+        selected.rssi = rssi as i8;
+        selected.clone()
+    };
+    if current.rssi >= ROAM_RSSI_THRESHOLD || last_scan.elapsed() < ROAM_SCAN_INTERVAL {
+        return false;
+    }
+
+    *last_scan = Instant::now();
+    let known = known_networks().await;
+    let candidates = scan(controller, &known).await;
+    let Some(best) = candidates.first() else {
+        return false;
+    };
+    if !should_roam(&current, &best.ap) {
+        return false;
+    }
+    info!(
+        "WiFi: roaming from {} ({} dBm) to {} ({} dBm)",
+        current.bssid, current.rssi, best.ap.bssid, best.ap.rssi
+    );
+    controller.disconnect_async().await.is_ok()
 }
 
 // We have to run this function in the background to keep the wifi on
 #[embassy_executor::task]
 async fn maintain_connection(mut controller: WifiController<'static>) {
+    let mut failures = 0;
+    let mut connected_before = false;
+    let mut last_scan = Instant::now();
     loop {
+        if PORTAL_REQUESTED.load(Ordering::Relaxed)
+            || (failures >= PORTAL_AFTER_FAILURES && !JOINED_BEFORE.load(Ordering::Relaxed))
+        {
+            run_portal(&mut controller).await;
+        }
+
         if esp_radio::wifi::sta_state() == WifiStaState::Connected {
-            // wait until we're no longer connected
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
+            // wait until we're no longer connected, checking the signal meanwhile
+            let disconnected = controller.wait_for_event(WifiEvent::StaDisconnected);
+            if let Either::Second(()) =
+                select(disconnected, Timer::after(SIGNAL_CHECK_INTERVAL)).await
+                && !check_signal(&mut controller, &mut last_scan).await
+                && esp_radio::wifi::sta_state() == WifiStaState::Connected
+            {
+                continue;
+            }
+            let reason = record_disconnect().await;
+            info!("WiFi: disconnected, reason {}", reason);
             update_status("WiFi disconnected").await.ok();
             set_heartbeat(HEARTBEAT_NET_AWAIT);
-            WIFI_CONNECTED.lock().await.clone_from(&false);
+            CONNECTED_AT.lock().await.take();
+            WIFI_STATUS.lock().await.selected = None;
             Timer::after(RECONNECT_DELAY).await
         }
 
+        let known = known_networks().await;
+        if known.is_empty() {
+            run_portal(&mut controller).await;
+        }
+
         if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = ModeConfig::Client(
-                ClientConfig::default()
-                    .with_ssid(SSID.into())
-                    .with_password(PASSWORD.into()),
-            );
+            let client_config = ModeConfig::Client(ClientConfig::default());
             controller.set_config(&client_config).unwrap();
             update_status("Starting WiFi").await.ok();
             controller.start_async().await.unwrap();
-            update_status("WiFi scan").await.ok();
-            let scan_config = ScanConfig::default();
-            let result = controller
-                .scan_with_config_async(scan_config)
-                .await
-                .unwrap();
-            for ap in result.iter().take(5) {
-                // Limit to first 5 APs to avoid too much output
-                println!("Found AP: {:?}", ap.ssid);
-            }
         }
 
+        update_status("WiFi scan").await.ok();
+        let candidates = scan(&mut controller, &known).await;
+        last_scan = Instant::now();
+
         update_status("Connecting to WiFi").await.ok();
 
-        match controller.connect_async().await {
-            Ok(_) => {
+        match connect(&mut controller, &known, &candidates).await {
+            Ok(ap) => {
+                failures = 0;
+                if !JOINED_BEFORE.swap(true, Ordering::Relaxed) {
+                    config::save(Key::WifiJoined, &true).await.ok();
+                }
+                if core::mem::replace(&mut connected_before, true) {
+                    RECONNECTS.fetch_add(1, Ordering::Relaxed);
+                }
                 update_status("Wifi connected!").await.ok();
+                info!("WiFi: connected to {} via {}", ap.ssid, ap.bssid);
                 set_heartbeat(HEARTBEAT_DEFAULT);
-                WIFI_CONNECTED.lock().await.clone_from(&true);
+                CONNECTED_AT.lock().await.replace(Instant::now());
+                WIFI_STATUS.lock().await.selected = Some(ap);
             }
             Err(e) => {
+                failures += 1;
                 set_heartbeat(HEARTBEAT_NET_AWAIT);
                 let mut errstring: String<STATUS_LEN> = String::new();
                 write!(errstring, "WiFi fail: {:?}", e).ok();
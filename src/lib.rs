@@ -1,12 +1,15 @@
 #![cfg_attr(not(test), no_std)]
 pub mod appcore;
 pub mod command;
+pub mod config;
 pub mod display;
 pub mod error;
 pub mod health;
 pub mod io;
+pub mod logger;
 pub mod net;
 pub mod power;
+pub mod provision;
 pub mod time;
 pub mod watchdog;
 pub mod watering;
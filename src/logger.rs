@@ -0,0 +1,125 @@
+//! `log` backend printing to the UART and queueing records for MQTT
+//!
+//! Logging never waits, records which don't fit the queue are counted and
+//! dropped. Set the level for the UART with `ESP_LOG` at build time and the
+//! remote one with the `SetLogLevel` command.
+
+use core::fmt::Write;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use esp_println::println;
+use heapless::String;
+use log::{LevelFilter, Log, Metadata, Record};
+
+pub const LOG_LINE_LEN: usize = 160;
+pub const LOG_QUEUE_LEN: usize = 16;
+const DEFAULT_REMOTE_LEVEL: LevelFilter = LevelFilter::Warn;
+// Their debug output would describe publishing our own log lines
+const NETWORK_TARGETS: [&str; 5] = [
+    "embassy_net",
+    "smoltcp",
+    "esp_radio",
+    "rust_mqtt",
+    "embedded_tls",
+];
+
+pub type LogLine = String<LOG_LINE_LEN>;
+
+static QUEUE: Channel<CriticalSectionRawMutex, LogLine, LOG_QUEUE_LEN> = Channel::new();
+static UART_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+static REMOTE_LEVEL: AtomicUsize = AtomicUsize::new(DEFAULT_REMOTE_LEVEL as usize);
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+fn level_filter(level: usize) -> LevelFilter {
+    match level {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+fn uart_level() -> LevelFilter {
+    level_filter(UART_LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn remote_level() -> LevelFilter {
+    level_filter(REMOTE_LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn set_remote_level(level: LevelFilter) {
+    REMOTE_LEVEL.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(uart_level().max(level));
+}
+
+/// Records lost to a full queue since boot
+pub fn dropped() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Next record for the log topic, dropping the wait loses nothing
+pub async fn next_line() -> LogLine {
+    QUEUE.receive().await
+}
+
+/// A record already queued behind the one [`next_line`] returned
+pub fn queued_line() -> Option<LogLine> {
+    QUEUE.try_receive().ok()
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= uart_level().max(remote_level())
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() <= uart_level() {
+            println!("{} - {}", record.level(), record.args());
+        }
+
+        let quiet = record.level() > LevelFilter::Info
+            && NETWORK_TARGETS
+                .iter()
+                .any(|target| record.target().starts_with(target));
+        if record.level() > remote_level() || quiet {
+            return;
+        }
+        let mut line = LogLine::new();
+        // Overlong messages are cut
+        write!(
+            line,
+            "{} {}: {}",
+            record.level(),
+            record.target(),
+            record.args()
+        )
+        .ok();
+        if QUEUE.try_send(line).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Takes the place of `esp_println::logger::init_logger_from_env`
+pub fn init() {
+    // Only the global level of `ESP_LOG` is supported, e.g. `info`
+    let level = option_env!("ESP_LOG")
+        .and_then(|filter| filter.split(',').next())
+        .and_then(|level| LevelFilter::from_str(level.trim()).ok())
+        .unwrap_or(LevelFilter::Info);
+    UART_LEVEL.store(level as usize, Ordering::Relaxed);
+
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level.max(remote_level()));
+    }
+}
@@ -0,0 +1,208 @@
+//! mDNS responder and DNS-SD lookups on the station interface
+//!
+//! The device answers as `<hostname>.local` and announces its REST API as
+//! `_water._tcp` and `_http._tcp`. Without a configured host the MQTT
+//! broker is looked up as `_mqtt._tcp` (`_secure-mqtt._tcp` with TLS).
+
+use core::fmt::Write;
+use core::net::Ipv4Addr;
+use embassy_net::Stack;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_time::{Duration, Timer, with_timeout};
+use heapless::String;
+use log::{error, info};
+
+use crate::error::NetError;
+use crate::net::mdns::{self, Host, Instance, Service, TYPE_A, TYPE_PTR};
+use crate::net::mqtt::device_id;
+use crate::net::rest::HTTP_PORT;
+use crate::net::stack::get_hostname;
+use crate::net::topics::DEVICE_ID_LEN;
+
+const PACKET_LEN: usize = 1024;
+const ANNOUNCEMENTS: usize = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+// How often the responder checks whether the address changed
+const ADDRESS_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const QUERY_ATTEMPTS: usize = 3;
+
+/// Ask for `name` until a response satisfies `parse`
+async fn ask<T>(
+    socket: &mut UdpSocket<'_>,
+    name: &str,
+    qtype: u16,
+    parse: impl Fn(&[u8]) -> Option<T>,
+) -> Result<T, NetError> {
+    let mut packet = [0u8; PACKET_LEN];
+    for _ in 0..QUERY_ATTEMPTS {
+        let len = mdns::query(name, qtype, &mut packet).ok_or(NetError::Resolve)?;
+        socket
+            .send_to(&packet[..len], (mdns::GROUP, mdns::PORT))
+            .await
+            .map_err(|_| NetError::Socket)?;
+
+        let answer = with_timeout(QUERY_TIMEOUT, async {
+            loop {
+                // Oversized or unrelated packets are skipped
+                if let Ok((len, _)) = socket.recv_from(&mut packet).await
+                    && let Some(found) = parse(&packet[..len])
+                {
+                    return found;
+                }
+            }
+        })
+        .await;
+        if let Ok(found) = answer {
+            return Ok(found);
+        }
+    }
+    Err(NetError::Resolve)
+}
+
+async fn with_socket<T>(
+    stack: Stack<'_>,
+    lookup: impl AsyncFnOnce(&mut UdpSocket<'_>) -> Result<T, NetError>,
+) -> Result<T, NetError> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; PACKET_LEN];
+    let mut tx_buffer = [0; PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    // Queries from an ephemeral port get unicast replies
+    socket.bind(0).map_err(|_| NetError::Socket)?;
+    lookup(&mut socket).await
+}
+
+/// Address of a `.local` host
+pub async fn resolve(stack: Stack<'_>, host: &str) -> Result<Ipv4Addr, NetError> {
+    with_socket(stack, async |socket| {
+        ask(socket, host, TYPE_A, |packet| {
+            mdns::find_address(packet, host)
+        })
+        .await
+    })
+    .await
+}
+
+/// First instance of `service` (e.g. `_mqtt._tcp.local`) with its address
+pub async fn find_service(
+    stack: Stack<'_>,
+    service: &str,
+) -> Result<(Instance, Ipv4Addr), NetError> {
+    with_socket(stack, async |socket| {
+        let instance = ask(socket, service, TYPE_PTR, |packet| {
+            mdns::find_instance(packet, service)
+        })
+        .await?;
+        let ip = match instance.ip {
+            Some(ip) => ip,
+            // The responder left out the address record
+            None => {
+                let host = instance.host.as_str();
+                ask(socket, host, TYPE_A, |packet| {
+                    mdns::find_address(packet, host)
+                })
+                .await?
+            }
+        };
+        info!(
+            "mDNS: found {} at {}:{}",
+            service, instance.host, instance.port
+        );
+        Ok((instance, ip))
+    })
+    .await
+}
+
+/// Answer queries for this device until the end of time
+#[embassy_executor::task]
+pub async fn mdns_task(stack: Stack<'static>) {
+    if let Err(e) = stack.join_multicast_group(mdns::GROUP) {
+        error!("mDNS: can't join group: {:?}", e);
+        return;
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; PACKET_LEN];
+    let mut tx_buffer = [0; PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if socket.bind(mdns::PORT).is_err() {
+        error!("mDNS: can't bind");
+        return;
+    }
+
+    let name = get_hostname().await;
+    let mut id = String::<{ DEVICE_ID_LEN + 3 }>::new();
+    write!(id, "id={}", device_id()).ok();
+    let water_txt = [id.as_str(), "path=/status"];
+    let services = [
+        Service {
+            name: "_water._tcp",
+            port: HTTP_PORT,
+            txt: &water_txt,
+        },
+        Service {
+            name: "_http._tcp",
+            port: HTTP_PORT,
+            txt: &["path=/status"],
+        },
+    ];
+
+    let mut announced = None;
+    let mut query = [0u8; PACKET_LEN];
+    let mut reply = [0u8; PACKET_LEN];
+    let group = (mdns::GROUP, mdns::PORT);
+    loop {
+        let Some(config) = stack.config_v4() else {
+            Timer::after(ADDRESS_CHECK_INTERVAL).await;
+            continue;
+        };
+        let host = Host {
+            name: &name,
+            ip: config.address.address(),
+            services: &services,
+        };
+
+        if announced != Some(host.ip) {
+            info!("mDNS: announcing {}.local", name);
+            for _ in 0..ANNOUNCEMENTS {
+                if let Some(len) = host.announce(&mut reply) {
+                    socket.send_to(&reply[..len], group).await.ok();
+                }
+                Timer::after(ANNOUNCE_INTERVAL).await;
+            }
+            announced = Some(host.ip);
+        }
+
+        // Timeouts recheck the address, oversized queries are dropped
+        let Ok(Ok((len, meta))) =
+            with_timeout(ADDRESS_CHECK_INTERVAL, socket.recv_from(&mut query)).await
+        else {
+            continue;
+        };
+        // Legacy resolvers query from other ports and only listen there
+        let legacy = meta.endpoint.port != mdns::PORT;
+        if let Some(answer) = host.respond(&query[..len], legacy, &mut reply) {
+            let destination = if answer.unicast {
+                meta.endpoint
+            } else {
+                group.into()
+            };
+            socket.send_to(&reply[..answer.len], destination).await.ok();
+        }
+    }
+}
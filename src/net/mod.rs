@@ -1,3 +1,10 @@
+pub use water_core::net::{
+    api, connection, dhcp, dns, homeassistant, http, ipconfig, mdns, metrics, sntp, topics,
+};
+
+pub mod discovery;
 pub mod mqtt;
 pub mod ntp;
+pub mod rest;
 pub mod stack;
+pub mod tls;
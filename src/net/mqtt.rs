@@ -1,30 +1,94 @@
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_futures::select::{Either4, select, select4};
 use embassy_net::dns::DnsQueryType;
 use embassy_net::icmp::PacketMetadata;
 use embassy_net::icmp::ping::{PingManager, PingParams};
 use embassy_net::tcp::TcpSocket;
-use embassy_net::{IpEndpoint, Stack};
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_net::{IpAddress, IpEndpoint, Stack};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
 use embassy_sync::mutex::Mutex;
-use embassy_time::Duration;
+use embassy_sync::signal::Signal;
 use embassy_time::Timer;
+use embassy_time::{Duration, Instant};
+use esp_hal::efuse::Efuse;
 use esp_hal::rng::Rng;
 use heapless::String;
+use log::warn;
 use rust_mqtt::client::client::MqttClient;
 use rust_mqtt::client::client_config::ClientConfig;
 use rust_mqtt::packet::v5::publish_packet::QualityOfService;
+use rust_mqtt::packet::v5::reason_codes::ReasonCode;
+use serde::{Deserialize, Serialize};
 
-use crate::command::Command;
-use crate::command::status::get_status;
-use crate::error::{ConversionError, NetError, SysError};
+use crate::command;
+use crate::command::history::{self, SAMPLE_LEN};
+use crate::command::status::{STATUS_JSON_LEN, get_status};
+use crate::error::NetError;
+use crate::logger::{self, LOG_QUEUE_LEN};
+use crate::net::connection::{ConnectionState, ConnectionStatus, Failure};
+use crate::net::discovery;
+use crate::net::homeassistant::{self, CONFIG_LEN, Device, Entity};
+use crate::net::tls::{self, MQTT_TLS_PORT, TLS_READ_RECORD_LEN, TLS_WRITE_RECORD_LEN};
+use crate::net::topics::{BROADCAST_ID, DEFAULT_PREFIX, DEVICE_ID_LEN, PREFIX_LEN, Topics};
 
-const MQTT_STATUS_LEN: usize = 10;
 static LATENCY: Mutex<CriticalSectionRawMutex, Duration> = Mutex::new(Duration::from_secs(0));
-static STATUS: Mutex<CriticalSectionRawMutex, String<MQTT_STATUS_LEN>> =
-    Mutex::new(String::<MQTT_STATUS_LEN>::new());
 
-pub async fn mqtt_status() -> Result<String<MQTT_STATUS_LEN>, ConversionError> {
-    Ok(STATUS.lock().await.clone())
+struct Connection {
+    state: ConnectionState,
+    since: Instant,
+    attempts: u32,
+    failures: u32,
+    last_failure: Option<Failure>,
+}
+
+static CONNECTION: Mutex<CriticalSectionRawMutex, Connection> = Mutex::new(Connection {
+    state: ConnectionState::Idle,
+    since: Instant::from_ticks(0),
+    attempts: 0,
+    failures: 0,
+    last_failure: None,
+});
+
+async fn enter(state: ConnectionState) {
+    let mut connection = CONNECTION.lock().await;
+    if state == ConnectionState::Resolving {
+        connection.attempts += 1;
+    }
+    if let ConnectionState::BackingOff { reason, .. } = state {
+        connection.failures += 1;
+        connection.last_failure = Some(reason);
+    }
+    connection.state = state;
+    connection.since = Instant::now();
+}
+
+pub async fn connection_status() -> ConnectionStatus {
+    let connection = CONNECTION.lock().await;
+    let state_secs = connection.since.elapsed().as_secs();
+    let state = match connection.state {
+        ConnectionState::BackingOff {
+            reason,
+            retry_in_secs,
+        } => ConnectionState::BackingOff {
+            reason,
+            retry_in_secs: retry_in_secs.saturating_sub(state_secs),
+        },
+        state => state,
+    };
+    ConnectionStatus {
+        state,
+        state_secs,
+        attempts: connection.attempts,
+        failures: connection.failures,
+        last_failure: connection.last_failure,
+    }
+}
+
+/// Log the details, the state only keeps the step that failed
+fn failed(step: Failure, error: impl core::fmt::Debug) -> Failure {
+    warn!("MQTT: {:?} failed: {:?}", step, error);
+    step
 }
 
 async fn measure_latency(stack: &Stack<'_>) -> Result<Duration, NetError> {
@@ -64,49 +128,393 @@ pub async fn latency() -> Result<u64, NetError> {
     }
 }
 
-const MQTT_REFRESH_TIME: Duration = Duration::from_secs(10);
-const MQTT_ERR_REFRESH_TIME: Duration = Duration::from_secs(5);
-const MQTT_SERVER: &str = "raspberrypi.jp.home.rayslava.com";
-const MQTT_USER: &str = env!("MQTT_USER");
-const MQTT_PASSWORD: &str = env!("MQTT_PASSWORD");
+const DEFAULT_TELEMETRY_INTERVAL: Duration = Duration::from_secs(10);
+const MQTT_KEEP_ALIVE_SECS: u16 = 60;
+// Send something well within the keep alive so the broker never drops us
+const MQTT_PING_INTERVAL: Duration = Duration::from_secs(MQTT_KEEP_ALIVE_SECS as u64 / 2);
+const MQTT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const MQTT_BACKOFF_MAX: Duration = Duration::from_secs(300);
+// Optional build-time broker, found through mDNS when there is none
+const DEFAULT_MQTT_SERVER: Option<&str> = option_env!("MQTT_HOST");
+// Broker of units built before DNS-SD discovery, tried when none is announced
+pub const LEGACY_MQTT_SERVER: &str = "raspberrypi.jp.home.rayslava.com";
+const MQTT_SERVICE: &str = "_mqtt._tcp.local";
+const MQTT_TLS_SERVICE: &str = "_secure-mqtt._tcp.local";
+pub const MQTT_HOST_LEN: usize = 64;
+// Optional build-time credentials used until the device is provisioned
+const DEFAULT_MQTT_USER: Option<&str> = option_env!("MQTT_USER");
+const DEFAULT_MQTT_PASSWORD: Option<&str> = option_env!("MQTT_PASSWORD");
 const MQTT_PORT: u16 = 1883;
-const MQTT_CLIENT_ID: &str = "water_machine";
-const MQTT_TOPIC: &str = "water/status";
-const MQTT_BUFFER_SIZE: usize = 1024;
+const MQTT_ACK_LEN: usize = 192;
+const PAYLOAD_ONLINE: &[u8] = b"online";
+const PAYLOAD_OFFLINE: &[u8] = b"offline";
+// A status report with the publish header
+const MQTT_BUFFER_SIZE: usize = STATUS_JSON_LEN + 256;
+const DEVICE_NAME: &str = "Watering machine";
+pub const CLIENT_ID_LEN: usize = DEVICE_ID_LEN + 6;
+
+/// The factory MAC in hex, stable across reflashes
+pub fn device_id() -> String<DEVICE_ID_LEN> {
+    let mut id = String::new();
+    for byte in Efuse::mac_address() {
+        write!(id, "{:02x}", byte).ok();
+    }
+    id
+}
+
+/// Unique on the broker, so several units don't kick each other off
+pub fn client_id() -> String<CLIENT_ID_LEN> {
+    let mut id = String::new();
+    write!(id, "water_{}", device_id()).ok();
+    id
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MqttCredentials {
+    pub user: String<32>,
+    pub password: String<64>,
+}
+
+impl MqttCredentials {
+    fn build_default() -> Option<Self> {
+        Some(MqttCredentials {
+            user: DEFAULT_MQTT_USER?.try_into().ok()?,
+            password: DEFAULT_MQTT_PASSWORD.unwrap_or("").try_into().ok()?,
+        })
+    }
+}
+
+static CREDENTIALS: Mutex<CriticalSectionRawMutex, Option<MqttCredentials>> = Mutex::new(None);
+
+/// Stored credentials, or the build-time ones if nothing is stored
+pub async fn get_credentials() -> Option<MqttCredentials> {
+    CREDENTIALS
+        .lock()
+        .await
+        .clone()
+        .or_else(MqttCredentials::build_default)
+}
+
+pub async fn set_credentials(credentials: Option<MqttCredentials>) {
+    *CREDENTIALS.lock().await = credentials;
+}
+
+static SERVER: Mutex<CriticalSectionRawMutex, Option<String<MQTT_HOST_LEN>>> = Mutex::new(None);
+
+/// Broker host name, the build-time one unless provisioned
+///
+/// `None` leaves finding the broker to DNS-SD.
+pub async fn get_server() -> Option<String<MQTT_HOST_LEN>> {
+    SERVER
+        .lock()
+        .await
+        .clone()
+        .or_else(|| DEFAULT_MQTT_SERVER?.try_into().ok())
+}
+
+pub async fn set_server(server: Option<String<MQTT_HOST_LEN>>) {
+    *SERVER.lock().await = server;
+}
+
+static TELEMETRY_INTERVAL: Mutex<CriticalSectionRawMutex, Duration> =
+    Mutex::new(DEFAULT_TELEMETRY_INTERVAL);
+static PUBLISH_NOW: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static SOCKET_TIMEOUT: Mutex<CriticalSectionRawMutex, Option<Duration>> = Mutex::new(None);
+
+pub async fn get_telemetry_interval() -> Duration {
+    *TELEMETRY_INTERVAL.lock().await
+}
+
+/// The configured timeout, or three missed exchanges, counting publishes
+/// and keepalives
+///
+/// Applied to the next connection.
+pub async fn get_socket_timeout() -> Duration {
+    match *SOCKET_TIMEOUT.lock().await {
+        Some(timeout) => timeout,
+        None => get_telemetry_interval().await.min(MQTT_PING_INTERVAL) * 3,
+    }
+}
+
+/// `None` derives it from the reporting interval
+pub async fn set_socket_timeout(timeout: Option<Duration>) {
+    *SOCKET_TIMEOUT.lock().await = timeout;
+}
+
+/// Configured socket timeout, `None` while derived
+pub async fn socket_timeout_setting() -> Option<Duration> {
+    *SOCKET_TIMEOUT.lock().await
+}
+
+/// Takes effect right away, the next report goes out immediately
+pub async fn set_telemetry_interval(interval: Duration) {
+    *TELEMETRY_INTERVAL.lock().await = interval;
+    request_publish();
+}
+
+/// Publish the status without waiting for the telemetry interval
+pub fn request_publish() {
+    PUBLISH_NOW.signal(());
+}
+
+static TOPIC_PREFIX: Mutex<CriticalSectionRawMutex, Option<String<PREFIX_LEN>>> = Mutex::new(None);
+
+pub async fn get_topic_prefix() -> String<PREFIX_LEN> {
+    TOPIC_PREFIX
+        .lock()
+        .await
+        .clone()
+        .unwrap_or_else(|| DEFAULT_PREFIX.try_into().unwrap_or_default())
+}
+
+/// Used from the next connection on
+pub async fn set_topic_prefix(prefix: Option<String<PREFIX_LEN>>) {
+    *TOPIC_PREFIX.lock().await = prefix;
+}
+
+pub async fn topics() -> Topics {
+    Topics::new(&get_topic_prefix().await, &device_id())
+}
+
+// Set while a session is up, offline samples go to the history otherwise
+static ONLINE: AtomicBool = AtomicBool::new(false);
+
+static TLS: Mutex<CriticalSectionRawMutex, bool> = Mutex::new(false);
+
+pub async fn get_tls() -> bool {
+    *TLS.lock().await
+}
+
+pub async fn set_tls(tls: bool) {
+    *TLS.lock().await = tls;
+}
+
+/// Retained discovery configs so Home Assistant picks the device up by itself
+async fn publish_discovery<T: embedded_io_async::Read + embedded_io_async::Write>(
+    client: &mut MqttClient<'_, T, 10, Rng>,
+    topics: &Topics,
+) -> Result<(), ReasonCode> {
+    let id = client_id();
+    let device = Device {
+        id: &id,
+        name: DEVICE_NAME,
+        sw_version: env!("CARGO_PKG_VERSION"),
+        state_topic: &topics.status,
+        // Home Assistant can't sign, its controls would only get rejected
+        command_topic: (!command::has_command_key().await).then_some(topics.control.as_str()),
+        availability_topic: &topics.availability,
+    };
+
+    let mut payload = [0u8; CONFIG_LEN];
+    for entity in Entity::all() {
+        // An empty retained config removes one published before the key was set
+        let len = homeassistant::config_payload(&device, entity, &mut payload).unwrap_or(0);
+        let topic = homeassistant::config_topic(&device, entity);
+        client
+            .send_message(&topic, &payload[..len], QualityOfService::QoS1, true)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Oldest first, a sample is only dropped once the broker took it
+async fn replay_history<T: embedded_io_async::Read + embedded_io_async::Write>(
+    client: &mut MqttClient<'_, T, 10, Rng>,
+    topics: &Topics,
+) -> Result<(), ReasonCode> {
+    let mut msg = [0u8; SAMPLE_LEN];
+    while let Some(len) = history::peek_oldest(&mut msg).await {
+        client
+            .send_message(&topics.history, &msg[..len], QualityOfService::QoS1, false)
+            .await?;
+        history::remove_oldest().await;
+    }
+    Ok(())
+}
 
-async fn update_mqtt(
+/// Sample at the telemetry interval whenever no session is up
+async fn record_offline(next_sample: &mut Instant) -> ! {
+    loop {
+        Timer::at(*next_sample).await;
+        *next_sample = Instant::now() + get_telemetry_interval().await;
+        if !ONLINE.load(Ordering::Relaxed)
+            && let Some(sample) = history::get_sample().await
+        {
+            history::record(sample).await;
+        }
+    }
+}
+
+/// Name to verify the certificate against, address and port of the broker
+async fn resolve_broker(
+    stack: Stack<'_>,
+    use_tls: bool,
+) -> Result<(String<MQTT_HOST_LEN>, IpAddress, u16), NetError> {
+    let port = if use_tls { MQTT_TLS_PORT } else { MQTT_PORT };
+    if let Some(server) = get_server().await {
+        let address = resolve_host(stack, &server).await?;
+        return Ok((server, address, port));
+    }
+
+    let service = if use_tls {
+        MQTT_TLS_SERVICE
+    } else {
+        MQTT_SERVICE
+    };
+    match discovery::find_service(stack, service).await {
+        Ok((instance, address)) => {
+            let host = instance
+                .host
+                .as_str()
+                .try_into()
+                .map_err(|_| NetError::Resolve)?;
+            Ok((host, address.into(), instance.port))
+        }
+        Err(e) => {
+            warn!(
+                "MQTT: no {} announced ({:?}), trying {}",
+                service, e, LEGACY_MQTT_SERVER
+            );
+            let address = resolve_host(stack, LEGACY_MQTT_SERVER).await?;
+            Ok((LEGACY_MQTT_SERVER.try_into().unwrap(), address, port))
+        }
+    }
+}
+
+async fn resolve_host(stack: Stack<'_>, host: &str) -> Result<IpAddress, NetError> {
+    if host.ends_with(".local") {
+        return Ok(discovery::resolve(stack, host).await?.into());
+    }
+    let addresses = stack.dns_query(host, DnsQueryType::A).await?;
+    addresses.first().copied().ok_or(NetError::Resolve)
+}
+
+/// Connect, subscribe and serve the session until the link drops
+async fn run_session(
     config: ClientConfig<'_, 10, Rng>,
+    topics: &Topics,
     stack: &'static Stack<'static>,
-) -> Result<(), SysError> {
+    backoff: &mut Duration,
+) -> Result<(), Failure> {
     let mut rx_buffer = [0; 4096];
     let mut tx_buffer = [0; 4096];
 
     let mut socket = TcpSocket::new(*stack, &mut rx_buffer, &mut tx_buffer);
-    socket.set_timeout(Some(MQTT_REFRESH_TIME));
+    socket.set_timeout(Some(get_socket_timeout().await));
 
-    let address = match stack.dns_query(MQTT_SERVER, DnsQueryType::A).await {
-        Ok(addr) => addr,
-        Err(e) => {
-            let mut status = STATUS.lock().await;
-            status.clear();
-            write!(status, "{:?}", e).ok();
-            return Err(SysError::Net(NetError::Resolve));
-        }
+    enter(ConnectionState::Resolving).await;
+    let use_tls = get_tls().await;
+    let (server, address, port) = match resolve_broker(*stack, use_tls).await {
+        Ok(broker) => broker,
+        Err(e) => return Err(failed(Failure::Resolve, e)),
     };
 
-    let remote_endpoint: IpEndpoint = (address[0], MQTT_PORT).into();
+    let remote_endpoint: IpEndpoint = (address, port).into();
+    enter(ConnectionState::Connecting).await;
     if let Err(e) = socket.connect(remote_endpoint).await {
-        let mut status = STATUS.lock().await;
-        status.clear();
-        write!(status, "{:?}", e).ok();
-        return Err(SysError::Net(NetError::Socket));
+        return Err(failed(Failure::Socket, e));
+    }
+
+    if !use_tls {
+        return serve_session(socket, config, topics, stack, backoff).await;
+    }
+
+    let mut read_record = [0; TLS_READ_RECORD_LEN];
+    let mut write_record = [0; TLS_WRITE_RECORD_LEN];
+    match tls::connect(socket, &server, &mut read_record, &mut write_record).await {
+        Ok(connection) => serve_session(connection, config, topics, stack, backoff).await,
+        Err(e) => Err(failed(Failure::Tls, e)),
+    }
+}
+
+/// Transport shared between the MQTT client and the session loop
+///
+/// `rust-mqtt` loses its place in the stream when a receive is dropped
+/// halfway through a packet. The loop instead waits for the first byte
+/// here, and only then starts a receive that runs to completion.
+struct Link<T> {
+    transport: T,
+    peeked: Option<u8>,
+}
+
+impl<T: embedded_io_async::Read> Link<T> {
+    /// Wait until input is pending, an error or EOF is left to the receive
+    ///
+    /// Only this single-byte read is ever cancelled, and dropping it loses
+    /// nothing on either transport:
+    /// - `TcpSocket::read` only dequeues bytes from the socket buffer in the
+    ///   poll that returns them, a pending read holds none.
+    /// - `TlsConnection::read` collects a record in its own read buffer,
+    ///   counting what arrived so far in the connection rather than in the
+    ///   future. A dropped read therefore leaves a partial record where the
+    ///   next one picks it up, and decrypting only starts, without awaiting,
+    ///   once the record is complete. Decrypted bytes beyond the one asked
+    ///   for stay buffered for the receive.
+    async fn wait_input(link: &Mutex<NoopRawMutex, Self>) {
+        let mut link = link.lock().await;
+        if link.peeked.is_some() {
+            return;
+        }
+        let mut byte = [0];
+        if let Ok(1) = link.transport.read(&mut byte).await {
+            link.peeked = Some(byte[0]);
+        }
+    }
+}
+
+/// The client's handle on the [`Link`]
+struct Shared<'a, T>(&'a Mutex<NoopRawMutex, Link<T>>);
+
+impl<T: embedded_io_async::ErrorType> embedded_io_async::ErrorType for Shared<'_, T> {
+    type Error = T::Error;
+}
+
+impl<T: embedded_io_async::Read> embedded_io_async::Read for Shared<'_, T> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut link = self.0.lock().await;
+        match (link.peeked, buf.first_mut()) {
+            (Some(byte), Some(first)) => {
+                *first = byte;
+                link.peeked = None;
+                Ok(1)
+            }
+            _ => link.transport.read(buf).await,
+        }
+    }
+}
+
+impl<T: embedded_io_async::Write> embedded_io_async::Write for Shared<'_, T> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0.lock().await.transport.write(buf).await
     }
 
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.lock().await.transport.flush().await
+    }
+}
+
+/// Publish, subscribe and serve the session
+///
+/// A QoS 1 publish or a ping takes the next packet for its reply, an
+/// incoming command would break it. Those all happen before subscribing,
+/// afterwards everything goes out at QoS 0 and the only reads are
+/// receives. A dead broker shows as the socket timing out on unacked data.
+async fn serve_session<T: embedded_io_async::Read + embedded_io_async::Write>(
+    transport: T,
+    config: ClientConfig<'_, 10, Rng>,
+    topics: &Topics,
+    stack: &'static Stack<'static>,
+    backoff: &mut Duration,
+) -> Result<(), Failure> {
     let mut recv_buffer = [0; MQTT_BUFFER_SIZE];
     let mut write_buffer = [0; MQTT_BUFFER_SIZE];
 
+    let link = Mutex::<NoopRawMutex, _>::new(Link {
+        transport,
+        peeked: None,
+    });
     let mut client = MqttClient::new(
-        socket,
+        Shared(&link),
         &mut write_buffer,
         MQTT_BUFFER_SIZE,
         &mut recv_buffer,
@@ -115,85 +523,172 @@ async fn update_mqtt(
     );
 
     if let Err(e) = client.connect_to_broker().await {
-        let mut status = STATUS.lock().await;
-        status.clear();
-        write!(status, "{:?}", e).ok();
-        return Err(SysError::Net(NetError::Mqtt));
+        return Err(failed(Failure::Broker, e));
     }
-
-    if let Err(e) = client.send_ping().await {
-        let mut status = STATUS.lock().await;
-        status.clear();
-        write!(status, "{:?}", e).ok();
-        return Err(SysError::Net(NetError::Mqtt));
-    }
-
-    let msg = serde_json_core::to_string::<_, MQTT_BUFFER_SIZE>(&get_status().await).unwrap();
+    enter(ConnectionState::Connected).await;
 
     if let Err(e) = client
-        .send_message(MQTT_TOPIC, msg.as_bytes(), QualityOfService::QoS1, true)
+        .send_message(
+            &topics.availability,
+            PAYLOAD_ONLINE,
+            QualityOfService::QoS1,
+            true,
+        )
         .await
     {
-        let mut status = STATUS.lock().await;
-        status.clear();
-        write!(status, "{:?}", e).ok();
-        return Err(SysError::Net(NetError::Mqtt));
+        return Err(failed(Failure::Publish, e));
     }
 
-    {
-        let mut status = STATUS.lock().await;
-        status.clear();
-        write!(status, "OK").ok();
-    }
-
-    if let Err(e) = client.subscribe_to_topic("water/control").await {
-        let mut status = STATUS.lock().await;
-        status.clear();
-        write!(status, "{:?}", e).ok();
-        return Err(SysError::Net(NetError::Mqtt));
-    }
-
-    match client.receive_message().await {
-        Ok((_topic, payload)) => {
-            let command: Result<(Command, _), _> = serde_json_core::from_slice(payload);
-            if let Ok((cmd, _)) = command {
-                let mut status = STATUS.lock().await;
-                status.clear();
-                write!(status, "Cmd").ok();
-                cmd.process().await;
-            } else {
-                let mut status = STATUS.lock().await;
-                status.clear();
-                write!(status, "ERecv").ok();
-                return Err(SysError::Net(NetError::Mqtt));
+    if let Err(e) = publish_discovery(&mut client, topics).await {
+        return Err(failed(Failure::Publish, e));
+    }
+
+    ONLINE.store(true, Ordering::Relaxed);
+    if let Err(e) = replay_history(&mut client, topics).await {
+        return Err(failed(Failure::Publish, e));
+    }
+
+    // One SUBSCRIBE, so a command can't arrive before the only SUBACK
+    let mut subscriptions = heapless08::Vec::<&str, 2>::new();
+    subscriptions.push(&topics.control).ok();
+    subscriptions.push(&topics.broadcast).ok();
+    if let Err(e) = client.subscribe_to_topics(&subscriptions).await {
+        return Err(failed(Failure::Subscribe, e));
+    }
+
+    enter(ConnectionState::Subscribed).await;
+    *backoff = MQTT_BACKOFF_MIN;
+
+    let mut next_telemetry = Instant::now();
+    let mut last_sent = Instant::now();
+    loop {
+        let now = Instant::now();
+        if now >= next_telemetry {
+            *LATENCY.lock().await = measure_latency(stack)
+                .await
+                .unwrap_or(Duration::from_secs(0));
+
+            let msg =
+                serde_json_core::to_string::<_, STATUS_JSON_LEN>(&get_status().await).unwrap();
+            if let Err(e) = client
+                .send_message(&topics.status, msg.as_bytes(), QualityOfService::QoS0, true)
+                .await
+            {
+                return Err(failed(Failure::Publish, e));
             }
-            Ok(())
+            next_telemetry = now + get_telemetry_interval().await;
+            last_sent = Instant::now();
+        } else if now >= last_sent + MQTT_PING_INTERVAL {
+            // Any packet keeps the session alive, and unlike a ping this one
+            // expects no reply
+            if let Err(e) = client
+                .send_message(
+                    &topics.availability,
+                    PAYLOAD_ONLINE,
+                    QualityOfService::QoS0,
+                    true,
+                )
+                .await
+            {
+                return Err(failed(Failure::Ping, e));
+            }
+            last_sent = Instant::now();
         }
-        Err(e) => {
-            let mut status = STATUS.lock().await;
-            status.clear();
-            write!(status, "{:?}", e).ok();
-            Err(SysError::Net(NetError::Mqtt))
+
+        let wakeup = next_telemetry.min(last_sent + MQTT_PING_INTERVAL);
+        match select4(
+            Link::wait_input(&link),
+            Timer::at(wakeup),
+            PUBLISH_NOW.wait(),
+            logger::next_line(),
+        )
+        .await
+        {
+            Either4::First(()) => {
+                let (topic, payload) = match client.receive_message().await {
+                    Ok(message) => message,
+                    Err(e) => return Err(failed(Failure::Receive, e)),
+                };
+                // Signatures name the unit, or all of them on the broadcast topic
+                let id = device_id();
+                let target = if topic == topics.broadcast.as_str() {
+                    BROADCAST_ID
+                } else {
+                    id.as_str()
+                };
+                // Every control message gets an acknowledgement
+                let ack = command::handle(target, payload).await;
+                let mut msg = [0u8; MQTT_ACK_LEN];
+                let len = serde_json_core::to_slice(&ack, &mut msg).unwrap_or(0);
+                if let Err(e) = client
+                    .send_message(&topics.ack, &msg[..len], QualityOfService::QoS0, false)
+                    .await
+                {
+                    return Err(failed(Failure::Publish, e));
+                }
+                last_sent = Instant::now();
+            }
+            Either4::Second(()) => {}
+            Either4::Third(()) => next_telemetry = Instant::now(),
+            Either4::Fourth(line) => {
+                // Flush what queued up meanwhile as well, at most a queue's
+                // worth so a chatty log can't keep commands waiting
+                let backlog = core::iter::from_fn(logger::queued_line).take(LOG_QUEUE_LEN - 1);
+                for record in core::iter::once(line).chain(backlog) {
+                    if let Err(e) = client
+                        .send_message(
+                            &topics.log,
+                            record.as_bytes(),
+                            QualityOfService::QoS0,
+                            false,
+                        )
+                        .await
+                    {
+                        return Err(failed(Failure::Publish, e));
+                    }
+                }
+                last_sent = Instant::now();
+            }
         }
     }
 }
 
 #[embassy_executor::task]
 pub async fn mqtt_task(rng: Rng, stack: &'static Stack<'static>) {
-    let mut config = ClientConfig::new(rust_mqtt::client::client_config::MqttVersion::MQTTv5, rng);
-    config.add_max_subscribe_qos(QualityOfService::QoS1);
-    config.add_client_id(MQTT_CLIENT_ID);
-    config.max_packet_size = 128;
-    config.add_username(MQTT_USER);
-    config.add_password(MQTT_PASSWORD);
+    let credentials = get_credentials().await;
+    let client_id = client_id();
 
+    let mut backoff = MQTT_BACKOFF_MIN;
+    let mut next_sample = Instant::now();
     loop {
-        *LATENCY.lock().await = measure_latency(stack)
-            .await
-            .unwrap_or(Duration::from_secs(0));
+        let topics = topics().await;
+        let mut config =
+            ClientConfig::new(rust_mqtt::client::client_config::MqttVersion::MQTTv5, rng);
+        config.add_max_subscribe_qos(QualityOfService::QoS1);
+        config.add_client_id(&client_id);
+        // The broker drops anything longer, signed commands included
+        config.max_packet_size = MQTT_BUFFER_SIZE as u32;
+        config.keep_alive = MQTT_KEEP_ALIVE_SECS;
+        config.add_will(&topics.availability, PAYLOAD_OFFLINE, true);
+        // Without credentials the broker has to accept anonymous clients
+        if let Some(ref credentials) = credentials {
+            config.add_username(&credentials.user);
+            config.add_password(&credentials.password);
+        }
 
-        if update_mqtt(config.clone(), stack).await.is_err() {
-            Timer::after(MQTT_ERR_REFRESH_TIME).await;
+        let attempt = async {
+            let result = run_session(config, &topics, stack, &mut backoff).await;
+            ONLINE.store(false, Ordering::Relaxed);
+            if let Err(reason) = result {
+                enter(ConnectionState::BackingOff {
+                    reason,
+                    retry_in_secs: backoff.as_secs(),
+                })
+                .await;
+                Timer::after(backoff).await;
+                backoff = (backoff * 2).min(MQTT_BACKOFF_MAX);
+            }
         };
+        select(attempt, record_offline(&mut next_sample)).await;
     }
 }
@@ -1,121 +1,252 @@
-use chrono::{DateTime, TimeDelta, Utc};
-use core::net::{IpAddr, SocketAddr};
-use embassy_net::{Stack, udp::UdpSocket};
-use embassy_time::{Duration, Instant, Timer};
-use smoltcp::{storage::PacketMetadata, wire::DnsQueryType};
-use sntpc::{NtpContext, NtpTimestampGenerator, get_time};
+//! Clock sync over SNTP
+//!
+//! Every sync asks up to `MAX_SAMPLES` servers: the one offered by DHCP
+//! first, then the addresses of the configured names. The clock is only set
+//! if most of the answers agree.
+
+use core::fmt::Write;
+use core::net::Ipv4Addr;
+use embassy_net::raw::{self, IpProtocol, IpVersion, RawSocket};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, Stack};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use esp_hal::efuse::Efuse;
+use esp_radio::wifi::WifiDevice;
+use heapless::{String, Vec};
+use log::{info, warn};
+use smoltcp::wire::DnsQueryType;
 
 use crate::{
-    display::update_status,
+    display::{STATUS_LEN, update_status},
     error::{NetError, SysError},
-    io::rtc::set_time,
+    io::rtc::{get_time, set_time},
+    net::dhcp,
+    net::sntp::{self, MAX_SAMPLES, MAX_SERVERS, NTP_HOST_LEN, Sample, SyncStats},
+    net::stack::get_static_ip,
+    time::now,
 };
 
-const NTP_SERVER: &str = "pool.ntp.org";
+const DEFAULT_NTP_SERVER: &str = "pool.ntp.org";
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+const DHCP_TIMEOUT: Duration = Duration::from_secs(2);
 
-#[derive(Copy, Clone)]
-struct Timestamp {
-    duration: Duration,
-    offset: DateTime<Utc>,
-}
+static SERVERS: Mutex<CriticalSectionRawMutex, Option<Vec<String<NTP_HOST_LEN>, MAX_SERVERS>>> =
+    Mutex::new(None);
+static STATS: Mutex<CriticalSectionRawMutex, SyncStats> = Mutex::new(SyncStats::new());
+static LAST_SYNC: Mutex<CriticalSectionRawMutex, Option<Instant>> = Mutex::new(None);
 
-impl Timestamp {
-    fn new(offset: DateTime<Utc>) -> Timestamp {
-        Timestamp {
-            duration: Duration::default(),
-            offset,
-        }
+/// Configured server names, `pool.ntp.org` unless set
+pub async fn get_servers() -> Vec<String<NTP_HOST_LEN>, MAX_SERVERS> {
+    if let Some(servers) = SERVERS.lock().await.clone() {
+        return servers;
     }
+    let mut servers = Vec::new();
+    servers.push(DEFAULT_NTP_SERVER.try_into().unwrap()).ok();
+    servers
 }
 
-impl NtpTimestampGenerator for Timestamp {
-    fn init(&mut self) {
-        self.duration = Duration::from_micros(
-            (self.offset + TimeDelta::milliseconds(Instant::now().as_millis().try_into().unwrap()))
-                .timestamp_micros()
-                .try_into()
-                .unwrap(),
-        );
-    }
+pub async fn set_servers(servers: Option<Vec<String<NTP_HOST_LEN>, MAX_SERVERS>>) {
+    *SERVERS.lock().await = servers;
+}
 
-    fn timestamp_sec(&self) -> u64 {
-        self.duration.as_secs()
-    }
+pub async fn sync_stats() -> SyncStats {
+    STATS.lock().await.clone()
+}
+
+/// Time since the clock was last set from NTP, `None` before the first sync
+pub async fn sync_age() -> Option<Duration> {
+    LAST_SYNC.lock().await.map(|at| at.elapsed())
+}
 
-    fn timestamp_subsec_micros(&self) -> u32 {
-        (self.duration.as_micros() - self.duration.as_secs() * 1_000_000)
-            .try_into()
-            .unwrap()
+/// NTP server offered with the lease, the network stack doesn't ask for it
+///
+/// A single raw socket sends the INFORM and catches the ACK, which the DHCP
+/// socket would otherwise take. It is gone before the sampling socket opens.
+async fn dhcp_server(stack: Stack<'_>) -> Option<Ipv4Addr> {
+    if get_static_ip().await.is_some_and(|ip| !ip.fallback) {
+        return None;
     }
+    let address = stack.config_v4()?.address.address();
+
+    let mut rx_meta = [raw::PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; dhcp::MAX_PACKET_LEN];
+    let mut tx_meta = [raw::PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; dhcp::INFORM_PACKET_LEN];
+    let socket = RawSocket::new::<WifiDevice<'static>>(
+        stack,
+        IpVersion::Ipv4,
+        IpProtocol::Udp,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    let xid = (Instant::now().as_ticks() as u32).to_be_bytes();
+    let mut packet = [0u8; dhcp::MAX_PACKET_LEN];
+    let len = dhcp::inform(xid, Efuse::mac_address(), address, &mut packet)?;
+    socket.send(&packet[..len]).await;
+
+    with_timeout(DHCP_TIMEOUT, async {
+        loop {
+            let Ok(len) = socket.recv(&mut packet).await else {
+                continue;
+            };
+            if let Some(mut servers) = dhcp::from_ip_packet(&packet[..len])
+                .and_then(|message| dhcp::ntp_servers(message, xid))
+            {
+                return servers.next();
+            }
+        }
+    })
+    .await
+    .ok()?
 }
 
 pub struct NtpClient<'a> {
     stack: &'a Stack<'a>,
-    context: NtpContext<Timestamp>,
 }
 
 impl<'a> NtpClient<'a> {
     pub fn new(stack: &'a Stack<'a>) -> NtpClient<'a> {
-        NtpClient {
-            stack,
-            context: NtpContext::new(Timestamp::new(DateTime::from_timestamp_nanos(0))),
-        }
+        NtpClient { stack }
     }
 
-    pub async fn sync(&self) -> Result<(), SysError> {
-        let stack = self.stack;
-
-        let mut udp_rx_meta = [PacketMetadata::EMPTY; 16];
-        let mut udp_rx_buffer = [0; 1024];
-        let mut udp_tx_meta = [PacketMetadata::EMPTY; 16];
-        let mut udp_tx_buffer = [0; 1024];
+    /// Addresses to ask, the one from DHCP first
+    async fn servers(&self) -> Vec<IpAddress, MAX_SAMPLES> {
+        let mut servers = Vec::new();
+        if let Some(server) = dhcp_server(*self.stack).await {
+            servers.push(IpAddress::Ipv4(server)).ok();
+        }
+        for host in get_servers().await {
+            match self.stack.dns_query(&host, DnsQueryType::A).await {
+                Ok(addresses) => {
+                    for address in addresses {
+                        if !servers.contains(&address) {
+                            servers.push(address).ok();
+                        }
+                    }
+                }
+                Err(e) => warn!("NTP: can't resolve {}: {:?}", host, e),
+            }
+        }
+        servers
+    }
 
+    /// Ask each server once, silent ones are left out
+    async fn sample(
+        &self,
+        servers: &[IpAddress],
+    ) -> Result<Vec<(IpAddress, Sample), MAX_SAMPLES>, SysError> {
+        let mut rx_meta = [PacketMetadata::EMPTY; 4];
+        let mut rx_buffer = [0; 256];
+        let mut tx_meta = [PacketMetadata::EMPTY; 1];
+        let mut tx_buffer = [0; 64];
         let mut socket = UdpSocket::new(
-            *stack,
-            &mut udp_rx_meta,
-            &mut udp_rx_buffer,
-            &mut udp_tx_meta,
-            &mut udp_tx_buffer,
+            *self.stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
         );
+        socket.bind(0).map_err(|_| NetError::Socket)?;
+
+        let mut samples = Vec::new();
+        for server in servers {
+            let mut packet = [0u8; sntp::PACKET_LEN];
+            let sent = get_time().await?;
+            sntp::request(sent, &mut packet);
+            if socket
+                .send_to(&packet, (*server, sntp::PORT))
+                .await
+                .is_err()
+            {
+                continue;
+            }
 
-        socket.bind(123).unwrap();
+            let answer = with_timeout(REPLY_TIMEOUT, async {
+                loop {
+                    // Late replies to an earlier server fail the origin check
+                    let Ok((len, _)) = socket.recv_from(&mut packet).await else {
+                        continue;
+                    };
+                    let received = get_time().await.ok()?;
+                    if let Some(sample) = sntp::parse(&packet[..len], sent, received) {
+                        return Some(sample);
+                    }
+                }
+            })
+            .await;
+            match answer {
+                Ok(Some(sample)) => {
+                    samples.push((*server, sample)).ok();
+                }
+                _ => warn!("NTP: no answer from {}", server),
+            }
+        }
+        Ok(samples)
+    }
 
-        let ntp_addrs = stack.dns_query(NTP_SERVER, DnsQueryType::A).await?;
-        if ntp_addrs.is_empty() {
+    pub async fn sync(&self) -> Result<(), SysError> {
+        let servers = self.servers().await;
+        if servers.is_empty() {
             return Err(SysError::Net(NetError::Resolve));
-        };
-        let addr: IpAddr = ntp_addrs[0].into();
-        let result = get_time(SocketAddr::from((addr, 123)), &socket, self.context).await;
-
-        match result {
-            Ok(time) => {
-                let datetime = DateTime::from_timestamp(
-                    time.sec().into(),
-                    (time.sec_fraction() as u64 * 1_000_000_000 / 4_294_967_296) as u32,
-                )
-                .unwrap();
-
-                Ok(set_time(datetime.timestamp_micros() as u64).await?)
-            }
-            Err(_) => Err(SysError::TimerSetup),
         }
+        let samples = self.sample(&servers).await?;
+
+        let offsets: Vec<Sample, MAX_SAMPLES> = samples.iter().map(|(_, sample)| *sample).collect();
+        let best = sntp::select(&offsets).ok_or(NetError::Ntp)?;
+        let (server, sample) = samples[best];
+        set_time(get_time().await?.saturating_add_signed(sample.offset_us)).await?;
+        info!(
+            "NTP: {} at stratum {}, offset {} us, round trip {} us",
+            server, sample.stratum, sample.offset_us, sample.delay_us
+        );
+
+        let mut stats = STATS.lock().await;
+        stats.last_sync = now().await.ok();
+        stats.server = Some(server.into());
+        stats.offset_us = sample.offset_us;
+        stats.rtt_us = sample.delay_us;
+        stats.stratum = sample.stratum;
+        Ok(())
     }
 }
 
 const NTP_REFRESH_TIME: Duration = Duration::from_secs(3600);
+const NTP_RETRY_TIME: Duration = Duration::from_secs(30);
 
 #[embassy_executor::task]
 pub async fn ntp_task(client: NtpClient<'static>) {
     loop {
-        let timeout;
         update_status("Syncing NTP").await.ok();
-        if let Ok(()) = client.sync().await {
-            update_status("Time synced").await.ok();
-            timeout = NTP_REFRESH_TIME;
-        } else {
-            update_status("NTP failed, proceeding").await.ok();
-            timeout = Duration::from_secs(5);
+        let mut message: String<STATUS_LEN> = String::new();
+        let timeout = match client.sync().await {
+            Ok(()) => {
+                *LAST_SYNC.lock().await = Some(Instant::now());
+                let stats = sync_stats().await;
+                write!(
+                    message,
+                    "NTP s{} rtt {}ms",
+                    stats.stratum,
+                    stats.rtt_us / 1000
+                )
+                .ok();
+                NTP_REFRESH_TIME
+            }
+            Err(e) => {
+                warn!("NTP: sync failed: {:?}", e);
+                let failures = {
+                    let mut stats = STATS.lock().await;
+                    stats.failures += 1;
+                    stats.failures
+                };
+                write!(message, "NTP failed x{}", failures).ok();
+                NTP_RETRY_TIME
+            }
         };
+        update_status(&message).await.ok();
         Timer::after(timeout).await;
     }
 }
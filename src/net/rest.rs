@@ -0,0 +1,163 @@
+//! HTTP server for the REST API on the station interface
+
+use embassy_net::Stack;
+use embassy_net::tcp::TcpSocket;
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use heapless::{String, Vec};
+use serde::Serialize;
+
+use crate::command::ack::Ack;
+use crate::command::auth;
+use crate::command::settings::Settings;
+use crate::command::status::{STATUS_JSON_LEN, get_status};
+use crate::command::{self, apply_settings, get_settings};
+use crate::health::get_health_status;
+use crate::io::gpio::{get_battery_value, get_sensor_value};
+use crate::io::wifi::reconnect_count;
+use crate::net::api::{Endpoint, ack_status, route};
+use crate::net::http::{ParseError, Status, parse_request, response_head};
+use crate::net::metrics::{self, METRICS_LEN, Metrics, ZoneMetrics};
+use crate::net::mqtt::{device_id, latency};
+use crate::net::ntp::sync_age;
+use crate::power::{charge_level, humidity_level};
+use crate::watchdog::get_watchdog_stats;
+use crate::watering::get_pump_stats;
+
+/// Parallel connections, each holds a socket out of `SOCKETS` while serving
+pub const HTTP_CONNECTIONS: usize = 3;
+pub const HTTP_PORT: u16 = 80;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_LEN: usize = 1536;
+const RESPONSE_LEN: usize = STATUS_JSON_LEN;
+const SOCKET_BUFFER_SIZE: usize = 1024;
+// Let the response leave before dropping the connection
+const CLOSE_DELAY: Duration = Duration::from_millis(100);
+const JSON: &str = "application/json";
+
+async fn respond(socket: &mut TcpSocket<'_>, status: Status, content_type: &str, body: &[u8]) {
+    let head = response_head(status, content_type, body.len());
+    if socket.write_all(head.as_bytes()).await.is_ok() {
+        socket.write_all(body).await.ok();
+    }
+    socket.flush().await.ok();
+}
+
+/// Serialize `value` into `out`, a 500 if it doesn't fit
+fn json<T: Serialize>(status: Status, value: &T, out: &mut [u8]) -> (Status, usize) {
+    match serde_json_core::to_slice(value, out) {
+        Ok(len) => (status, len),
+        Err(_) => (Status::INTERNAL_ERROR, 0),
+    }
+}
+
+async fn get_metrics() -> Metrics {
+    let pump_stats = get_pump_stats().await;
+    let mut zones = Vec::new();
+    for (zone, stats) in pump_stats.iter().enumerate() {
+        let zone = ZoneMetrics {
+            humidity: humidity_level(zone).await,
+            humidity_raw: get_sensor_value(zone).await,
+            pump_runtime_secs: stats.runtime.as_secs(),
+            cycles: stats.cycles,
+        };
+        zones.push(zone).ok();
+    }
+    let (watchdog_enabled, watchdog_feeds) = get_watchdog_stats();
+    let (wifi, mqtt, display, adc) = get_health_status();
+
+    Metrics {
+        zones,
+        charge: charge_level().await,
+        charge_raw: get_battery_value().await,
+        latency_ms: latency().await.ok(),
+        wifi_reconnects: reconnect_count(),
+        ntp_sync_age_secs: sync_age().await.map(|age| age.as_secs()),
+        watchdog_enabled,
+        watchdog_feeds,
+        health: [
+            ("wifi", wifi),
+            ("mqtt", mqtt),
+            ("display", display),
+            ("adc", adc),
+        ],
+    }
+}
+
+async fn serve_metrics(socket: &mut TcpSocket<'_>) {
+    let mut text: String<METRICS_LEN> = String::new();
+    match get_metrics().await.render(&mut text) {
+        Ok(()) => respond(socket, Status::OK, metrics::CONTENT_TYPE, text.as_bytes()).await,
+        Err(_) => respond(socket, Status::INTERNAL_ERROR, metrics::CONTENT_TYPE, b"").await,
+    }
+}
+
+async fn serve(socket: &mut TcpSocket<'_>) {
+    let mut buf = [0u8; REQUEST_LEN];
+    let mut len = 0;
+    let request = loop {
+        match socket.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => len += read,
+        }
+        match parse_request(&buf[..len], buf.len()) {
+            Err(ParseError::Incomplete) => continue,
+            result => break result,
+        }
+    };
+
+    let request = match request {
+        Ok(request) => request,
+        Err(ParseError::TooLarge) => {
+            return respond(socket, Status::PAYLOAD_TOO_LARGE, JSON, b"").await;
+        }
+        Err(ParseError::UnsupportedMethod) => {
+            return respond(socket, Status::METHOD_NOT_ALLOWED, JSON, b"").await;
+        }
+        Err(_) => return respond(socket, Status::BAD_REQUEST, JSON, b"").await,
+    };
+
+    let mut out = [0u8; RESPONSE_LEN];
+    let (status, len) = match route(request.method, request.path) {
+        Err(status) => (status, 0),
+        // Plain text, too large for the JSON buffer
+        Ok(Endpoint::GetMetrics) => return serve_metrics(socket).await,
+        Ok(Endpoint::GetStatus) => json(Status::OK, &get_status().await, &mut out),
+        Ok(Endpoint::PostCommand) => {
+            let ack = command::handle(&device_id(), request.body).await;
+            json(ack_status(&ack), &ack, &mut out)
+        }
+        Ok(Endpoint::GetConfig) => json(Status::OK, &get_settings().await, &mut out),
+        Ok(Endpoint::PutConfig) => {
+            let ack = match command::authenticate(&device_id(), request.body).await {
+                Ok(body) => match serde_json_core::from_slice::<Settings>(body) {
+                    Ok((settings, _)) => apply_settings(&settings).await,
+                    Err(_) => Ack::malformed(),
+                },
+                Err(reason) => Ack::new("", Err(reason)),
+            }
+            .with_id(auth::request_id(request.body).0);
+            json(ack_status(&ack), &ack, &mut out)
+        }
+    };
+    respond(socket, status, JSON, &out[..len]).await;
+}
+
+#[embassy_executor::task(pool_size = HTTP_CONNECTIONS)]
+pub async fn http_task(stack: Stack<'static>) {
+    let mut rx_buffer = [0; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = [0; SOCKET_BUFFER_SIZE];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(HTTP_TIMEOUT));
+        if socket.accept(HTTP_PORT).await.is_err() {
+            continue;
+        }
+
+        serve(&mut socket).await;
+        socket.close();
+        Timer::after(CLOSE_DELAY).await;
+        socket.abort();
+    }
+}
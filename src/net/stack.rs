@@ -1,20 +1,103 @@
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
 use embassy_executor::Spawner;
-use embassy_net::{Ipv4Cidr, Runner, Stack, StackResources};
-use embassy_time::{Duration, Timer};
+use embassy_net::{ConfigV4, DhcpConfig, Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use esp_hal::efuse::Efuse;
 use esp_radio::wifi::WifiDevice;
 use heapless::String;
+use log::{info, warn};
 use static_cell::StaticCell;
 
 use crate::{
     display::{STATUS_LEN, update_status},
     error::SysError,
     io::led::{HEARTBEAT_NET_AWAIT, set_heartbeat},
+    net::ipconfig::{
+        DEFAULT_DHCP_TIMEOUT_SECS, DHCP_RETRY_INTERVAL, HOSTNAME_LEN, LINK_LOCAL_PREFIX_LEN,
+        StaticIp, link_local,
+    },
+    net::mqtt::device_id,
+    watchdog::feed_watchdog,
 };
 
-// Available number of sockets for the network stack
+// Available number of sockets for the network stack: DHCP, DNS, NTP (or
+// the raw one asking DHCP for NTP servers before), MQTT, ping, the HTTP
+// connections, the mDNS responder and an mDNS lookup
 const SOCKETS: usize = 10;
 
+static STATIC_IP: Mutex<CriticalSectionRawMutex, Option<StaticIp>> = Mutex::new(None);
+static DHCP_TIMEOUT_SECS: Mutex<CriticalSectionRawMutex, u32> =
+    Mutex::new(DEFAULT_DHCP_TIMEOUT_SECS);
+// Set once DHCP timed out at boot
+static FALLBACK: AtomicBool = AtomicBool::new(false);
+static HOSTNAME: Mutex<CriticalSectionRawMutex, Option<String<HOSTNAME_LEN>>> = Mutex::new(None);
+
+pub async fn get_static_ip() -> Option<StaticIp> {
+    STATIC_IP.lock().await.clone()
+}
+
+/// `None` uses DHCP, applied on the next boot
+pub async fn set_static_ip(ip: Option<StaticIp>) {
+    *STATIC_IP.lock().await = ip;
+}
+
+/// How long to wait for a lease before falling back, `0` waits forever
+pub async fn get_dhcp_timeout() -> u32 {
+    *DHCP_TIMEOUT_SECS.lock().await
+}
+
+pub async fn set_dhcp_timeout(secs: u32) {
+    *DHCP_TIMEOUT_SECS.lock().await = secs;
+}
+
+/// Name sent to the DHCP server and announced over mDNS, `water-<id>`
+/// unless configured
+pub async fn get_hostname() -> String<HOSTNAME_LEN> {
+    if let Some(name) = HOSTNAME.lock().await.clone() {
+        return name;
+    }
+    let mut name = String::new();
+    write!(name, "water-{}", device_id()).ok();
+    name
+}
+
+pub async fn set_hostname(name: Option<String<HOSTNAME_LEN>>) {
+    *HOSTNAME.lock().await = name;
+}
+
+fn static_config(ip: &StaticIp) -> StaticConfigV4 {
+    let mut config = StaticConfigV4 {
+        address: Ipv4Cidr::new(ip.address(), ip.prefix_len),
+        gateway: ip.gateway(),
+        dns_servers: Default::default(),
+    };
+    for server in ip.dns_servers() {
+        config.dns_servers.push(server).ok();
+    }
+    config
+}
+
+/// The stored static address, or a link-local one
+async fn fallback_config() -> StaticConfigV4 {
+    match get_static_ip().await {
+        Some(ip) => static_config(&ip),
+        None => StaticConfigV4 {
+            address: Ipv4Cidr::new(link_local(Efuse::mac_address()), LINK_LOCAL_PREFIX_LEN),
+            gateway: None,
+            dns_servers: Default::default(),
+        },
+    }
+}
+
+async fn dhcp_config() -> DhcpConfig {
+    let mut dhcp = DhcpConfig::default();
+    dhcp.hostname = get_hostname().await.as_str().try_into().ok();
+    dhcp
+}
+
 pub async fn init_net(
     driver: WifiDevice<'static>,
     seed: u64,
@@ -24,7 +107,10 @@ pub async fn init_net(
         static RESOURCES: StaticCell<StackResources<SOCKETS>> = StaticCell::new();
         RESOURCES.init(StackResources::<SOCKETS>::new())
     };
-    let config = embassy_net::Config::dhcpv4(Default::default());
+    let config = match get_static_ip().await {
+        Some(ip) if !ip.fallback => embassy_net::Config::ipv4_static(static_config(&ip)),
+        _ => embassy_net::Config::dhcpv4(dhcp_config().await),
+    };
 
     let (stack, runner) = embassy_net::new(driver, config, resources, seed);
     spawner.spawn(net_task(runner))?;
@@ -50,13 +136,22 @@ pub async fn wait_for_link(stack: &Stack<'static>) {
         if stack.is_link_up() {
             break;
         }
+        // The setup portal may keep us here for a while
+        feed_watchdog();
         Timer::after(NET_REFRESH_TIME).await;
     }
 }
 
+/// Wait for the address, DHCP gets [`get_dhcp_timeout`] before the stack
+/// switches to the fallback address until [`dhcp_retry_task`] gets a lease
 pub async fn wait_for_ip(stack: &Stack<'static>) -> Ipv4Cidr {
     set_heartbeat(HEARTBEAT_NET_AWAIT);
     update_status("Waiting for IP").await.ok();
+    let timeout = match get_dhcp_timeout().await {
+        0 => None,
+        secs => Some(Duration::from_secs(secs as u64)),
+    };
+    let started = Instant::now();
     loop {
         if let Some(config) = stack.config_v4() {
             let mut ip_string: String<STATUS_LEN> = String::new();
@@ -65,6 +160,53 @@ pub async fn wait_for_ip(stack: &Stack<'static>) -> Ipv4Cidr {
 
             return config.address;
         }
+        if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            let config = fallback_config().await;
+            warn!("No DHCP lease, falling back to {}", config.address);
+            stack.set_config_v4(ConfigV4::Static(config));
+            FALLBACK.store(true, Ordering::Relaxed);
+            continue;
+        }
+        // The setup portal may keep us here for a while
+        feed_watchdog();
         Timer::after(NET_REFRESH_TIME).await;
     }
 }
+
+/// Give DHCP another chance now and then while on the fallback address
+///
+/// A router coming back slower than the unit after a power cut would
+/// otherwise leave it without a routable address until the next boot.
+#[embassy_executor::task]
+pub async fn dhcp_retry_task(stack: &'static Stack<'static>) {
+    if !FALLBACK.load(Ordering::Relaxed) {
+        return;
+    }
+    let timeout = Duration::from_secs(get_dhcp_timeout().await as u64);
+    loop {
+        Timer::after(DHCP_RETRY_INTERVAL).await;
+
+        info!("Retrying DHCP");
+        stack.set_config_v4(ConfigV4::Dhcp(dhcp_config().await));
+        let lease = with_timeout(timeout, async {
+            loop {
+                if let Some(config) = stack.config_v4() {
+                    return config.address;
+                }
+                Timer::after(NET_REFRESH_TIME).await;
+            }
+        })
+        .await;
+        match lease {
+            Ok(address) => {
+                info!("DHCP lease {} replaces the fallback address", address);
+                FALLBACK.store(false, Ordering::Relaxed);
+                let mut ip_string: String<STATUS_LEN> = String::new();
+                write!(ip_string, "IP: {}", address.address()).ok();
+                update_status(&ip_string).await.ok();
+                return;
+            }
+            Err(_) => stack.set_config_v4(ConfigV4::Static(fallback_config().await)),
+        }
+    }
+}
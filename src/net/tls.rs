@@ -0,0 +1,94 @@
+//! TLS 1.3 transport for the MQTT connection
+//!
+//! The broker certificate has to chain up to the certificate pinned at build
+//! time through the `MQTT_CA` environment variable (a DER file). For a broker
+//! with a self-signed certificate pin that certificate itself. Changing it
+//! means reflashing: a DER certificate doesn't fit a config store value.
+//!
+//! For a local mosquitto: convert the CA with
+//! `openssl x509 -in ca.crt -outform der -out ca.der`, build with
+//! `MQTT_CA=ca.der` and select the broker with `broker <host> tls` on the
+//! console. The server has to offer TLS 1.3 with an EC key.
+
+use embassy_net::tcp::TcpSocket;
+use embedded_tls::pki::CertVerifier;
+use embedded_tls::{
+    Aes128GcmSha256, Certificate, CryptoProvider, TlsClock, TlsConfig, TlsConnection, TlsContext,
+    TlsError, TlsVerifier,
+};
+use esp_hal::rng::Trng;
+
+pub const MQTT_TLS_PORT: u16 = 8883;
+/// Largest TLS record a server may send us
+pub const TLS_READ_RECORD_LEN: usize = 16640;
+pub const TLS_WRITE_RECORD_LEN: usize = 4096;
+// Leaf certificates are a few hundred bytes for EC keys, RSA ones are larger
+const MAX_CERT_LEN: usize = 2048;
+
+static MQTT_CA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/mqtt_ca.der"));
+
+pub type TlsSocket<'a> = TlsConnection<'a, TcpSocket<'a>, Aes128GcmSha256>;
+
+/// Certificate dates are not checked, the pinned CA is the trust anchor
+struct NoClock;
+
+impl TlsClock for NoClock {
+    fn now() -> Option<u64> {
+        None
+    }
+}
+
+struct PinnedProvider {
+    rng: Trng,
+    verifier: CertVerifier<Aes128GcmSha256, NoClock, MAX_CERT_LEN>,
+}
+
+impl CryptoProvider for PinnedProvider {
+    type CipherSuite = Aes128GcmSha256;
+    type Signature = &'static [u8];
+
+    fn rng(&mut self) -> impl embedded_tls::CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<Self::CipherSuite>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Built without `MQTT_CA`, refusing to talk to an unverified broker
+    NoCa,
+    /// The radio isn't up, so there is no entropy for the handshake
+    NoEntropy,
+    Handshake(TlsError),
+}
+
+/// Run the handshake over an already connected socket
+pub async fn connect<'a>(
+    socket: TcpSocket<'a>,
+    server_name: &str,
+    read_record: &'a mut [u8],
+    write_record: &'a mut [u8],
+) -> Result<TlsSocket<'a>, Error> {
+    if MQTT_CA.is_empty() {
+        return Err(Error::NoCa);
+    }
+    let rng = Trng::try_new().map_err(|_| Error::NoEntropy)?;
+
+    let config = TlsConfig::new()
+        .with_server_name(server_name)
+        .with_ca(Certificate::X509(MQTT_CA));
+    let provider = PinnedProvider {
+        rng,
+        verifier: CertVerifier::new(),
+    };
+
+    let mut connection = TlsConnection::new(socket, read_record, write_record);
+    connection
+        .open(TlsContext::new(&config, provider))
+        .await
+        .map_err(Error::Handshake)?;
+    Ok(connection)
+}
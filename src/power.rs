@@ -13,11 +13,11 @@ pub async fn charge_level() -> u32 {
     (4000u32.saturating_sub(adc_val as u32)) * 100 / (4000 - 1200)
 }
 
-/// Returns the level moisture in percent
+/// Returns the level moisture of the zone in percent
 ///
 /// The computations are mostly random guesses but seem to show some value.
-pub async fn humidity_level() -> u32 {
-    let adc_val = get_sensor_value().await;
+pub async fn humidity_level(zone: usize) -> u32 {
+    let adc_val = get_sensor_value(zone).await;
 
     // Approximate values:
     // 2950 - dry air
@@ -0,0 +1,288 @@
+use esp_hal::peripherals::{GPIO3, UART0};
+use esp_hal::uart::{Config as UartConfig, UartRx};
+use esp_println::println;
+use heapless::{String, Vec};
+
+use crate::command;
+use crate::config::{self, Key};
+use crate::error::{HwError, UartError};
+use crate::io::networks::KnownNetwork;
+use crate::io::wifi::{self, WifiCredentials};
+use crate::net::mqtt::{self, MqttCredentials};
+use crate::net::{ntp, stack};
+
+pub use water_core::provision::{PORTAL_PASSWORD_LEN, console, form, portal_password};
+
+pub mod portal;
+
+use console::{ConsoleCommand, HELP, LineBuffer, parse_line};
+
+fn to_field<const N: usize>(value: &str) -> Option<String<N>> {
+    value.try_into().ok()
+}
+
+async fn handle(line: &str) {
+    let command = match parse_line(line) {
+        Ok(command) => command,
+        Err(e) => {
+            println!("Console: {:?}, type `help`", e);
+            return;
+        }
+    };
+
+    match command {
+        ConsoleCommand::Help => println!("{}", HELP),
+        ConsoleCommand::Show => {
+            match wifi::get_credentials().await {
+                Some(creds) => println!("WiFi: {}", creds.ssid),
+                None => println!("WiFi: not provisioned"),
+            }
+            for network in wifi::get_networks().await {
+                println!("WiFi: {} (priority {})", network.ssid, network.priority);
+            }
+            match mqtt::get_credentials().await {
+                Some(creds) => println!("MQTT: {}", creds.user),
+                None => println!("MQTT: anonymous"),
+            }
+            let tls = if mqtt::get_tls().await { " (TLS)" } else { "" };
+            match mqtt::get_server().await {
+                Some(server) => println!("Broker: {}{}", server, tls),
+                None => println!(
+                    "Broker: mDNS discovery, else {}{}",
+                    mqtt::LEGACY_MQTT_SERVER,
+                    tls
+                ),
+            }
+            println!("Topics: {}", mqtt::topics().await.status);
+            match stack::get_static_ip().await {
+                Some(ip) if !ip.fallback => println!("IP: {}/{}", ip.address(), ip.prefix_len),
+                Some(ip) => println!("IP: DHCP, fallback {}/{}", ip.address(), ip.prefix_len),
+                None => println!("IP: DHCP, fallback link-local"),
+            }
+            println!("DHCP timeout: {}s", stack::get_dhcp_timeout().await);
+            println!("Hostname: {}", stack::get_hostname().await);
+            for server in ntp::get_servers().await {
+                println!("NTP: {}", server);
+            }
+            println!(
+                "Setup AP: {}, password {}",
+                wifi::PORTAL_SSID,
+                wifi::get_portal_password().await
+            );
+            if command::has_command_key().await {
+                println!("Commands: signed only");
+            } else {
+                println!("Commands: unsigned accepted");
+            }
+        }
+        ConsoleCommand::Reboot => esp_hal::system::software_reset(),
+        ConsoleCommand::Portal => {
+            wifi::request_portal();
+            println!("Opening the setup portal");
+        }
+        ConsoleCommand::Forget => {
+            config::remove(Key::WifiCredentials).await.ok();
+            config::remove(Key::WifiNetworks).await.ok();
+            config::remove(Key::MqttCredentials).await.ok();
+            config::remove(Key::CommandKey).await.ok();
+            config::remove(Key::WifiJoined).await.ok();
+            wifi::set_credentials(None).await;
+            wifi::set_joined_before(false);
+            wifi::set_networks(Default::default()).await;
+            mqtt::set_credentials(None).await;
+            command::set_command_key(None).await;
+            println!("Credentials dropped");
+        }
+        ConsoleCommand::Wifi { ssid, password } => {
+            let (Some(ssid), Some(password)) = (to_field(ssid), to_field(password)) else {
+                println!("SSID or password too long");
+                return;
+            };
+            let credentials = WifiCredentials { ssid, password };
+            match config::save(Key::WifiCredentials, &credentials).await {
+                Ok(()) => println!("WiFi credentials saved, reboot to apply"),
+                Err(e) => println!("Can't save WiFi credentials: {:?}", e),
+            }
+            wifi::set_credentials(Some(credentials)).await;
+        }
+        ConsoleCommand::AddNetwork {
+            ssid,
+            password,
+            priority,
+        } => {
+            let (Some(ssid), Some(password)) = (to_field(ssid), to_field(password)) else {
+                println!("SSID or password too long");
+                return;
+            };
+            let mut networks = wifi::get_networks().await;
+            networks.retain(|network| network.ssid != ssid);
+            let network = KnownNetwork {
+                ssid,
+                password,
+                priority,
+            };
+            if networks.push(network).is_err() {
+                println!("Too many networks, drop one first");
+                return;
+            }
+            match config::save(Key::WifiNetworks, &networks).await {
+                Ok(()) => println!("WiFi network saved"),
+                Err(e) => println!("Can't save WiFi network: {:?}", e),
+            }
+            wifi::set_networks(networks).await;
+        }
+        ConsoleCommand::RemoveNetwork(ssid) => {
+            let mut networks = wifi::get_networks().await;
+            networks.retain(|network| network.ssid != ssid);
+            match config::save(Key::WifiNetworks, &networks).await {
+                Ok(()) => println!("WiFi network dropped"),
+                Err(e) => println!("Can't save WiFi networks: {:?}", e),
+            }
+            wifi::set_networks(networks).await;
+        }
+        ConsoleCommand::Mqtt { user, password } => {
+            let (Some(user), Some(password)) = (to_field(user), to_field(password)) else {
+                println!("User or password too long");
+                return;
+            };
+            let credentials = MqttCredentials { user, password };
+            match config::save(Key::MqttCredentials, &credentials).await {
+                Ok(()) => println!("MQTT credentials saved, reboot to apply"),
+                Err(e) => println!("Can't save MQTT credentials: {:?}", e),
+            }
+            mqtt::set_credentials(Some(credentials)).await;
+        }
+        ConsoleCommand::Broker { host, tls } => {
+            let host = match host.map(to_field) {
+                Some(None) => {
+                    println!("Host too long");
+                    return;
+                }
+                host => host.flatten(),
+            };
+            let saved = match &host {
+                Some(host) => config::save(Key::MqttServer, host).await,
+                None => config::remove(Key::MqttServer).await,
+            };
+            let saved = match saved {
+                Ok(()) => config::save(Key::MqttTls, &tls).await,
+                Err(e) => Err(e),
+            };
+            match saved {
+                Ok(()) => println!("MQTT broker saved, reboot to apply"),
+                Err(e) => println!("Can't save MQTT broker: {:?}", e),
+            }
+            mqtt::set_server(host).await;
+            mqtt::set_tls(tls).await;
+        }
+        ConsoleCommand::Prefix(prefix) => {
+            let Some(prefix) = to_field(prefix) else {
+                println!("Prefix too long");
+                return;
+            };
+            match config::save(Key::TopicPrefix, &prefix).await {
+                Ok(()) => println!("Topic prefix saved"),
+                Err(e) => println!("Can't save topic prefix: {:?}", e),
+            }
+            mqtt::set_topic_prefix(Some(prefix)).await;
+        }
+        ConsoleCommand::CommandKey(key) => {
+            let saved = match key {
+                Some(key) => config::save(Key::CommandKey, &key).await,
+                None => config::remove(Key::CommandKey).await,
+            };
+            match saved {
+                Ok(()) => println!("Command key saved"),
+                Err(e) => println!("Can't save command key: {:?}", e),
+            }
+            command::set_command_key(key).await;
+        }
+        ConsoleCommand::Ip(ip) => {
+            let saved = match &ip {
+                Some(ip) => config::save(Key::StaticIp, ip).await,
+                None => config::remove(Key::StaticIp).await,
+            };
+            match saved {
+                Ok(()) => println!("IP settings saved, reboot to apply"),
+                Err(e) => println!("Can't save IP settings: {:?}", e),
+            }
+            stack::set_static_ip(ip).await;
+        }
+        ConsoleCommand::DhcpTimeout(secs) => {
+            match config::save(Key::DhcpTimeout, &secs).await {
+                Ok(()) => println!("DHCP timeout saved, reboot to apply"),
+                Err(e) => println!("Can't save DHCP timeout: {:?}", e),
+            }
+            stack::set_dhcp_timeout(secs).await;
+        }
+        ConsoleCommand::Hostname(name) => {
+            let name = name.and_then(to_field);
+            let saved = match &name {
+                Some(name) => config::save(Key::Hostname, name).await,
+                None => config::remove(Key::Hostname).await,
+            };
+            match saved {
+                Ok(()) => println!("Hostname saved, reboot to apply"),
+                Err(e) => println!("Can't save hostname: {:?}", e),
+            }
+            stack::set_hostname(name).await;
+        }
+        ConsoleCommand::NtpServers(servers) => {
+            let servers = match servers {
+                Some(names) => {
+                    let mut servers = Vec::new();
+                    for name in names {
+                        let Some(server) = to_field(name) else {
+                            println!("Server name too long");
+                            return;
+                        };
+                        servers.push(server).ok();
+                    }
+                    Some(servers)
+                }
+                None => None,
+            };
+            let saved = match &servers {
+                Some(servers) => config::save(Key::NtpServers, servers).await,
+                None => config::remove(Key::NtpServers).await,
+            };
+            match saved {
+                Ok(()) => println!("NTP servers saved"),
+                Err(e) => println!("Can't save NTP servers: {:?}", e),
+            }
+            ntp::set_servers(servers).await;
+        }
+    }
+}
+
+pub fn console_init(
+    uart: UART0<'static>,
+    rx_pin: GPIO3<'static>,
+) -> Result<UartRx<'static, esp_hal::Async>, HwError> {
+    let rx =
+        UartRx::new(uart, UartConfig::default()).map_err(|_| UartError::InitializationFailed)?;
+    Ok(rx.with_rx(rx_pin).into_async())
+}
+
+/// Serial console to provision credentials over the UART used for logs
+#[embassy_executor::task]
+pub async fn console_task(mut rx: UartRx<'static, esp_hal::Async>) {
+    let mut line = LineBuffer::new();
+    let mut buf = [0u8; 32];
+
+    if wifi::get_credentials().await.is_none() {
+        println!("WiFi is not provisioned");
+        println!("{}", HELP);
+    }
+
+    loop {
+        let Ok(len) = rx.read_async(&mut buf).await else {
+            continue;
+        };
+        for byte in &buf[..len] {
+            if let Some(text) = line.push(*byte) {
+                handle(text).await;
+            }
+        }
+    }
+}
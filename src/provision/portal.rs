@@ -0,0 +1,276 @@
+//! Captive setup portal served on the fallback access point
+
+use core::fmt::Write;
+use core::net::Ipv4Addr;
+use embassy_futures::select::{select, select4};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Ipv4Cidr, Stack, StackResources, StaticConfigV4};
+use embassy_time::{Duration, Timer};
+use esp_radio::wifi::WifiDevice;
+use heapless::String;
+use log::{error, warn};
+use static_cell::StaticCell;
+
+use crate::config::{self, Key};
+use crate::display::{STATUS_LEN, update_status};
+use crate::error::StorageError;
+use crate::io::wifi::{WifiCredentials, get_portal_password, wait_for_portal};
+use crate::net::dhcp::{self, DhcpServer};
+use crate::net::dns;
+use crate::net::http::{Method, ParseError, Status, parse_request, redirect_head, response_head};
+use crate::net::mqtt::MqttCredentials;
+
+use super::form::{ProvisioningForm, parse_form};
+
+const PORTAL_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1);
+const DHCP_POOL_START: u8 = 10;
+const DHCP_POOL_SIZE: usize = 4;
+// DHCP, DNS and a single HTTP connection
+const PORTAL_SOCKETS: usize = 4;
+// Nobody came to configure us, try the stored network again
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(600);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const HTTP_BUFFER_SIZE: usize = 1024;
+const UDP_BUFFER_SIZE: usize = 512;
+// Let the last response leave before dropping the connection
+const CLOSE_DELAY: Duration = Duration::from_millis(100);
+const REBOOT_DELAY: Duration = Duration::from_secs(1);
+
+const PAGE_HEAD: &str = "<!DOCTYPE html><html><head><meta name=viewport content=\"width=device-width\">\
+<title>Water setup</title></head><body><h2>Water setup</h2>";
+const PAGE_FORM: &str = "<form method=post action=/save>\
+<p>WiFi SSID<br><input name=ssid maxlength=32 required></p>\
+<p>WiFi password<br><input name=password type=password maxlength=63></p>\
+<p>MQTT host (blank to find it via mDNS)<br><input name=mqtt_host maxlength=64></p>\
+<p>MQTT user<br><input name=mqtt_user maxlength=32></p>\
+<p>MQTT password<br><input name=mqtt_password type=password maxlength=64></p>\
+<p>Time zone (POSIX, e.g. JST-9)<br><input name=tz maxlength=48></p>\
+<p><button>Save and reboot</button></p></form></body></html>";
+const PAGE_SAVED: &str = "<p>Saved, rebooting.</p></body></html>";
+
+async fn write_all(socket: &mut TcpSocket<'_>, mut data: &[u8]) -> Result<(), ()> {
+    while !data.is_empty() {
+        match socket.write(data).await {
+            Ok(0) | Err(_) => return Err(()),
+            Ok(len) => data = &data[len..],
+        }
+    }
+    Ok(())
+}
+
+/// Send a page made of the common head and `parts`
+async fn send_page(socket: &mut TcpSocket<'_>, status: Status, parts: &[&str]) -> Result<(), ()> {
+    let len = PAGE_HEAD.len() + parts.iter().map(|part| part.len()).sum::<usize>();
+    write_all(socket, response_head(status, "text/html", len).as_bytes()).await?;
+    write_all(socket, PAGE_HEAD.as_bytes()).await?;
+    for part in parts {
+        write_all(socket, part.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+async fn apply(form: &ProvisioningForm) -> Result<(), StorageError> {
+    let wifi = WifiCredentials {
+        ssid: form.ssid.clone(),
+        password: form.password.clone(),
+    };
+    config::save(Key::WifiCredentials, &wifi).await?;
+
+    if form.mqtt_user.is_empty() {
+        config::remove(Key::MqttCredentials).await?;
+    } else {
+        let mqtt = MqttCredentials {
+            user: form.mqtt_user.clone(),
+            password: form.mqtt_password.clone(),
+        };
+        config::save(Key::MqttCredentials, &mqtt).await?;
+    }
+    if !form.mqtt_host.is_empty() {
+        config::save(Key::MqttServer, &form.mqtt_host).await?;
+    }
+    if !form.timezone.is_empty() {
+        config::save(Key::Timezone, &form.timezone).await?;
+    }
+    Ok(())
+}
+
+/// Serve one connection, `true` once the settings are saved
+async fn serve(socket: &mut TcpSocket<'_>) -> bool {
+    let mut buf = [0u8; HTTP_BUFFER_SIZE];
+    let mut len = 0;
+    let request = loop {
+        match socket.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => return false,
+            Ok(read) => len += read,
+        }
+        match parse_request(&buf[..len], buf.len()) {
+            Err(ParseError::Incomplete) => continue,
+            result => break result,
+        }
+    };
+
+    let saved = match request {
+        Ok(request) if request.path == "/save" && request.method == Method::Post => {
+            match parse_form(request.body) {
+                Ok(form) => match apply(&form).await {
+                    Ok(()) => {
+                        send_page(socket, Status::OK, &[PAGE_SAVED]).await.ok();
+                        true
+                    }
+                    Err(e) => {
+                        error!("Portal: can't save settings: {:?}", e);
+                        let parts = ["<p>Can't save settings</p>", PAGE_FORM];
+                        send_page(socket, Status::INTERNAL_ERROR, &parts).await.ok();
+                        false
+                    }
+                },
+                Err(e) => {
+                    let parts = ["<p>", e.message(), "</p>", PAGE_FORM];
+                    send_page(socket, Status::UNPROCESSABLE, &parts).await.ok();
+                    false
+                }
+            }
+        }
+        Ok(request) if request.path == "/" => {
+            send_page(socket, Status::OK, &[PAGE_FORM]).await.ok();
+            false
+        }
+        // Connectivity checks of phones land here and pop up the portal
+        Ok(_) => {
+            write_all(socket, redirect_head("/").as_bytes()).await.ok();
+            false
+        }
+        Err(ParseError::TooLarge) => {
+            let head = response_head(Status::PAYLOAD_TOO_LARGE, "text/plain", 0);
+            write_all(socket, head.as_bytes()).await.ok();
+            false
+        }
+        Err(_) => {
+            let head = response_head(Status::BAD_REQUEST, "text/plain", 0);
+            write_all(socket, head.as_bytes()).await.ok();
+            false
+        }
+    };
+
+    socket.flush().await.ok();
+    saved
+}
+
+async fn http_server(stack: Stack<'static>) {
+    let mut rx_buffer = [0; HTTP_BUFFER_SIZE];
+    let mut tx_buffer = [0; HTTP_BUFFER_SIZE];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(HTTP_TIMEOUT));
+        if socket.accept(80).await.is_err() {
+            continue;
+        }
+
+        let saved = serve(&mut socket).await;
+        socket.close();
+        Timer::after(CLOSE_DELAY).await;
+        socket.abort();
+
+        if saved {
+            update_status("Setup saved").await.ok();
+            Timer::after(REBOOT_DELAY).await;
+            esp_hal::system::software_reset();
+        }
+    }
+}
+
+async fn dhcp_server(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; UDP_BUFFER_SIZE];
+    let mut tx_buffer = [0; UDP_BUFFER_SIZE];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if socket.bind(dhcp::SERVER_PORT).is_err() {
+        error!("Portal: can't bind DHCP");
+        return;
+    }
+
+    let mut server = DhcpServer::<DHCP_POOL_SIZE>::new(PORTAL_IP, DHCP_POOL_START);
+    let mut packet = [0u8; UDP_BUFFER_SIZE];
+    let mut reply = [0u8; dhcp::MAX_REPLY_LEN];
+    let broadcast: IpEndpoint = (Ipv4Addr::BROADCAST, dhcp::CLIENT_PORT).into();
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        if let Some(len) = server.handle(&packet[..len], &mut reply) {
+            socket.send_to(&reply[..len], broadcast).await.ok();
+        }
+    }
+}
+
+async fn dns_server(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; UDP_BUFFER_SIZE];
+    let mut tx_buffer = [0; UDP_BUFFER_SIZE];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if socket.bind(dns::PORT).is_err() {
+        error!("Portal: can't bind DNS");
+        return;
+    }
+
+    let mut query = [0u8; UDP_BUFFER_SIZE];
+    let mut reply = [0u8; UDP_BUFFER_SIZE];
+    loop {
+        let Ok((len, meta)) = socket.recv_from(&mut query).await else {
+            continue;
+        };
+        if let Some(len) = dns::captive_response(&query[..len], PORTAL_IP, &mut reply) {
+            socket.send_to(&reply[..len], meta).await.ok();
+        }
+    }
+}
+
+/// Serve the setup form on the access point once the WiFi task falls back to it
+#[embassy_executor::task]
+pub async fn portal_task(device: WifiDevice<'static>, seed: u64) {
+    wait_for_portal().await;
+
+    let resources = {
+        static RESOURCES: StaticCell<StackResources<PORTAL_SOCKETS>> = StaticCell::new();
+        RESOURCES.init(StackResources::<PORTAL_SOCKETS>::new())
+    };
+    let config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(PORTAL_IP, 24),
+        gateway: Some(PORTAL_IP),
+        dns_servers: Default::default(),
+    });
+    let (stack, mut runner) = embassy_net::new(device, config, resources, seed);
+    let mut status: String<STATUS_LEN> = String::new();
+    write!(status, "AP key {}", get_portal_password().await).ok();
+    update_status(&status).await.ok();
+
+    select(
+        select4(
+            runner.run(),
+            dhcp_server(stack),
+            dns_server(stack),
+            http_server(stack),
+        ),
+        Timer::after(PORTAL_TIMEOUT),
+    )
+    .await;
+
+    warn!("Portal timed out, rebooting");
+    esp_hal::system::software_reset();
+}
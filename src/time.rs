@@ -1,5 +1,6 @@
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
+use heapless::String;
 
 use jiff::{
     Timestamp,
@@ -9,7 +10,30 @@ use jiff::{
 
 use crate::{error::SysError, io::rtc::get_time};
 
-pub static TZ: TimeZone = tz::get!("Asia/Tokyo");
+pub use water_core::time::TZ_LEN;
+
+static DEFAULT_TZ: TimeZone = tz::get!("Asia/Tokyo");
+static TZ: Mutex<CriticalSectionRawMutex, Option<(String<TZ_LEN>, TimeZone)>> = Mutex::new(None);
+
+pub async fn timezone() -> TimeZone {
+    match &*TZ.lock().await {
+        Some((_, tz)) => tz.clone(),
+        None => DEFAULT_TZ.clone(),
+    }
+}
+
+/// The POSIX TZ string set, `None` for the built-in zone
+pub async fn timezone_name() -> Option<String<TZ_LEN>> {
+    TZ.lock().await.as_ref().map(|(name, _)| name.clone())
+}
+
+/// Switch to a POSIX TZ string like `JST-9`
+pub async fn set_timezone(posix: &str) -> Result<(), SysError> {
+    let name = String::try_from(posix).map_err(|_| SysError::BadTimezone)?;
+    let tz = TimeZone::posix(posix)?;
+    TZ.lock().await.replace((name, tz));
+    Ok(())
+}
 
 pub async fn localtime() -> Result<Time, SysError> {
     let timestamp = get_time().await?;
@@ -18,7 +42,7 @@ pub async fn localtime() -> Result<Time, SysError> {
         Err(SysError::NoTime)
     } else {
         let now = Timestamp::from_microsecond(timestamp as i64)?;
-        Ok(now.to_zoned(TZ.clone()).time())
+        Ok(now.to_zoned(timezone().await).time())
     }
 }
 
@@ -49,7 +73,12 @@ pub async fn get_next_watering() -> Timestamp {
     *NEXT_WATERING.lock().await
 }
 
-pub async fn get_next_watering_time() -> Time {
+/// Local time of the next scheduled watering, `None` if nothing is scheduled
+pub async fn get_next_watering_time() -> Option<Time> {
     let time = *NEXT_WATERING.lock().await;
-    time.to_zoned(TZ.clone()).time()
+    if time == Timestamp::UNIX_EPOCH {
+        None
+    } else {
+        Some(time.to_zoned(timezone().await).time())
+    }
 }
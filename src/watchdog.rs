@@ -5,7 +5,7 @@ use embassy_sync::mutex::Mutex;
 use esp_hal::peripherals::TIMG1;
 use esp_hal::time::Duration;
 use esp_hal::timer::timg::{MwdtStage, Wdt};
-use esp_println::println;
+use log::{info, warn};
 
 use crate::error::SysError;
 
@@ -24,7 +24,7 @@ pub fn init_watchdog(mut wdt: Wdt<TIMG1<'static>>) -> Result<(), SysError> {
     // Enable the watchdog
     wdt.enable();
 
-    println!("Watchdog initialized with 60s timeout on TIMG1");
+    info!("Watchdog initialized with 60s timeout on TIMG1");
 
     // Store the watchdog in global mutex
     critical_section::with(|_| {
@@ -70,7 +70,7 @@ pub fn disable_watchdog() {
         {
             wdt.disable();
             WATCHDOG_ENABLED.store(false, Ordering::SeqCst);
-            println!("WARNING: Watchdog disabled");
+            warn!("Watchdog disabled");
         }
     });
 }
@@ -83,7 +83,7 @@ pub fn enable_watchdog() {
         {
             wdt.enable();
             WATCHDOG_ENABLED.store(true, Ordering::SeqCst);
-            println!("Watchdog re-enabled");
+            info!("Watchdog re-enabled");
         }
     });
 }
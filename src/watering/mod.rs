@@ -0,0 +1,283 @@
+use crate::power::humidity_level;
+use crate::time::{now, set_last_watered, set_next_watering, timezone};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::gpio::{Input, Output};
+use jiff::Timestamp;
+use log::info;
+
+pub use water_core::watering::{BOARD_ZONES, controller, schedule};
+
+use controller::{Event, Sample, Tunables, WateringController};
+use schedule::{MAX_FIXED_DURATION_SECS, Schedule, Window};
+
+/// Zone controlled by the manual override button
+const BUTTON_ZONE: usize = 0;
+
+static LOW_HUMIDITY_LIMIT: Mutex<CriticalSectionRawMutex, [u16; BOARD_ZONES]> =
+    Mutex::new([10; BOARD_ZONES]);
+static PUMPING: Mutex<CriticalSectionRawMutex, [bool; BOARD_ZONES]> =
+    Mutex::new([false; BOARD_ZONES]);
+static SCHEDULE: Mutex<CriticalSectionRawMutex, Schedule> = Mutex::new(Schedule::new());
+static MANUAL_REQUESTS: Mutex<CriticalSectionRawMutex, [Option<u32>; BOARD_ZONES]> =
+    Mutex::new([None; BOARD_ZONES]);
+static STOP_REQUESTED: Mutex<CriticalSectionRawMutex, bool> = Mutex::new(false);
+static PAUSED_UNTIL: Mutex<CriticalSectionRawMutex, Timestamp> =
+    Mutex::new(Timestamp::constant(0, 0));
+static TUNABLES: Mutex<CriticalSectionRawMutex, Option<Tunables>> = Mutex::new(None);
+static PUMP_STATS: Mutex<CriticalSectionRawMutex, [PumpStats; BOARD_ZONES]> =
+    Mutex::new([PumpStats::new(); BOARD_ZONES]);
+
+/// Pump usage of a zone since boot
+#[derive(Debug, Copy, Clone)]
+pub struct PumpStats {
+    /// Times the pump was switched on
+    pub cycles: u32,
+    pub runtime: Duration,
+}
+
+impl PumpStats {
+    const fn new() -> Self {
+        PumpStats {
+            cycles: 0,
+            runtime: Duration::from_ticks(0),
+        }
+    }
+}
+
+// Clock jumps larger than this (e.g. the first NTP sync) don't fire fixed windows
+const SCHEDULE_MAX_GAP: jiff::SignedDuration = jiff::SignedDuration::from_secs(60);
+
+pub async fn get_low_humidity_limit(zone: usize) -> u16 {
+    LOW_HUMIDITY_LIMIT
+        .lock()
+        .await
+        .get(zone)
+        .copied()
+        .unwrap_or(0)
+}
+
+pub async fn get_low_humidity_limits() -> [u16; BOARD_ZONES] {
+    *LOW_HUMIDITY_LIMIT.lock().await
+}
+
+/// Returns `false` if there is no such zone
+pub async fn set_low_humidity_limit(zone: usize, lim: u16) -> bool {
+    match LOW_HUMIDITY_LIMIT.lock().await.get_mut(zone) {
+        Some(limit) => {
+            *limit = lim;
+            true
+        }
+        None => false,
+    }
+}
+
+pub async fn is_pumping(zone: usize) -> bool {
+    PUMPING.lock().await.get(zone).copied().unwrap_or(false)
+}
+
+pub async fn get_pump_stats() -> [PumpStats; BOARD_ZONES] {
+    *PUMP_STATS.lock().await
+}
+
+pub async fn get_schedule() -> Schedule {
+    *SCHEDULE.lock().await
+}
+
+pub async fn set_schedule(schedule: Schedule) {
+    SCHEDULE.lock().await.clone_from(&schedule);
+}
+
+/// Replace or clear (`None`) a schedule slot
+pub async fn set_schedule_window(slot: usize, window: Option<Window>) -> bool {
+    let mut schedule = SCHEDULE.lock().await;
+    match schedule.windows.get_mut(slot) {
+        Some(entry) => {
+            *entry = window;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Queue a fixed cycle, `false` for a bad zone or duration
+pub async fn request_watering(zone: usize, secs: u32) -> bool {
+    if secs == 0 || secs > MAX_FIXED_DURATION_SECS {
+        return false;
+    }
+    match MANUAL_REQUESTS.lock().await.get_mut(zone) {
+        Some(request) => {
+            *request = Some(secs);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Abort running cycles and forget the queued ones
+pub async fn stop_watering() {
+    MANUAL_REQUESTS.lock().await.fill(None);
+    *STOP_REQUESTED.lock().await = true;
+}
+
+/// Suspend automatic watering, `UNIX_EPOCH` resumes it
+pub async fn pause_until(until: Timestamp) {
+    *PAUSED_UNTIL.lock().await = until;
+}
+
+pub async fn get_paused_until() -> Timestamp {
+    *PAUSED_UNTIL.lock().await
+}
+
+pub async fn get_tunables() -> Tunables {
+    TUNABLES.lock().await.unwrap_or_default()
+}
+
+pub async fn set_tunables(tunables: Tunables) {
+    TUNABLES.lock().await.replace(tunables);
+}
+
+/// Drives all zones, letting only one pump run at a time to limit supply current
+#[embassy_executor::task]
+pub async fn watering_task(
+    mut compressors: [Output<'static>; BOARD_ZONES],
+    button: Option<Input<'static>>,
+) {
+    let mut controllers: [WateringController; BOARD_ZONES] =
+        core::array::from_fn(|_| WateringController::new(Tunables::default()));
+    // Scheduled and remote cycles waiting for the pump line to become free
+    let mut pending_fixed: [Option<Duration>; BOARD_ZONES] = [None; BOARD_ZONES];
+    let mut last_step = Instant::now();
+    let mut last_time: Option<Timestamp> = None;
+
+    loop {
+        let schedule = get_schedule().await;
+        let tz = timezone().await;
+        let time = now().await.ok().filter(|ts| ts.as_second() > 1_000_000_000);
+        let paused_until = get_paused_until().await;
+        let paused = time.is_some_and(|ts| ts < paused_until);
+
+        let tunables = get_tunables().await;
+        for controller in controllers.iter_mut() {
+            controller.set_tunables(tunables);
+        }
+
+        if core::mem::take(&mut *STOP_REQUESTED.lock().await) {
+            pending_fixed.fill(None);
+            for (zone, controller) in controllers.iter_mut().enumerate() {
+                if controller.stop() {
+                    info!("Watering #{}: stopped remotely", zone);
+                }
+            }
+        }
+
+        if let Some(ts) = time {
+            if !paused
+                && let Some(since) = last_time
+                && ts.duration_since(since) < SCHEDULE_MAX_GAP
+                && let Some(secs) = schedule.fixed_due(since, ts, &tz)
+            {
+                info!("Watering: scheduled cycle for {}s", secs);
+                pending_fixed.fill(Some(Duration::from_secs(secs as u64)));
+            }
+            set_next_watering(
+                schedule
+                    .next_occurrence(ts, &tz)
+                    .unwrap_or(Timestamp::UNIX_EPOCH),
+            )
+            .await;
+        }
+        last_time = time;
+
+        for (zone, request) in MANUAL_REQUESTS.lock().await.iter_mut().enumerate() {
+            if let Some(secs) = request.take() {
+                info!("Watering #{}: remote cycle for {}s", zone, secs);
+                pending_fixed[zone] = Some(Duration::from_secs(secs as u64));
+            }
+        }
+
+        let allowed = !paused && time.is_none_or(|ts| schedule.humidity_allowed(ts, &tz));
+        let elapsed = last_step.elapsed();
+        last_step = Instant::now();
+        let mut next_poll = Duration::MAX;
+
+        for zone in 0..BOARD_ZONES {
+            let busy_elsewhere = controllers
+                .iter()
+                .enumerate()
+                .any(|(other, ctrl)| other != zone && ctrl.pump_on());
+
+            if !busy_elsewhere
+                && let Some(duration) = pending_fixed[zone]
+                && controllers[zone].start_fixed(duration)
+            {
+                pending_fixed[zone] = None;
+                if let Some(ts) = time {
+                    set_last_watered(ts).await;
+                }
+            }
+
+            let sample = Sample {
+                humidity: humidity_level(zone).await,
+                limit: get_low_humidity_limit(zone).await as u32,
+                button: zone == BUTTON_ZONE
+                    && !busy_elsewhere
+                    && button.as_ref().is_some_and(|btn| btn.is_low()),
+                // Without a valid clock only the humidity matters
+                allowed: allowed && !busy_elsewhere,
+            };
+
+            let controller = &mut controllers[zone];
+            let step = controller.step(sample, elapsed);
+            next_poll = next_poll.min(step.next_poll);
+
+            match step.event {
+                Some(Event::Started { humidity, limit }) => {
+                    info!(
+                        "Watering #{}: humidity {}% < limit {}% → start",
+                        zone, humidity, limit
+                    );
+                    // Record watering start
+                    if let Some(ts) = time {
+                        set_last_watered(ts).await;
+                    }
+                }
+                Some(Event::EarlyStop { humidity, limit }) => {
+                    info!(
+                        "Watering #{}: early stop at {}% (≥ {}% + {}%)",
+                        zone,
+                        humidity,
+                        limit,
+                        controller.tunables().hysteresis
+                    );
+                    info!("Watering #{}: cycle complete", zone);
+                }
+                Some(Event::MaxOnTime) | Some(Event::FixedFinished) => {
+                    info!("Watering #{}: cycle complete", zone)
+                }
+                Some(Event::OverrideStarted) => info!("Watering #{}: manual override", zone),
+                Some(Event::OverrideFinished) => {
+                    info!("Watering #{}: manual override released", zone)
+                }
+                None => {}
+            }
+
+            if step.pump {
+                compressors[zone].set_high();
+            } else {
+                compressors[zone].set_low();
+            }
+            let mut pumping = PUMPING.lock().await;
+            let stats = &mut PUMP_STATS.lock().await[zone];
+            if pumping[zone] {
+                stats.runtime += elapsed;
+            } else if step.pump {
+                stats.cycles += 1;
+            }
+            pumping[zone] = step.pump;
+        }
+
+        Timer::after(next_poll).await;
+    }
+}
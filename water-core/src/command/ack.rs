@@ -0,0 +1,106 @@
+//! Structured command results published on the acknowledgement topic
+
+use serde::Serialize;
+
+use crate::watering::schedule::Window;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum Rejection {
+    /// Not a valid command JSON
+    Malformed,
+    BadZone,
+    BadSlot,
+    BadWindow,
+    BadDuration,
+    OutOfRange,
+    BadTimezone,
+    /// The clock isn't set yet
+    NoTime,
+    /// A command key is set but the message carries no signature
+    Unsigned,
+    BadSignature,
+    /// The nonce was already used
+    Replay,
+}
+
+/// Setting in effect after the command
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum AckValue {
+    Number(u32),
+    /// Unix seconds
+    Timestamp(i64),
+    Window(Option<Window>),
+}
+
+pub type Outcome = Result<Option<AckValue>, Rejection>;
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Ack {
+    /// Request id of the control message, its nonce if signed. rust-mqtt
+    /// doesn't expose the v5 correlation data, so this matches the replies.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    /// Variant name of the command, empty if it couldn't be parsed
+    pub command: &'static str,
+    pub accepted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<Rejection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<AckValue>,
+}
+
+impl Ack {
+    pub fn new(command: &'static str, outcome: Outcome) -> Self {
+        match outcome {
+            Ok(value) => Ack {
+                id: None,
+                command,
+                accepted: true,
+                reason: None,
+                value,
+            },
+            Err(reason) => Ack {
+                id: None,
+                command,
+                accepted: false,
+                reason: Some(reason),
+                value: None,
+            },
+        }
+    }
+
+    pub fn malformed() -> Self {
+        Ack::new("", Err(Rejection::Malformed))
+    }
+
+    pub fn with_id(self, id: Option<u64>) -> Self {
+        Ack { id, ..self }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(ack: &Ack) -> heapless::String<96> {
+        serde_json_core::to_string(ack).unwrap()
+    }
+
+    #[test]
+    fn echoes_the_request_id() {
+        let ack = Ack::new("StartWatering", Ok(Some(AckValue::Number(30))));
+        assert_eq!(
+            json(&ack),
+            r#"{"command":"StartWatering","accepted":true,"value":30}"#
+        );
+        assert_eq!(
+            json(&ack.with_id(Some(1_700_000_000_000))),
+            r#"{"id":1700000000000,"command":"StartWatering","accepted":true,"value":30}"#
+        );
+        assert_eq!(
+            json(&Ack::new("", Err(Rejection::Replay)).with_id(Some(7))),
+            r#"{"id":7,"command":"","accepted":false,"reason":"Replay"}"#
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! Optional HMAC-SHA256 authentication of control messages
+//!
+//! A signed message is `<nonce>:<signature>:<command JSON>`. The signature
+//! is the hex HMAC-SHA256 of `<target>:<nonce>:<command JSON>` under the
+//! device key, the target being the device id or `all` for the broadcast
+//! topic. Nonces must grow with every command, after a restart also past
+//! the [`NONCE_WINDOW`] following the last stored one. The Unix time in
+//! milliseconds works:
+//!
+//! ```sh
+//! cmd='"StopWatering"'; nonce=$(date +%s%3N)
+//! sig=$(printf '%s' "$DEVICE:$nonce:$cmd" | openssl dgst -sha256 -mac HMAC -macopt hexkey:$KEY -r | cut -d' ' -f1)
+//! mosquitto_pub -t water/$DEVICE/control -m "$nonce:$sig:$cmd"
+//! ```
+//!
+//! A command signed for one unit is rejected by every other one sharing
+//! its key. Only commands signed for `all` are meant to reach them all.
+//!
+//! Acknowledgements echo the nonce as `id`. Without a key, the message may
+//! start with an `<id>:` of its own instead.
+
+use core::fmt::Write;
+use heapless::String;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::command::ack::Rejection;
+
+pub const KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 32;
+
+pub type CommandKey = [u8; KEY_LEN];
+
+/// How far the accepted nonce may run ahead of the persisted one, a minute
+/// of millisecond timestamps
+pub const NONCE_WINDOW: u64 = 60_000;
+
+/// Last accepted nonce, persisted in steps to spare the flash
+///
+/// A nonce is only written once it gets more than `NONCE_WINDOW` past the
+/// stored one, and after a restart nonces up to `NONCE_WINDOW` past the
+/// stored one are refused. Whatever was accepted but not written is
+/// covered by that window, so it can't be replayed.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NonceGuard {
+    last: u64,
+    /// Missing until a nonce is stored
+    saved: Option<u64>,
+}
+
+impl NonceGuard {
+    pub const fn new() -> Self {
+        NonceGuard {
+            last: 0,
+            saved: None,
+        }
+    }
+
+    /// Continue from the nonce stored before a restart
+    pub fn restore(saved: u64) -> Self {
+        NonceGuard {
+            last: saved.saturating_add(NONCE_WINDOW),
+            saved: Some(saved),
+        }
+    }
+
+    pub fn last(&self) -> u64 {
+        self.last
+    }
+
+    /// Take `nonce` as the last one, `Some` when it has to be stored
+    pub fn accept(&mut self, nonce: u64) -> Option<u64> {
+        self.last = nonce;
+        match self.saved {
+            Some(saved) if nonce <= saved.saturating_add(NONCE_WINDOW) => None,
+            _ => {
+                self.saved = Some(nonce);
+                Some(nonce)
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Verified<'a> {
+    pub nonce: u64,
+    pub command: &'a [u8],
+}
+
+/// Decode exactly `N` bytes of hex
+pub fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.as_bytes();
+    if hex.len() != N * 2 {
+        return None;
+    }
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    let mut out = [0; N];
+    for (byte, pair) in out.iter_mut().zip(hex.chunks_exact(2)) {
+        *byte = (digit(pair[0])? << 4) | digit(pair[1])?;
+    }
+    Some(out)
+}
+
+fn mac(key: &[u8], target: &str, nonce: &[u8], command: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(target.as_bytes());
+    mac.update(b":");
+    mac.update(nonce);
+    mac.update(b":");
+    mac.update(command);
+    mac
+}
+
+/// `target` is the device id, or `all` for the broadcast topic
+pub fn sign(key: &CommandKey, target: &str, nonce: u64, command: &[u8]) -> [u8; SIGNATURE_LEN] {
+    let mut digits: String<20> = String::new();
+    write!(digits, "{}", nonce).ok();
+    mac(key, target, digits.as_bytes(), command)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+/// Split off the leading `<digits>:` request id, the nonce of a signed
+/// message, which unsigned ones may carry as `<id>:<command JSON>` too
+pub fn request_id(payload: &[u8]) -> (Option<u64>, &[u8]) {
+    let Some(colon) = payload.iter().position(|&b| b == b':') else {
+        return (None, payload);
+    };
+    let id = core::str::from_utf8(&payload[..colon])
+        .ok()
+        .filter(|digits| digits.bytes().all(|c| c.is_ascii_digit()))
+        .and_then(|digits| digits.parse::<u64>().ok());
+    match id {
+        Some(id) => (Some(id), &payload[colon + 1..]),
+        None => (None, payload),
+    }
+}
+
+/// Check the signature for `target` first, then that the nonce is newer
+/// than `last_nonce`
+pub fn verify<'a>(
+    key: &CommandKey,
+    target: &str,
+    payload: &'a [u8],
+    last_nonce: u64,
+) -> Result<Verified<'a>, Rejection> {
+    let mut parts = payload.splitn(3, |&b| b == b':');
+    let (Some(nonce_digits), Some(signature), Some(command)) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(Rejection::Unsigned);
+    };
+    let nonce = core::str::from_utf8(nonce_digits)
+        .ok()
+        .filter(|digits| digits.bytes().all(|c| c.is_ascii_digit()))
+        .and_then(|digits| digits.parse::<u64>().ok())
+        .ok_or(Rejection::Unsigned)?;
+    let signature = core::str::from_utf8(signature)
+        .ok()
+        .and_then(parse_hex::<SIGNATURE_LEN>)
+        .ok_or(Rejection::BadSignature)?;
+
+    mac(key, target, nonce_digits, command)
+        .verify_slice(&signature)
+        .map_err(|_| Rejection::BadSignature)?;
+    if nonce <= last_nonce {
+        return Err(Rejection::Replay);
+    }
+    Ok(Verified { nonce, command })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const DEVICE: &str = "a0b1c2d3e4f5";
+    // printf '%s' 'a0b1c2d3e4f5:1700000000000:"StopWatering"' | openssl dgst -sha256 -mac HMAC -macopt hexkey:$KEY
+    const SIGNED: &[u8] = b"1700000000000:\
+        9f9688da684989d00f835b28c6542c73415775a0e883016b4608dd927f0e9beb:\"StopWatering\"";
+
+    fn key() -> CommandKey {
+        parse_hex(KEY).unwrap()
+    }
+
+    #[test]
+    fn rfc4231_case_2() {
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"Jefe").unwrap();
+        mac.update(b"what do ya want for nothing?");
+        let digest = mac.finalize().into_bytes();
+        let expected: [u8; SIGNATURE_LEN] =
+            parse_hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843").unwrap();
+        assert_eq!(digest[..], expected[..]);
+    }
+
+    #[test]
+    fn accepts_signed() {
+        let verified = verify(&key(), DEVICE, SIGNED, 0).unwrap();
+        assert_eq!(verified.nonce, 1_700_000_000_000);
+        assert_eq!(verified.command, b"\"StopWatering\"");
+        assert_eq!(
+            sign(&key(), DEVICE, 1_700_000_000_000, b"\"StopWatering\"")[..4],
+            [0x9f, 0x96, 0x88, 0xda]
+        );
+    }
+
+    #[test]
+    fn binds_the_target() {
+        assert_eq!(
+            verify(&key(), "f5e4d3c2b1a0", SIGNED, 0),
+            Err(Rejection::BadSignature)
+        );
+        assert_eq!(
+            verify(&key(), "all", SIGNED, 0),
+            Err(Rejection::BadSignature)
+        );
+        let signature = sign(&key(), "all", 1_700_000_000_000, b"\"StopWatering\"");
+        assert_eq!(signature[..4], [0x15, 0x80, 0x62, 0xc2]);
+    }
+
+    #[test]
+    fn splits_the_request_id() {
+        assert_eq!(
+            request_id(b"42:\"StopWatering\""),
+            (Some(42), &b"\"StopWatering\""[..])
+        );
+        assert_eq!(request_id(SIGNED).0, Some(1_700_000_000_000));
+        assert_eq!(
+            request_id(b"{\"SetMqttTimeout\":30}"),
+            (None, &b"{\"SetMqttTimeout\":30}"[..])
+        );
+        assert_eq!(request_id(b":\"Reboot\""), (None, &b":\"Reboot\""[..]));
+    }
+
+    #[test]
+    fn rejects_replay() {
+        assert_eq!(
+            verify(&key(), DEVICE, SIGNED, 1_700_000_000_000),
+            Err(Rejection::Replay)
+        );
+    }
+
+    #[test]
+    fn stores_nonces_in_steps() {
+        let mut nonces = NonceGuard::new();
+        assert_eq!(nonces.accept(5), Some(5));
+        assert_eq!(nonces.accept(6), None);
+        assert_eq!(nonces.accept(NONCE_WINDOW + 5), None);
+        assert_eq!(nonces.accept(NONCE_WINDOW + 6), Some(NONCE_WINDOW + 6));
+        assert_eq!(nonces.last(), NONCE_WINDOW + 6);
+
+        // Nothing accepted since the last write can come back after a restart
+        let mut restarted = NonceGuard::restore(5);
+        assert_eq!(restarted.last(), NONCE_WINDOW + 5);
+        assert_eq!(
+            verify(&key(), DEVICE, SIGNED, restarted.last()).map(|v| v.nonce),
+            Ok(1_700_000_000_000)
+        );
+        assert_eq!(restarted.accept(1_700_000_000_000), Some(1_700_000_000_000));
+        assert_eq!(NonceGuard::restore(u64::MAX).last(), u64::MAX);
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let mut tampered = SIGNED.to_vec();
+        *tampered.last_mut().unwrap() = b' ';
+        assert_eq!(
+            verify(&key(), DEVICE, &tampered, 0),
+            Err(Rejection::BadSignature)
+        );
+        let mut other_key = key();
+        other_key[0] ^= 1;
+        assert_eq!(
+            verify(&other_key, DEVICE, SIGNED, 0),
+            Err(Rejection::BadSignature)
+        );
+        assert_eq!(
+            verify(&key(), DEVICE, b"1:zz:\"StopWatering\"", 0),
+            Err(Rejection::BadSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_unsigned() {
+        assert_eq!(
+            verify(&key(), DEVICE, b"\"StopWatering\"", 0),
+            Err(Rejection::Unsigned)
+        );
+        assert_eq!(
+            verify(&key(), DEVICE, b"{\"SetMqttTimeout\":30}", 0),
+            Err(Rejection::Unsigned)
+        );
+    }
+}
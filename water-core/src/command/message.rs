@@ -0,0 +1,369 @@
+//! Remote commands and their wire format
+//!
+//! Commands are externally tagged JSON: `{"StartWatering":{"zone":0,"secs":30}}`,
+//! variants without arguments are plain strings like `"StopWatering"`.
+
+use core::ops::RangeInclusive;
+use heapless::String;
+use jiff::tz::TimeZone;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use super::ack::Rejection;
+use crate::time::TZ_LEN;
+use crate::watering::BOARD_ZONES;
+use crate::watering::controller::TunableSettings;
+use crate::watering::schedule::{MAX_FIXED_DURATION_SECS, Window};
+
+pub const REPORTING_INTERVAL_SECS: RangeInclusive<u32> = 5..=3600;
+/// Above the keepalive publishes, `0` follows the reporting interval
+pub const SOCKET_TIMEOUT_SECS: RangeInclusive<u32> = 45..=3600;
+/// Humidity limits are percents
+pub const MAX_HUMIDITY_LIMIT: u16 = 100;
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    /// Same as [`Command::SetReportingInterval`], kept for older clients
+    SetMqttTimeout(u32),
+    SetHumidityTrigger {
+        zone: u8,
+        value: u16,
+    },
+    /// Set schedule slot, `None` clears it
+    SetSchedule {
+        slot: u8,
+        window: Option<Window>,
+    },
+    StartWatering {
+        zone: u8,
+        secs: u32,
+    },
+    /// Stop running cycles in all zones and drop the queued ones
+    StopWatering,
+    /// No humidity or scheduled watering until the Unix time, 0 resumes
+    PauseUntil {
+        timestamp: i64,
+    },
+    Reboot,
+    /// Publish the status right away
+    PublishNow,
+    SetReportingInterval {
+        secs: u32,
+    },
+    /// Drop the MQTT connection once the broker is silent this long, from
+    /// the next connection on. `0` derives it from the reporting interval.
+    SetSocketTimeout {
+        secs: u32,
+    },
+    SetTunables(TunableSettings),
+    /// POSIX TZ string like `JST-9`
+    SetTimezone {
+        tz: String<TZ_LEN>,
+    },
+    /// Level of the records streamed to the log topic, `"OFF"` to stop
+    SetLogLevel {
+        level: LevelFilter,
+    },
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::SetMqttTimeout(_) => "SetMqttTimeout",
+            Command::SetHumidityTrigger { .. } => "SetHumidityTrigger",
+            Command::SetSchedule { .. } => "SetSchedule",
+            Command::StartWatering { .. } => "StartWatering",
+            Command::StopWatering => "StopWatering",
+            Command::PauseUntil { .. } => "PauseUntil",
+            Command::Reboot => "Reboot",
+            Command::PublishNow => "PublishNow",
+            Command::SetReportingInterval { .. } => "SetReportingInterval",
+            Command::SetSocketTimeout { .. } => "SetSocketTimeout",
+            Command::SetTunables(_) => "SetTunables",
+            Command::SetTimezone { .. } => "SetTimezone",
+            Command::SetLogLevel { .. } => "SetLogLevel",
+        }
+    }
+
+    /// Checks which don't depend on the device state
+    pub fn validate(&self) -> Result<(), Rejection> {
+        let zone_valid = |zone: &u8| (*zone as usize) < BOARD_ZONES;
+
+        match self {
+            Command::SetHumidityTrigger { zone, .. } | Command::StartWatering { zone, .. }
+                if !zone_valid(zone) =>
+            {
+                Err(Rejection::BadZone)
+            }
+            Command::SetHumidityTrigger { value, .. } if *value > MAX_HUMIDITY_LIMIT => {
+                Err(Rejection::OutOfRange)
+            }
+            Command::SetSchedule {
+                window: Some(w), ..
+            } if !w.is_valid() => Err(Rejection::BadWindow),
+            Command::StartWatering { secs, .. }
+                if *secs == 0 || *secs > MAX_FIXED_DURATION_SECS =>
+            {
+                Err(Rejection::BadDuration)
+            }
+            Command::PauseUntil { timestamp } if *timestamp < 0 => Err(Rejection::OutOfRange),
+            Command::SetMqttTimeout(secs) | Command::SetReportingInterval { secs }
+                if !REPORTING_INTERVAL_SECS.contains(secs) =>
+            {
+                Err(Rejection::OutOfRange)
+            }
+            Command::SetSocketTimeout { secs }
+                if *secs != 0 && !SOCKET_TIMEOUT_SECS.contains(secs) =>
+            {
+                Err(Rejection::OutOfRange)
+            }
+            Command::SetTunables(settings) if !settings.is_valid() => Err(Rejection::OutOfRange),
+            Command::SetTimezone { tz } if TimeZone::posix(tz).is_err() => {
+                Err(Rejection::BadTimezone)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watering::schedule::WindowMode;
+
+    fn parse(json: &str) -> Option<Command> {
+        serde_json_core::from_str::<Command>(json)
+            .ok()
+            .map(|(command, _)| command)
+    }
+
+    fn check(json: &str) -> Result<(), Rejection> {
+        parse(json).expect(json).validate()
+    }
+
+    /// Serialize `command` and check it reads back the same
+    fn round_trip(command: Command, json: &str) {
+        let text: heapless::String<256> = serde_json_core::to_string(&command).unwrap();
+        assert_eq!(text, json);
+        assert_eq!(parse(json), Some(command));
+    }
+
+    #[test]
+    fn wire_format() {
+        round_trip(Command::StopWatering, r#""StopWatering""#);
+        round_trip(Command::Reboot, r#""Reboot""#);
+        round_trip(Command::PublishNow, r#""PublishNow""#);
+        round_trip(Command::SetMqttTimeout(60), r#"{"SetMqttTimeout":60}"#);
+        round_trip(
+            Command::SetHumidityTrigger { zone: 1, value: 35 },
+            r#"{"SetHumidityTrigger":{"zone":1,"value":35}}"#,
+        );
+        round_trip(
+            Command::SetSchedule {
+                slot: 2,
+                window: Some(Window {
+                    hour: 6,
+                    minute: 30,
+                    weekdays: 0x1f,
+                    mode: WindowMode::Fixed { duration_secs: 20 },
+                }),
+            },
+            r#"{"SetSchedule":{"slot":2,"window":{"hour":6,"minute":30,"weekdays":31,"mode":{"Fixed":{"duration_secs":20}}}}}"#,
+        );
+        round_trip(
+            Command::SetSchedule {
+                slot: 0,
+                window: None,
+            },
+            r#"{"SetSchedule":{"slot":0,"window":null}}"#,
+        );
+        round_trip(
+            Command::StartWatering { zone: 0, secs: 30 },
+            r#"{"StartWatering":{"zone":0,"secs":30}}"#,
+        );
+        round_trip(
+            Command::PauseUntil {
+                timestamp: 1_767_225_600,
+            },
+            r#"{"PauseUntil":{"timestamp":1767225600}}"#,
+        );
+        round_trip(
+            Command::SetReportingInterval { secs: 300 },
+            r#"{"SetReportingInterval":{"secs":300}}"#,
+        );
+        round_trip(
+            Command::SetSocketTimeout { secs: 120 },
+            r#"{"SetSocketTimeout":{"secs":120}}"#,
+        );
+        round_trip(
+            Command::SetTunables(TunableSettings {
+                max_on_time_secs: 45,
+                hysteresis: 3,
+                cooldown_secs: 10,
+            }),
+            r#"{"SetTunables":{"max_on_time_secs":45,"hysteresis":3,"cooldown_secs":10}}"#,
+        );
+        round_trip(
+            Command::SetTimezone {
+                tz: "JST-9".try_into().unwrap(),
+            },
+            r#"{"SetTimezone":{"tz":"JST-9"}}"#,
+        );
+        round_trip(
+            Command::SetLogLevel {
+                level: LevelFilter::Warn,
+            },
+            r#"{"SetLogLevel":{"level":"WARN"}}"#,
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        for json in [
+            r#""Unknown""#,
+            r#"{"StartWatering":{"zone":0}}"#,
+            r#"{"StartWatering":{"zone":-1,"secs":30}}"#,
+            r#"{"StartWatering":{"zone":0,"secs":"30"}}"#,
+            r#"{"SetHumidityTrigger":{"zone":0,"value":70000}}"#,
+            r#"{"SetSchedule":{"slot":0,"window":{"hour":6}}}"#,
+            r#"{"SetSchedule":{"slot":0,"window":{"hour":6,"minute":0,"weekdays":1,"mode":"Fixed"}}}"#,
+            r#"{"SetTunables":{"max_on_time_secs":45,"hysteresis":3}}"#,
+            r#"{"SetLogLevel":{"level":"LOUD"}}"#,
+            r#"{"SetMqttTimeout":{"secs":60}}"#,
+            r#""StopWatering"#,
+        ] {
+            assert_eq!(parse(json), None, "{}", json);
+        }
+        // Longer than any POSIX TZ string kept
+        let tz = format!(r#"{{"SetTimezone":{{"tz":"{}"}}}}"#, "A".repeat(TZ_LEN + 1));
+        assert_eq!(parse(&tz), None);
+    }
+
+    #[test]
+    fn validates_schedule_windows() {
+        let window = |hour, minute, weekdays, mode| {
+            format!(
+                r#"{{"SetSchedule":{{"slot":0,"window":{{"hour":{},"minute":{},"weekdays":{},"mode":{}}}}}}}"#,
+                hour, minute, weekdays, mode
+            )
+        };
+        let fixed = |secs| format!(r#"{{"Fixed":{{"duration_secs":{}}}}}"#, secs);
+        let allow = |mins| format!(r#"{{"Allow":{{"length_mins":{}}}}}"#, mins);
+
+        assert_eq!(check(&window(23, 59, 127, fixed(600))), Ok(()));
+        assert_eq!(check(&window(0, 0, 1, allow(1440))), Ok(()));
+        for bad in [
+            window(24, 0, 127, fixed(10)),
+            window(6, 60, 127, fixed(10)),
+            window(6, 0, 0, fixed(10)),
+            window(6, 0, 0x80, fixed(10)),
+            window(6, 0, 127, fixed(0)),
+            window(6, 0, 127, fixed(601)),
+            window(6, 0, 127, allow(0)),
+            window(6, 0, 127, allow(1441)),
+        ] {
+            assert_eq!(check(&bad), Err(Rejection::BadWindow), "{}", bad);
+        }
+        assert_eq!(check(r#"{"SetSchedule":{"slot":0,"window":null}}"#), Ok(()));
+    }
+
+    #[test]
+    fn validates_watering_and_limits() {
+        assert_eq!(check(r#"{"StartWatering":{"zone":1,"secs":1}}"#), Ok(()));
+        assert_eq!(check(r#"{"StartWatering":{"zone":1,"secs":600}}"#), Ok(()));
+        assert_eq!(
+            check(r#"{"StartWatering":{"zone":2,"secs":30}}"#),
+            Err(Rejection::BadZone)
+        );
+        assert_eq!(
+            check(r#"{"StartWatering":{"zone":0,"secs":0}}"#),
+            Err(Rejection::BadDuration)
+        );
+        assert_eq!(
+            check(r#"{"StartWatering":{"zone":0,"secs":601}}"#),
+            Err(Rejection::BadDuration)
+        );
+
+        assert_eq!(
+            check(r#"{"SetHumidityTrigger":{"zone":0,"value":100}}"#),
+            Ok(())
+        );
+        assert_eq!(
+            check(r#"{"SetHumidityTrigger":{"zone":0,"value":101}}"#),
+            Err(Rejection::OutOfRange)
+        );
+        assert_eq!(
+            check(r#"{"SetHumidityTrigger":{"zone":2,"value":10}}"#),
+            Err(Rejection::BadZone)
+        );
+    }
+
+    #[test]
+    fn validates_pause_and_intervals() {
+        assert_eq!(check(r#"{"PauseUntil":{"timestamp":0}}"#), Ok(()));
+        assert_eq!(check(r#"{"PauseUntil":{"timestamp":1767225600}}"#), Ok(()));
+        assert_eq!(
+            check(r#"{"PauseUntil":{"timestamp":-1}}"#),
+            Err(Rejection::OutOfRange)
+        );
+
+        for secs in [5, 3600] {
+            assert_eq!(check(&format!(r#"{{"SetMqttTimeout":{}}}"#, secs)), Ok(()));
+            let json = format!(r#"{{"SetReportingInterval":{{"secs":{}}}}}"#, secs);
+            assert_eq!(check(&json), Ok(()));
+        }
+        for secs in [0, 4, 3601] {
+            assert_eq!(
+                check(&format!(r#"{{"SetMqttTimeout":{}}}"#, secs)),
+                Err(Rejection::OutOfRange)
+            );
+            let json = format!(r#"{{"SetReportingInterval":{{"secs":{}}}}}"#, secs);
+            assert_eq!(check(&json), Err(Rejection::OutOfRange));
+        }
+    }
+
+    #[test]
+    fn validates_socket_timeout() {
+        let timeout = |secs| format!(r#"{{"SetSocketTimeout":{{"secs":{}}}}}"#, secs);
+        for secs in [0, 45, 3600] {
+            assert_eq!(check(&timeout(secs)), Ok(()));
+        }
+        for secs in [1, 44, 3601] {
+            assert_eq!(check(&timeout(secs)), Err(Rejection::OutOfRange));
+        }
+    }
+
+    #[test]
+    fn validates_tunables_and_timezone() {
+        let tunables = |max_on, hysteresis, cooldown| {
+            format!(
+                r#"{{"SetTunables":{{"max_on_time_secs":{},"hysteresis":{},"cooldown_secs":{}}}}}"#,
+                max_on, hysteresis, cooldown
+            )
+        };
+        assert_eq!(check(&tunables(1, 0, 0)), Ok(()));
+        assert_eq!(check(&tunables(600, 50, 3600)), Ok(()));
+        for bad in [
+            tunables(0, 2, 3),
+            tunables(601, 2, 3),
+            tunables(30, 51, 3),
+            tunables(30, 2, 3601),
+        ] {
+            assert_eq!(check(&bad), Err(Rejection::OutOfRange), "{}", bad);
+        }
+
+        assert_eq!(check(r#"{"SetTimezone":{"tz":"JST-9"}}"#), Ok(()));
+        assert_eq!(
+            check(r#"{"SetTimezone":{"tz":"CET-1CEST,M3.5.0,M10.5.0/3"}}"#),
+            Ok(())
+        );
+        assert_eq!(
+            check(r#"{"SetTimezone":{"tz":"Not a zone"}}"#),
+            Err(Rejection::BadTimezone)
+        );
+        assert_eq!(
+            check(r#"{"SetTimezone":{"tz":""}}"#),
+            Err(Rejection::BadTimezone)
+        );
+    }
+}
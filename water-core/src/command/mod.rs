@@ -0,0 +1,6 @@
+pub mod ack;
+pub mod auth;
+pub mod message;
+pub mod settings;
+
+pub use message::Command;
@@ -0,0 +1,83 @@
+//! Persistent settings as one document, read and written over HTTP
+//!
+//! Writing goes through the same [`Command`]s as MQTT, so validation and
+//! storage stay in one place. Missing fields are left unchanged.
+
+use heapless::String;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use super::Command;
+use super::ack::Rejection;
+use crate::time::TZ_LEN;
+use crate::watering::BOARD_ZONES;
+use crate::watering::controller::TunableSettings;
+use crate::watering::schedule::Schedule;
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub low_humidity_limits: Option<[u16; BOARD_ZONES]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tunables: Option<TunableSettings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reporting_interval_secs: Option<u32>,
+    /// `0` while derived from the reporting interval
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket_timeout_secs: Option<u32>,
+    /// POSIX TZ string, absent while the built-in zone is used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String<TZ_LEN>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<LevelFilter>,
+}
+
+impl Settings {
+    /// Commands applying every present field
+    pub fn commands(&self) -> impl Iterator<Item = Command> + '_ {
+        let limits = self.low_humidity_limits.iter().flat_map(|limits| {
+            limits
+                .iter()
+                .enumerate()
+                .map(|(zone, &value)| Command::SetHumidityTrigger {
+                    zone: zone as u8,
+                    value,
+                })
+        });
+        let schedule = self.schedule.iter().flat_map(|schedule| {
+            schedule
+                .windows
+                .iter()
+                .enumerate()
+                .map(|(slot, &window)| Command::SetSchedule {
+                    slot: slot as u8,
+                    window,
+                })
+        });
+
+        limits
+            .chain(schedule)
+            .chain(self.tunables.map(Command::SetTunables))
+            .chain(
+                self.reporting_interval_secs
+                    .map(|secs| Command::SetReportingInterval { secs }),
+            )
+            .chain(
+                self.socket_timeout_secs
+                    .map(|secs| Command::SetSocketTimeout { secs }),
+            )
+            .chain(self.timezone.clone().map(|tz| Command::SetTimezone { tz }))
+            .chain(self.log_level.map(|level| Command::SetLogLevel { level }))
+    }
+
+    /// The first invalid field, nothing is applied if there is one
+    pub fn validate(&self) -> Result<(), (&'static str, Rejection)> {
+        self.commands().try_for_each(|command| {
+            command
+                .validate()
+                .map_err(|reason| (command.name(), reason))
+        })
+    }
+}
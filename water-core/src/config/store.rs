@@ -0,0 +1,619 @@
+//! Log-structured key/value store on top of NOR flash
+//!
+//! The partition is split into erase sectors which are used as a ring. One
+//! sector is active at a time: records are appended to it until it is full,
+//! then the live values are compacted into the next sector. This spreads
+//! erase cycles over the whole partition.
+//!
+//! Sector layout:
+//!
+//! ```text
+//! | magic u32 | version u32 | generation u32 | reserved u32 | records... | 0xff... |
+//! ```
+//!
+//! Record layout, data padded with `0xff` to a 4-byte boundary:
+//!
+//! ```text
+//! | key u16 | len u16 | crc32 u32 | data |
+//! ```
+//!
+//! The sector header is written after the compacted records, so an
+//! interrupted compaction leaves a sector without a valid header which is
+//! ignored on mount. A zero-length record removes the key.
+
+use embedded_storage::nor_flash::NorFlash;
+
+const MAGIC: u32 = 0x4746_4357; // "WCFG"
+pub const FORMAT_VERSION: u32 = 1;
+pub const MAX_VALUE_LEN: usize = 512;
+
+const ALIGN: u32 = 4;
+const HEADER_LEN: u32 = 16;
+const RECORD_HEADER_LEN: u32 = 8;
+const ERASED_KEY: u16 = 0xffff;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Flash(E),
+    /// Partition is not suitable for the store
+    BadPartition,
+    /// Key is reserved
+    BadKey,
+    TooLarge,
+    /// Live values don't fit in a single sector
+    Full,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Record {
+    key: u16,
+    len: u16,
+    crc: u32,
+}
+
+impl Record {
+    fn parse(raw: &[u8; RECORD_HEADER_LEN as usize]) -> Self {
+        Record {
+            key: u16::from_le_bytes([raw[0], raw[1]]),
+            len: u16::from_le_bytes([raw[2], raw[3]]),
+            crc: u32::from_le_bytes([raw[4], raw[5], raw[6], raw[7]]),
+        }
+    }
+
+    fn encode(&self) -> [u8; RECORD_HEADER_LEN as usize] {
+        let mut raw = [0u8; RECORD_HEADER_LEN as usize];
+        raw[0..2].copy_from_slice(&self.key.to_le_bytes());
+        raw[2..4].copy_from_slice(&self.len.to_le_bytes());
+        raw[4..8].copy_from_slice(&self.crc.to_le_bytes());
+        raw
+    }
+
+    fn size(&self) -> u32 {
+        RECORD_HEADER_LEN + padded(self.len as u32)
+    }
+}
+
+const fn padded(len: u32) -> u32 {
+    len.div_ceil(ALIGN) * ALIGN
+}
+
+fn crc32(key: u16, data: &[u8]) -> u32 {
+    let len = data.len() as u16;
+    let mut crc = 0xffff_ffffu32;
+    for byte in key
+        .to_le_bytes()
+        .iter()
+        .chain(len.to_le_bytes().iter())
+        .chain(data)
+    {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+pub struct Store<F: NorFlash> {
+    flash: F,
+    offset: u32,
+    sectors: u32,
+    active: u32,
+    generation: u32,
+    /// Free space start within the active sector
+    write_pos: u32,
+}
+
+impl<F: NorFlash> Store<F> {
+    const SECTOR_SIZE: u32 = F::ERASE_SIZE as u32;
+
+    /// Open the store in `[offset, offset + size)`, formatting it if empty
+    pub fn mount(flash: F, offset: u32, size: u32) -> Result<Self, Error<F::Error>> {
+        if !ALIGN.is_multiple_of(F::WRITE_SIZE as u32)
+            || !ALIGN.is_multiple_of(F::READ_SIZE as u32)
+            || !offset.is_multiple_of(Self::SECTOR_SIZE)
+            || size / Self::SECTOR_SIZE < 2
+        {
+            return Err(Error::BadPartition);
+        }
+
+        let mut store = Store {
+            flash,
+            offset,
+            sectors: size / Self::SECTOR_SIZE,
+            active: 0,
+            generation: 0,
+            write_pos: HEADER_LEN,
+        };
+
+        let mut found = None;
+        for sector in 0..store.sectors {
+            if let Some(generation) = store.read_header(sector)?
+                && found.is_none_or(|(_, best)| generation > best)
+            {
+                found = Some((sector, generation));
+            }
+        }
+
+        match found {
+            Some((sector, generation)) => {
+                store.active = sector;
+                store.generation = generation;
+                store.write_pos = store.find_end()?;
+            }
+            None => store.format()?,
+        }
+
+        Ok(store)
+    }
+
+    /// Drop all values
+    pub fn format(&mut self) -> Result<(), Error<F::Error>> {
+        self.erase(0)?;
+        self.write_header(0, self.generation.wrapping_add(1))?;
+        self.active = 0;
+        self.generation = self.generation.wrapping_add(1);
+        self.write_pos = HEADER_LEN;
+        Ok(())
+    }
+
+    /// Copy the value of `key` into `buf` and return its length
+    pub fn read(&mut self, key: u16, buf: &mut [u8]) -> Result<Option<usize>, Error<F::Error>> {
+        let Some((pos, record)) = self.find_last(key)? else {
+            return Ok(None);
+        };
+        if record.len == 0 {
+            return Ok(None);
+        }
+        if record.len as usize > buf.len() {
+            return Err(Error::TooLarge);
+        }
+
+        let mut data = [0u8; MAX_VALUE_LEN];
+        let data = self.read_data(pos, record, &mut data)?;
+        buf[..data.len()].copy_from_slice(data);
+        Ok(Some(data.len()))
+    }
+
+    pub fn write(&mut self, key: u16, value: &[u8]) -> Result<(), Error<F::Error>> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::TooLarge);
+        }
+        if key == ERASED_KEY {
+            return Err(Error::BadKey);
+        }
+
+        // Rewriting the same value only wears the flash out
+        let mut current = [0u8; MAX_VALUE_LEN];
+        if let Some((pos, record)) = self.find_last(key)?
+            && record.len as usize == value.len()
+            && self.read_data(pos, record, &mut current)? == value
+        {
+            return Ok(());
+        }
+
+        let record = Record {
+            key,
+            len: value.len() as u16,
+            crc: crc32(key, value),
+        };
+
+        if self.write_pos + record.size() > Self::SECTOR_SIZE {
+            self.compact()?;
+            if self.write_pos + record.size() > Self::SECTOR_SIZE {
+                return Err(Error::Full);
+            }
+        }
+
+        let pos = self.write_pos;
+        self.write_record(self.active, pos, record, value)?;
+        self.write_pos += record.size();
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: u16) -> Result<(), Error<F::Error>> {
+        match self.find_last(key)? {
+            Some((_, record)) if record.len > 0 => self.write(key, &[]),
+            _ => Ok(()),
+        }
+    }
+
+    fn sector_addr(&self, sector: u32) -> u32 {
+        self.offset + sector * Self::SECTOR_SIZE
+    }
+
+    fn erase(&mut self, sector: u32) -> Result<(), Error<F::Error>> {
+        let from = self.sector_addr(sector);
+        self.flash
+            .erase(from, from + Self::SECTOR_SIZE)
+            .map_err(Error::Flash)
+    }
+
+    fn read_header(&mut self, sector: u32) -> Result<Option<u32>, Error<F::Error>> {
+        let mut raw = [0u8; HEADER_LEN as usize];
+        self.flash
+            .read(self.sector_addr(sector), &mut raw)
+            .map_err(Error::Flash)?;
+
+        let word =
+            |idx: usize| u32::from_le_bytes([raw[idx], raw[idx + 1], raw[idx + 2], raw[idx + 3]]);
+        if word(0) == MAGIC && word(4) == FORMAT_VERSION {
+            Ok(Some(word(8)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn write_header(&mut self, sector: u32, generation: u32) -> Result<(), Error<F::Error>> {
+        let mut raw = [0xffu8; HEADER_LEN as usize];
+        raw[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        raw[4..8].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        raw[8..12].copy_from_slice(&generation.to_le_bytes());
+        self.flash
+            .write(self.sector_addr(sector), &raw)
+            .map_err(Error::Flash)
+    }
+
+    /// Record header at `pos` of the active sector, `None` past the last record
+    fn record_at(&mut self, pos: u32) -> Result<Option<Record>, Error<F::Error>> {
+        if pos + RECORD_HEADER_LEN > Self::SECTOR_SIZE {
+            return Ok(None);
+        }
+
+        let mut raw = [0u8; RECORD_HEADER_LEN as usize];
+        self.flash
+            .read(self.sector_addr(self.active) + pos, &mut raw)
+            .map_err(Error::Flash)?;
+        let record = Record::parse(&raw);
+
+        // A torn length can't be trusted to find the next record
+        if record.key == ERASED_KEY
+            || record.len as usize > MAX_VALUE_LEN
+            || pos + record.size() > Self::SECTOR_SIZE
+        {
+            Ok(None)
+        } else {
+            Ok(Some(record))
+        }
+    }
+
+    fn find_end(&mut self) -> Result<u32, Error<F::Error>> {
+        let mut pos = HEADER_LEN;
+        while let Some(record) = self.record_at(pos)? {
+            pos += record.size();
+        }
+
+        // Anything after the end marker is garbage from an interrupted write,
+        // so treat the sector as full to get it compacted
+        let mut raw = [0u8; RECORD_HEADER_LEN as usize];
+        if pos + RECORD_HEADER_LEN <= Self::SECTOR_SIZE {
+            self.flash
+                .read(self.sector_addr(self.active) + pos, &mut raw)
+                .map_err(Error::Flash)?;
+            if raw.iter().any(|b| *b != 0xff) {
+                return Ok(Self::SECTOR_SIZE);
+            }
+        }
+        Ok(pos)
+    }
+
+    fn read_data<'b>(
+        &mut self,
+        pos: u32,
+        record: Record,
+        buf: &'b mut [u8; MAX_VALUE_LEN],
+    ) -> Result<&'b [u8], Error<F::Error>> {
+        let addr = self.sector_addr(self.active) + pos + RECORD_HEADER_LEN;
+        let padded_len = padded(record.len as u32) as usize;
+        self.flash
+            .read(addr, &mut buf[..padded_len])
+            .map_err(Error::Flash)?;
+        Ok(&buf[..record.len as usize])
+    }
+
+    fn is_valid(&mut self, pos: u32, record: Record) -> Result<bool, Error<F::Error>> {
+        let mut data = [0u8; MAX_VALUE_LEN];
+        let data = self.read_data(pos, record, &mut data)?;
+        Ok(crc32(record.key, data) == record.crc)
+    }
+
+    /// Latest intact record for `key`
+    fn find_last(&mut self, key: u16) -> Result<Option<(u32, Record)>, Error<F::Error>> {
+        let mut found = None;
+        let mut pos = HEADER_LEN;
+        while let Some(record) = self.record_at(pos)? {
+            if record.key == key && self.is_valid(pos, record)? {
+                found = Some((pos, record));
+            }
+            pos += record.size();
+        }
+        Ok(found)
+    }
+
+    fn write_record(
+        &mut self,
+        sector: u32,
+        pos: u32,
+        record: Record,
+        value: &[u8],
+    ) -> Result<(), Error<F::Error>> {
+        let addr = self.sector_addr(sector) + pos;
+        let mut data = [0xffu8; MAX_VALUE_LEN];
+        data[..value.len()].copy_from_slice(value);
+
+        self.flash
+            .write(addr, &record.encode())
+            .map_err(Error::Flash)?;
+        self.flash
+            .write(
+                addr + RECORD_HEADER_LEN,
+                &data[..padded(record.len as u32) as usize],
+            )
+            .map_err(Error::Flash)
+    }
+
+    /// Move the live values into the next sector
+    fn compact(&mut self) -> Result<(), Error<F::Error>> {
+        let target = (self.active + 1) % self.sectors;
+        self.erase(target)?;
+
+        let mut target_pos = HEADER_LEN;
+        let mut pos = HEADER_LEN;
+        let mut data = [0u8; MAX_VALUE_LEN];
+        while let Some(record) = self.record_at(pos)? {
+            let is_latest = self
+                .find_last(record.key)?
+                .is_some_and(|(last, _)| last == pos);
+
+            if is_latest && record.len > 0 {
+                let len = self.read_data(pos, record, &mut data)?.len();
+                self.write_record(target, target_pos, record, &data[..len])?;
+                target_pos += record.size();
+            }
+            pos += record.size();
+        }
+
+        let generation = self.generation.wrapping_add(1);
+        self.write_header(target, generation)?;
+        self.active = target;
+        self.generation = generation;
+        self.write_pos = target_pos;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    const SECTOR: usize = 1024;
+    const SECTORS: usize = 3;
+    const SIZE: u32 = (SECTOR * SECTORS) as u32;
+
+    /// NOR flash in RAM: erasing sets bits, writing can only clear them
+    struct RamFlash {
+        data: [u8; SECTOR * SECTORS],
+        erases: [u32; SECTORS],
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            RamFlash {
+                data: [0xff; SECTOR * SECTORS],
+                erases: [0; SECTORS],
+            }
+        }
+
+        fn check(&self, offset: u32, len: usize, align: usize) -> Result<(), NorFlashErrorKind> {
+            if !(offset as usize).is_multiple_of(align) || !len.is_multiple_of(align) {
+                return Err(NorFlashErrorKind::NotAligned);
+            }
+            if offset as usize + len > self.data.len() {
+                return Err(NorFlashErrorKind::OutOfBounds);
+            }
+            Ok(())
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 4;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            self.check(offset, bytes.len(), Self::READ_SIZE)?;
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            self.check(from, (to - from) as usize, Self::ERASE_SIZE)?;
+            self.data[from as usize..to as usize].fill(0xff);
+            for sector in from as usize / SECTOR..to as usize / SECTOR {
+                self.erases[sector] += 1;
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            self.check(offset, bytes.len(), Self::WRITE_SIZE)?;
+            let offset = offset as usize;
+            for (cell, byte) in self.data[offset..].iter_mut().zip(bytes) {
+                *cell &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    fn read(store: &mut Store<RamFlash>, key: u16) -> Option<std::vec::Vec<u8>> {
+        let mut buf = [0u8; MAX_VALUE_LEN];
+        let len = store.read(key, &mut buf).unwrap()?;
+        Some(buf[..len].to_vec())
+    }
+
+    fn remount(store: Store<RamFlash>) -> Store<RamFlash> {
+        Store::mount(store.flash, 0, SIZE).unwrap()
+    }
+
+    #[test]
+    fn writes_and_reads_back() {
+        let mut store = Store::mount(RamFlash::new(), 0, SIZE).unwrap();
+        assert_eq!(read(&mut store, 1), None);
+        store.write(1, b"hello").unwrap();
+        store.write(2, &[]).unwrap();
+        store.write(3, &[7; MAX_VALUE_LEN]).unwrap();
+        assert_eq!(read(&mut store, 1).unwrap(), b"hello");
+        // Empty values read as missing
+        assert_eq!(read(&mut store, 2), None);
+
+        let mut store = remount(store);
+        assert_eq!(read(&mut store, 1).unwrap(), b"hello");
+        assert_eq!(read(&mut store, 3).unwrap(), [7; MAX_VALUE_LEN]);
+
+        let mut short = [0u8; 4];
+        assert_eq!(store.read(1, &mut short), Err(Error::TooLarge));
+        assert_eq!(
+            store.write(4, &[0; MAX_VALUE_LEN + 1]),
+            Err(Error::TooLarge)
+        );
+        assert_eq!(store.write(ERASED_KEY, b"x"), Err(Error::BadKey));
+    }
+
+    #[test]
+    fn overwrites_and_removes() {
+        let mut store = Store::mount(RamFlash::new(), 0, SIZE).unwrap();
+        store.write(1, b"first").unwrap();
+        store.write(1, b"second value").unwrap();
+        assert_eq!(read(&mut store, 1).unwrap(), b"second value");
+
+        // The same value again takes no space
+        let end = store.write_pos;
+        store.write(1, b"second value").unwrap();
+        assert_eq!(store.write_pos, end);
+
+        store.remove(1).unwrap();
+        assert_eq!(read(&mut store, 1), None);
+        let end = store.write_pos;
+        store.remove(1).unwrap();
+        assert_eq!(store.write_pos, end);
+
+        let mut store = remount(store);
+        assert_eq!(read(&mut store, 1), None);
+    }
+
+    #[test]
+    fn compacts_into_the_next_sector() {
+        let mut store = Store::mount(RamFlash::new(), 0, SIZE).unwrap();
+        store.write(1, b"kept").unwrap();
+        store.write(2, b"removed").unwrap();
+        store.remove(2).unwrap();
+        let generation = store.generation;
+
+        let mut counter = 0u32;
+        while store.active == 0 {
+            counter += 1;
+            store.write(3, &counter.to_le_bytes()).unwrap();
+        }
+        assert_eq!(store.generation, generation + 1);
+        // Only the live values moved, then the new one was appended
+        assert_eq!(store.write_pos, HEADER_LEN + 3 * (RECORD_HEADER_LEN + 4));
+        assert_eq!(read(&mut store, 1).unwrap(), b"kept");
+        assert_eq!(read(&mut store, 2), None);
+        assert_eq!(read(&mut store, 3).unwrap(), counter.to_le_bytes());
+
+        // The newer sector wins over the stale one
+        let mut store = remount(store);
+        assert_eq!(store.active, 1);
+        assert_eq!(read(&mut store, 3).unwrap(), counter.to_le_bytes());
+
+        // Around the ring and back to the first sector
+        while store.active != 0 {
+            counter += 1;
+            store.write(3, &counter.to_le_bytes()).unwrap();
+        }
+        assert_eq!(read(&mut store, 1).unwrap(), b"kept");
+        assert_eq!(store.flash.erases, [2, 1, 1]);
+    }
+
+    #[test]
+    fn refuses_what_never_fits() {
+        let mut store = Store::mount(RamFlash::new(), 0, SIZE).unwrap();
+        store.write(1, &[1; MAX_VALUE_LEN]).unwrap();
+        assert_eq!(store.write(2, &[2; MAX_VALUE_LEN]), Err(Error::Full));
+        assert_eq!(read(&mut store, 1).unwrap(), [1; MAX_VALUE_LEN]);
+        assert!(matches!(
+            Store::mount(RamFlash::new(), 0, SECTOR as u32),
+            Err(Error::BadPartition)
+        ));
+        assert!(matches!(
+            Store::mount(RamFlash::new(), 4, SIZE),
+            Err(Error::BadPartition)
+        ));
+    }
+
+    #[test]
+    fn ignores_a_sector_without_header() {
+        let mut store = Store::mount(RamFlash::new(), 0, SIZE).unwrap();
+        store.write(1, b"old").unwrap();
+        let before = store.flash.data;
+
+        // Compaction copied the records but lost power before the header
+        let mut counter = 0u32;
+        while store.active == 0 {
+            counter += 1;
+            store.write(1, &counter.to_le_bytes()).unwrap();
+        }
+        let mut flash = store.flash;
+        flash.data[..SECTOR].copy_from_slice(&before[..SECTOR]);
+        flash.data[SECTOR..SECTOR + HEADER_LEN as usize].fill(0xff);
+
+        let mut store = Store::mount(flash, 0, SIZE).unwrap();
+        assert_eq!(store.active, 0);
+        assert_eq!(read(&mut store, 1).unwrap(), b"old");
+    }
+
+    #[test]
+    fn skips_records_with_a_bad_crc() {
+        let mut store = Store::mount(RamFlash::new(), 0, SIZE).unwrap();
+        store.write(1, b"good").unwrap();
+        let torn = store.write_pos as usize;
+        store.write(1, b"torn").unwrap();
+        store.write(2, b"after").unwrap();
+
+        // A bit which never got cleared in the data of the second record
+        store.flash.data[torn + RECORD_HEADER_LEN as usize] |= 0x80;
+        let mut store = remount(store);
+        assert_eq!(read(&mut store, 1).unwrap(), b"good");
+        assert_eq!(read(&mut store, 2).unwrap(), b"after");
+    }
+
+    #[test]
+    fn compacts_after_garbage_past_the_end() {
+        let mut store = Store::mount(RamFlash::new(), 0, SIZE).unwrap();
+        store.write(1, b"value").unwrap();
+        // Header of a record lost halfway: the key never made it
+        let end = store.write_pos as usize;
+        store.flash.data[end + 2..end + 4].copy_from_slice(&[4, 0]);
+
+        let mut store = remount(store);
+        assert_eq!(store.write_pos, SECTOR as u32);
+        store.write(2, b"next").unwrap();
+        assert_eq!(store.active, 1);
+        assert_eq!(read(&mut store, 1).unwrap(), b"value");
+        assert_eq!(read(&mut store, 2).unwrap(), b"next");
+    }
+}
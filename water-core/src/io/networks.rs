@@ -0,0 +1,267 @@
+//! Choosing among the known WiFi networks
+//!
+//! Networks are ranked by priority, access points of the same priority by
+//! signal strength. A refused password rules out every access point of that
+//! network for the rest of the round.
+
+use core::fmt::{self, Write};
+use heapless::{String, Vec};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize, Serializer};
+
+pub const SSID_LEN: usize = 32;
+pub const PASSWORD_LEN: usize = 64;
+/// Networks stored besides the provisioned one
+pub const MAX_NETWORKS: usize = 3;
+pub const MAX_CANDIDATES: usize = 8;
+/// Strongest access points kept from a scan
+pub const SCAN_LEN: usize = 6;
+/// A weaker link is re-evaluated
+pub const ROAM_RSSI_THRESHOLD: i8 = -75;
+/// How much stronger another access point must be to switch to it
+pub const ROAM_HYSTERESIS: i8 = 8;
+/// Disconnects kept for telemetry
+pub const MAX_DISCONNECTS: usize = 5;
+pub const SIGNAL_BARS: u8 = 4;
+// RSSI needed for the second, third and fourth bar
+const BAR_THRESHOLDS: [i8; 3] = [-75, -65, -55];
+
+// `wifi_err_reason_t` values meaning the credentials were refused
+const REASON_AUTH_EXPIRE: u8 = 2;
+const REASON_4WAY_HANDSHAKE_TIMEOUT: u8 = 15;
+const REASON_802_1X_AUTH_FAILED: u8 = 23;
+const REASON_AUTH_FAIL: u8 = 202;
+const REASON_HANDSHAKE_TIMEOUT: u8 = 204;
+
+pub fn is_auth_failure(reason: u8) -> bool {
+    matches!(
+        reason,
+        REASON_AUTH_EXPIRE
+            | REASON_4WAY_HANDSHAKE_TIMEOUT
+            | REASON_802_1X_AUTH_FAILED
+            | REASON_AUTH_FAIL
+            | REASON_HANDSHAKE_TIMEOUT
+    )
+}
+
+/// Access point MAC, shown as `aa:bb:cc:dd:ee:ff`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Bssid(pub [u8; 6]);
+
+impl fmt::Display for Bssid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_char(':')?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Bssid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut text: String<17> = String::new();
+        write!(text, "{}", self).ok();
+        serializer.serialize_str(&text)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnownNetwork {
+    pub ssid: String<SSID_LEN>,
+    pub password: String<PASSWORD_LEN>,
+    /// Higher is preferred, the provisioned network has 0
+    pub priority: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccessPoint {
+    pub ssid: String<SSID_LEN>,
+    pub bssid: Bssid,
+    pub channel: u8,
+    pub rssi: i8,
+}
+
+/// An access point of a known network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    /// Index into the known networks
+    pub network: usize,
+    pub ap: AccessPoint,
+}
+
+/// Known access points in range, best first
+pub fn rank(
+    known: &[KnownNetwork],
+    scan: impl IntoIterator<Item = AccessPoint>,
+) -> Vec<Candidate, MAX_CANDIDATES> {
+    let rank_of = |candidate: &Candidate| (known[candidate.network].priority, candidate.ap.rssi);
+    let mut candidates: Vec<Candidate, MAX_CANDIDATES> = Vec::new();
+    for ap in scan {
+        let Some(network) = known.iter().position(|network| network.ssid == ap.ssid) else {
+            continue;
+        };
+        let candidate = Candidate { network, ap };
+        let at = candidates.partition_point(|other| rank_of(other) >= rank_of(&candidate));
+        // Crowded places have more access points than we need, the worst
+        // one makes room for a better one
+        if candidates.is_full() {
+            if at == candidates.len() {
+                continue;
+            }
+            candidates.pop();
+        }
+        candidates.insert(at, candidate).ok();
+    }
+    candidates
+}
+
+/// Bars out of `SIGNAL_BARS` shown for a link, at least one
+pub fn signal_bars(rssi: i8) -> u8 {
+    1 + BAR_THRESHOLDS
+        .iter()
+        .filter(|threshold| rssi >= **threshold)
+        .count() as u8
+}
+
+/// Whether a weak link should move to `best`
+pub fn should_roam(current: &AccessPoint, best: &AccessPoint) -> bool {
+    current.rssi < ROAM_RSSI_THRESHOLD
+        && best.bssid != current.bssid
+        && best.rssi >= current.rssi.saturating_add(ROAM_HYSTERESIS)
+}
+
+/// A lost link or a failed attempt to join
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Disconnect {
+    pub uptime_secs: u64,
+    /// Missing until the clock is set
+    pub timestamp: Option<Timestamp>,
+    /// `wifi_err_reason_t`, e.g. 200 for a lost beacon
+    pub reason: u8,
+    pub rssi: i8,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WifiStatus {
+    /// Access point in use, its RSSI is polled while connected
+    pub selected: Option<AccessPoint>,
+    /// Seconds since the link came up
+    pub connected_secs: Option<u64>,
+    /// Successful connections after the first one since boot
+    pub reconnects: u32,
+    /// Latest last
+    pub disconnects: Vec<Disconnect, MAX_DISCONNECTS>,
+    /// Strongest access points of the last scan
+    pub scan: Vec<AccessPoint, SCAN_LEN>,
+}
+
+impl WifiStatus {
+    pub const fn new() -> Self {
+        WifiStatus {
+            selected: None,
+            connected_secs: None,
+            reconnects: 0,
+            disconnects: Vec::new(),
+            scan: Vec::new(),
+        }
+    }
+
+    /// Keeps the latest `MAX_DISCONNECTS`
+    pub fn record(&mut self, disconnect: Disconnect) {
+        if self.disconnects.is_full() {
+            self.disconnects.remove(0);
+        }
+        self.disconnects.push(disconnect).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(ssid: &str, priority: u8) -> KnownNetwork {
+        KnownNetwork {
+            ssid: ssid.try_into().unwrap(),
+            password: "secret123".try_into().unwrap(),
+            priority,
+        }
+    }
+
+    fn ap(ssid: &str, last: u8, rssi: i8) -> AccessPoint {
+        AccessPoint {
+            ssid: ssid.try_into().unwrap(),
+            bssid: Bssid([2, 0, 0, 0, 0, last]),
+            channel: 6,
+            rssi,
+        }
+    }
+
+    #[test]
+    fn ranks_by_priority_then_signal() {
+        let known = [network("home", 0), network("garden", 1)];
+        let scan = [
+            ap("home", 1, -50),
+            ap("cafe", 2, -30),
+            ap("garden", 3, -80),
+            ap("home", 4, -40),
+            ap("garden", 5, -70),
+        ];
+        let ranked = rank(&known, scan);
+        let order: std::vec::Vec<u8> = ranked.iter().map(|c| c.ap.bssid.0[5]).collect();
+        assert_eq!(order, [5, 3, 4, 1]);
+        assert_eq!(ranked[0].network, 1);
+        assert!(rank(&known, [ap("cafe", 2, -30)]).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_best_of_a_crowded_scan() {
+        let known = [network("office", 0), network("home", 1)];
+        let mut scan: std::vec::Vec<_> = (0..12).map(|i| ap("office", i, -40 - i as i8)).collect();
+        scan.push(ap("home", 100, -85));
+        scan.push(ap("office", 101, -30));
+        let ranked = rank(&known, scan);
+        let order: std::vec::Vec<u8> = ranked.iter().map(|c| c.ap.bssid.0[5]).collect();
+        assert_eq!(order, [100, 101, 0, 1, 2, 3, 4, 5]);
+        assert_eq!(ranked.len(), MAX_CANDIDATES);
+    }
+
+    #[test]
+    fn roams_only_from_weak_links() {
+        let current = ap("home", 1, -80);
+        assert!(should_roam(&current, &ap("home", 2, -65)));
+        assert!(!should_roam(&current, &ap("home", 2, -75)));
+        assert!(!should_roam(&current, &ap("home", 1, -40)));
+        assert!(!should_roam(&ap("home", 1, -60), &ap("home", 2, -30)));
+    }
+
+    #[test]
+    fn keeps_latest_disconnects() {
+        let mut status = WifiStatus::new();
+        for uptime_secs in 0..8 {
+            status.record(Disconnect {
+                uptime_secs,
+                timestamp: None,
+                reason: 200,
+                rssi: -90,
+            });
+        }
+        let kept: std::vec::Vec<u64> = status.disconnects.iter().map(|d| d.uptime_secs).collect();
+        assert_eq!(kept, [3, 4, 5, 6, 7]);
+        assert_eq!(signal_bars(-90), 1);
+        assert_eq!(signal_bars(-65), 3);
+        assert_eq!(signal_bars(-30), SIGNAL_BARS);
+    }
+
+    #[test]
+    fn bssid_text() {
+        let bssid = Bssid([0xa0, 0xb1, 0xc2, 0xd3, 0xe4, 0x05]);
+        let mut out = [0u8; 32];
+        let len = serde_json_core::to_slice(&bssid, &mut out).unwrap();
+        assert_eq!(&out[..len], b"\"a0:b1:c2:d3:e4:05\"");
+        assert!(is_auth_failure(202));
+        assert!(!is_auth_failure(201));
+    }
+}
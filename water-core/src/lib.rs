@@ -0,0 +1,13 @@
+//! Hardware independent parts of the watering firmware
+//!
+//! Parsers, protocol messages and the watering logic live here so they can
+//! be tested on the host. The firmware re-exports the modules under the
+//! same paths.
+#![cfg_attr(not(test), no_std)]
+pub mod command;
+pub mod config;
+pub mod io;
+pub mod net;
+pub mod provision;
+pub mod time;
+pub mod watering;
@@ -0,0 +1,110 @@
+//! Routing of the REST API
+//!
+//! - `GET /status`: the telemetry [`crate::command::status::Status`]
+//! - `POST /command`: a [`crate::command::Command`], answered with its ack
+//! - `GET /config`, `PUT /config`: [`crate::command::settings::Settings`]
+//! - `GET /metrics`: [`crate::net::metrics::Metrics`] for Prometheus
+//!
+//! With a command key set, command and settings bodies are signed just like
+//! MQTT control messages.
+
+use crate::command::ack::{Ack, Rejection};
+use crate::net::http::{Method, Status};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    GetStatus,
+    PostCommand,
+    GetConfig,
+    PutConfig,
+    GetMetrics,
+}
+
+pub fn route(method: Method, path: &str) -> Result<Endpoint, Status> {
+    match (method, path) {
+        (Method::Get, "/status") => Ok(Endpoint::GetStatus),
+        (Method::Post, "/command") => Ok(Endpoint::PostCommand),
+        (Method::Get, "/config") => Ok(Endpoint::GetConfig),
+        (Method::Put, "/config") => Ok(Endpoint::PutConfig),
+        (Method::Get, "/metrics") => Ok(Endpoint::GetMetrics),
+        (_, "/status" | "/command" | "/config" | "/metrics") => Err(Status::METHOD_NOT_ALLOWED),
+        _ => Err(Status::NOT_FOUND),
+    }
+}
+
+/// Response status for the acknowledgement of a command or settings update
+pub fn ack_status(ack: &Ack) -> Status {
+    match ack.reason {
+        None => Status::OK,
+        Some(Rejection::Malformed) => Status::BAD_REQUEST,
+        Some(Rejection::Unsigned | Rejection::BadSignature | Rejection::Replay) => {
+            Status::FORBIDDEN
+        }
+        Some(_) => Status::UNPROCESSABLE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Command;
+    use crate::command::settings::Settings;
+    use crate::net::http::parse_request;
+
+    #[test]
+    fn routes() {
+        assert_eq!(route(Method::Get, "/status"), Ok(Endpoint::GetStatus));
+        assert_eq!(route(Method::Put, "/config"), Ok(Endpoint::PutConfig));
+        assert_eq!(route(Method::Get, "/metrics"), Ok(Endpoint::GetMetrics));
+        assert_eq!(
+            route(Method::Post, "/status"),
+            Err(Status::METHOD_NOT_ALLOWED)
+        );
+        assert_eq!(route(Method::Get, "/"), Err(Status::NOT_FOUND));
+    }
+
+    #[test]
+    fn command_request() {
+        let raw =
+            b"POST /command HTTP/1.1\r\nHost: water\r\nContent-Length: 14\r\n\r\n\"StopWatering\"";
+        let request = parse_request(raw, 256).unwrap();
+        assert_eq!(
+            route(request.method, request.path),
+            Ok(Endpoint::PostCommand)
+        );
+        let (command, _) = serde_json_core::from_slice::<Command>(request.body).unwrap();
+        assert_eq!(command, Command::StopWatering);
+    }
+
+    #[test]
+    fn partial_settings() {
+        let body = br#"{"reporting_interval_secs":60,"low_humidity_limits":[20,30]}"#;
+        let (settings, _) = serde_json_core::from_slice::<Settings>(body).unwrap();
+        assert!(settings.validate().is_ok());
+        let commands = settings.commands().collect::<std::vec::Vec<_>>();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[2], Command::SetReportingInterval { secs: 60 });
+
+        let body = br#"{"socket_timeout_secs":0,"reporting_interval_secs":60}"#;
+        let (settings, _) = serde_json_core::from_slice::<Settings>(body).unwrap();
+        let commands = settings.commands().collect::<std::vec::Vec<_>>();
+        assert_eq!(commands[1], Command::SetSocketTimeout { secs: 0 });
+
+        let body = br#"{"reporting_interval_secs":1}"#;
+        let (settings, _) = serde_json_core::from_slice::<Settings>(body).unwrap();
+        assert_eq!(
+            settings.validate(),
+            Err(("SetReportingInterval", Rejection::OutOfRange))
+        );
+    }
+
+    #[test]
+    fn statuses() {
+        assert_eq!(ack_status(&Ack::new("Reboot", Ok(None))), Status::OK);
+        assert_eq!(ack_status(&Ack::malformed()), Status::BAD_REQUEST);
+        assert_eq!(
+            ack_status(&Ack::new("", Err(Rejection::Replay))),
+            Status::FORBIDDEN
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! MQTT connection state as reported on the display and in the status
+
+use serde::Serialize;
+
+/// Step the last session failed at
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum Failure {
+    Resolve,
+    Socket,
+    Tls,
+    /// The broker refused the connection or didn't answer it
+    Broker,
+    Subscribe,
+    Publish,
+    Ping,
+    Receive,
+}
+
+impl Failure {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Failure::Resolve => "DNS",
+            Failure::Socket => "TCP",
+            Failure::Tls => "TLS",
+            Failure::Broker => "BRK",
+            Failure::Subscribe => "SUB",
+            Failure::Publish => "PUB",
+            Failure::Ping => "PNG",
+            Failure::Receive => "RCV",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum ConnectionState {
+    /// Waiting for the network
+    Idle,
+    Resolving,
+    /// TCP, TLS and the MQTT handshake
+    Connecting,
+    /// Announcing availability and subscribing
+    Connected,
+    /// Serving the session
+    Subscribed,
+    BackingOff {
+        reason: Failure,
+        retry_in_secs: u64,
+    },
+}
+
+impl ConnectionState {
+    /// Three characters fit next to the MQTT icon, steps in progress are lower
+    /// case and failures upper case
+    pub fn label(&self) -> &'static str {
+        match self {
+            ConnectionState::Idle => "...",
+            ConnectionState::Resolving => "dns",
+            ConnectionState::Connecting => "con",
+            ConnectionState::Connected => "sub",
+            ConnectionState::Subscribed => "OK",
+            ConnectionState::BackingOff { reason, .. } => reason.label(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    /// Seconds spent in the current state
+    pub state_secs: u64,
+    /// Sessions started since boot
+    pub attempts: u32,
+    pub failures: u32,
+    pub last_failure: Option<Failure>,
+}
@@ -0,0 +1,380 @@
+//! Tiny DHCPv4 server for the setup access point
+//!
+//! Hands out addresses from a small pool on the server's /24, keyed by the
+//! client hardware address. Only DISCOVER and REQUEST are answered.
+//!
+//! On the station side a configured client asks its server for the options
+//! the network stack leaves out with INFORM.
+
+use core::net::Ipv4Addr;
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_START: usize = 240;
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_NTP_SERVERS: u8 = 42;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETER_LIST: u8 = 55;
+const OPT_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+const INFORM: u8 = 8;
+
+const LEASE_SECS: u32 = 3600;
+
+/// Smallest buffer able to hold any reply
+pub const MAX_REPLY_LEN: usize = OPTIONS_START + 40;
+/// Some servers ignore anything shorter than a BOOTP message
+const INFORM_LEN: usize = 300;
+const IPV4_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const IPV4_PROTOCOL_UDP: u8 = 17;
+/// INFORM with its IPv4 and UDP headers
+pub const INFORM_PACKET_LEN: usize = IPV4_HEADER_LEN + UDP_HEADER_LEN + INFORM_LEN;
+/// Clients have to take replies up to this size (RFC 2131)
+pub const MAX_PACKET_LEN: usize = 576;
+
+struct Message<'a> {
+    xid: [u8; 4],
+    flags: [u8; 2],
+    chaddr: [u8; 16],
+    ciaddr: Ipv4Addr,
+    options: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    fn parse(packet: &'a [u8], op: u8) -> Option<Self> {
+        if packet.len() < OPTIONS_START || packet[0] != op || packet[236..240] != MAGIC_COOKIE {
+            return None;
+        }
+
+        let mut chaddr = [0u8; 16];
+        chaddr.copy_from_slice(&packet[28..44]);
+        Some(Message {
+            xid: packet[4..8].try_into().ok()?,
+            flags: packet[10..12].try_into().ok()?,
+            chaddr,
+            ciaddr: Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]),
+            options: &packet[OPTIONS_START..],
+        })
+    }
+
+    fn option(&self, code: u8) -> Option<&'a [u8]> {
+        let mut rest = self.options;
+        loop {
+            match rest {
+                [OPT_END, ..] | [] => return None,
+                [OPT_PAD, tail @ ..] => rest = tail,
+                [opt, len, tail @ ..] => {
+                    let value = tail.get(..*len as usize)?;
+                    if *opt == code {
+                        return Some(value);
+                    }
+                    rest = &tail[*len as usize..];
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn message_type(&self) -> Option<u8> {
+        self.option(OPT_MESSAGE_TYPE)?.first().copied()
+    }
+
+    fn requested_ip(&self) -> Option<Ipv4Addr> {
+        let ip: [u8; 4] = self.option(OPT_REQUESTED_IP)?.try_into().ok()?;
+        Some(Ipv4Addr::from(ip))
+    }
+
+    fn mac(&self) -> [u8; 6] {
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&self.chaddr[..6]);
+        mac
+    }
+}
+
+pub struct DhcpServer<const N: usize> {
+    server: Ipv4Addr,
+    pool_start: u8,
+    leases: [Option<[u8; 6]>; N],
+}
+
+impl<const N: usize> DhcpServer<N> {
+    /// Pool is `N` addresses starting at `pool_start` within the server's /24
+    pub const fn new(server: Ipv4Addr, pool_start: u8) -> Self {
+        DhcpServer {
+            server,
+            pool_start,
+            leases: [None; N],
+        }
+    }
+
+    fn address(&self, slot: usize) -> Ipv4Addr {
+        let [a, b, c, _] = self.server.octets();
+        Ipv4Addr::new(a, b, c, self.pool_start.wrapping_add(slot as u8))
+    }
+
+    fn lease_for(&mut self, mac: [u8; 6]) -> Ipv4Addr {
+        let slot = match self.leases.iter().position(|lease| *lease == Some(mac)) {
+            Some(slot) => slot,
+            None => {
+                // The pool is tiny, the first lease gets recycled when full
+                let slot = self
+                    .leases
+                    .iter()
+                    .position(Option::is_none)
+                    .unwrap_or_default();
+                self.leases[slot] = Some(mac);
+                slot
+            }
+        };
+        self.address(slot)
+    }
+
+    /// Build the reply for a client packet into `out`, returning its length
+    pub fn handle(&mut self, packet: &[u8], out: &mut [u8]) -> Option<usize> {
+        let request = Message::parse(packet, BOOTREQUEST)?;
+        let mac = request.mac();
+
+        let (reply_type, yiaddr) = match request.message_type()? {
+            DISCOVER => (OFFER, self.lease_for(mac)),
+            REQUEST => {
+                // Requests aimed at another server are none of our business
+                if let Some(server_id) = request.option(OPT_SERVER_ID)
+                    && server_id != self.server.octets()
+                {
+                    return None;
+                }
+                let leased = self.lease_for(mac);
+                let wanted = request.requested_ip().unwrap_or(request.ciaddr);
+                if wanted == leased {
+                    (ACK, leased)
+                } else {
+                    (NAK, Ipv4Addr::UNSPECIFIED)
+                }
+            }
+            _ => return None,
+        };
+
+        self.encode(&request, reply_type, yiaddr, out)
+    }
+
+    fn encode(
+        &self,
+        request: &Message,
+        reply_type: u8,
+        yiaddr: Ipv4Addr,
+        out: &mut [u8],
+    ) -> Option<usize> {
+        let out = out.get_mut(..MAX_REPLY_LEN)?;
+        out.fill(0);
+        out[0] = BOOTREPLY;
+        out[1] = 1; // Ethernet
+        out[2] = 6;
+        out[4..8].copy_from_slice(&request.xid);
+        out[10..12].copy_from_slice(&request.flags);
+        out[16..20].copy_from_slice(&yiaddr.octets());
+        out[20..24].copy_from_slice(&self.server.octets());
+        out[28..44].copy_from_slice(&request.chaddr);
+        out[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        let server = self.server.octets();
+        let mut pos = OPTIONS_START;
+        let mut put = |code: u8, value: &[u8]| {
+            out[pos] = code;
+            out[pos + 1] = value.len() as u8;
+            out[pos + 2..pos + 2 + value.len()].copy_from_slice(value);
+            pos += 2 + value.len();
+        };
+
+        put(OPT_MESSAGE_TYPE, &[reply_type]);
+        put(OPT_SERVER_ID, &server);
+        if reply_type != NAK {
+            put(OPT_LEASE_TIME, &LEASE_SECS.to_be_bytes());
+            put(OPT_SUBNET_MASK, &[255, 255, 255, 0]);
+            put(OPT_ROUTER, &server);
+            // We answer every DNS query ourselves to make the portal pop up
+            put(OPT_DNS, &server);
+        }
+        out[pos] = OPT_END;
+
+        Some(pos + 1)
+    }
+}
+
+/// INFORM asking the server of a configured client for NTP servers
+///
+/// Built as a whole IPv4 packet for a raw socket, the network stack fills in
+/// the header checksum.
+pub fn inform(xid: [u8; 4], mac: [u8; 6], ciaddr: Ipv4Addr, out: &mut [u8]) -> Option<usize> {
+    let out = out.get_mut(..INFORM_PACKET_LEN)?;
+    out.fill(0);
+    let (ip, rest) = out.split_at_mut(IPV4_HEADER_LEN);
+    let (udp, message) = rest.split_at_mut(UDP_HEADER_LEN);
+
+    ip[0] = 0x45; // Version 4, no options
+    ip[2..4].copy_from_slice(&(INFORM_PACKET_LEN as u16).to_be_bytes());
+    ip[8] = 64; // TTL
+    ip[9] = IPV4_PROTOCOL_UDP;
+    ip[12..16].copy_from_slice(&ciaddr.octets());
+    ip[16..20].copy_from_slice(&Ipv4Addr::BROADCAST.octets());
+
+    // The checksum is optional over IPv4 and left out
+    udp[0..2].copy_from_slice(&CLIENT_PORT.to_be_bytes());
+    udp[2..4].copy_from_slice(&SERVER_PORT.to_be_bytes());
+    udp[4..6].copy_from_slice(&((UDP_HEADER_LEN + INFORM_LEN) as u16).to_be_bytes());
+
+    message[0] = BOOTREQUEST;
+    message[1] = 1; // Ethernet
+    message[2] = 6;
+    message[4..8].copy_from_slice(&xid);
+    message[12..16].copy_from_slice(&ciaddr.octets());
+    message[28..34].copy_from_slice(&mac);
+    message[236..240].copy_from_slice(&MAGIC_COOKIE);
+    message[OPTIONS_START..OPTIONS_START + 7].copy_from_slice(&[
+        OPT_MESSAGE_TYPE,
+        1,
+        INFORM,
+        OPT_PARAMETER_LIST,
+        1,
+        OPT_NTP_SERVERS,
+        OPT_END,
+    ]);
+    Some(INFORM_PACKET_LEN)
+}
+
+/// NTP servers in the server's ACK to the INFORM with `xid`
+pub fn ntp_servers(packet: &[u8], xid: [u8; 4]) -> Option<impl Iterator<Item = Ipv4Addr> + '_> {
+    let reply = Message::parse(packet, BOOTREPLY)?;
+    if reply.xid != xid || reply.message_type()? != ACK {
+        return None;
+    }
+    let servers = reply.option(OPT_NTP_SERVERS)?;
+    Some(
+        servers
+            .chunks_exact(4)
+            .map(|ip| Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+    )
+}
+
+/// The DHCP message of a raw IPv4 packet from a server to a client
+///
+/// The network stack keeps replies on the client port to itself, only raw
+/// sockets see them.
+pub fn from_ip_packet(packet: &[u8]) -> Option<&[u8]> {
+    let header_len = (*packet.first()? & 0x0f) as usize * 4;
+    if header_len < IPV4_HEADER_LEN || *packet.get(9)? != IPV4_PROTOCOL_UDP {
+        return None;
+    }
+    let udp = packet.get(header_len..)?;
+    if *udp.get(..2)? != SERVER_PORT.to_be_bytes() || *udp.get(2..4)? != CLIENT_PORT.to_be_bytes() {
+        return None;
+    }
+    udp.get(UDP_HEADER_LEN..)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XID: [u8; 4] = [1, 2, 3, 4];
+    const MAC: [u8; 6] = [0xaa, 1, 2, 3, 4, 5];
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 20);
+
+    /// ACK to an INFORM as a raw IPv4 packet, `options` without the end
+    fn ack(xid: [u8; 4], options: &[u8]) -> std::vec::Vec<u8> {
+        let mut packet = std::vec![0u8; IPV4_HEADER_LEN + UDP_HEADER_LEN + OPTIONS_START];
+        packet[0] = 0x45;
+        packet[9] = IPV4_PROTOCOL_UDP;
+        let udp = &mut packet[IPV4_HEADER_LEN..];
+        udp[0..2].copy_from_slice(&SERVER_PORT.to_be_bytes());
+        udp[2..4].copy_from_slice(&CLIENT_PORT.to_be_bytes());
+        let message = &mut udp[UDP_HEADER_LEN..];
+        message[0] = BOOTREPLY;
+        message[4..8].copy_from_slice(&xid);
+        message[236..240].copy_from_slice(&MAGIC_COOKIE);
+        packet.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, ACK]);
+        packet.extend_from_slice(options);
+        packet.push(OPT_END);
+        packet
+    }
+
+    #[test]
+    fn builds_inform_packet() {
+        let mut out = [0u8; 576];
+        assert_eq!(inform(XID, MAC, CLIENT, &mut out[..100]), None);
+        let len = inform(XID, MAC, CLIENT, &mut out).unwrap();
+        assert_eq!(len, INFORM_PACKET_LEN);
+        assert_eq!(out[0], 0x45);
+        assert_eq!(u16::from_be_bytes([out[2], out[3]]) as usize, len);
+        assert_eq!(out[9], IPV4_PROTOCOL_UDP);
+        assert_eq!(out[12..16], CLIENT.octets());
+        assert_eq!(out[16..20], [255; 4]);
+
+        let udp = &out[IPV4_HEADER_LEN..len];
+        assert_eq!(udp[0..4], [0, 68, 0, 67]);
+        assert_eq!(u16::from_be_bytes([udp[4], udp[5]]) as usize, udp.len());
+
+        let message = Message::parse(&udp[UDP_HEADER_LEN..], BOOTREQUEST).unwrap();
+        assert_eq!(message.xid, XID);
+        assert_eq!(message.mac(), MAC);
+        assert_eq!(message.ciaddr, CLIENT);
+        assert_eq!(message.message_type(), Some(INFORM));
+        assert_eq!(
+            message.option(OPT_PARAMETER_LIST),
+            Some(&[OPT_NTP_SERVERS][..])
+        );
+    }
+
+    #[test]
+    fn reads_ntp_servers_from_ack() {
+        let packet = ack(XID, &[OPT_NTP_SERVERS, 8, 10, 0, 0, 1, 10, 0, 0, 2]);
+        let message = from_ip_packet(&packet).unwrap();
+        let servers: std::vec::Vec<_> = ntp_servers(message, XID).unwrap().collect();
+        assert_eq!(
+            servers,
+            [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]
+        );
+
+        // Another exchange, no servers offered, an option running past the end
+        assert!(ntp_servers(message, [9; 4]).is_none());
+        let packet = ack(XID, &[OPT_ROUTER, 4, 10, 0, 0, 1]);
+        assert!(ntp_servers(from_ip_packet(&packet).unwrap(), XID).is_none());
+        let mut packet = ack(XID, &[OPT_NTP_SERVERS, 8, 10, 0, 0, 1]);
+        packet.pop();
+        assert!(ntp_servers(from_ip_packet(&packet).unwrap(), XID).is_none());
+    }
+
+    #[test]
+    fn ignores_other_ip_packets() {
+        let packet = ack(XID, &[]);
+        assert!(from_ip_packet(&packet).is_some());
+        assert_eq!(from_ip_packet(&[]), None);
+        assert_eq!(from_ip_packet(&packet[..IPV4_HEADER_LEN + 2]), None);
+
+        let mut tcp = packet.clone();
+        tcp[9] = 6;
+        assert_eq!(from_ip_packet(&tcp), None);
+        let mut request = packet.clone();
+        request[IPV4_HEADER_LEN..IPV4_HEADER_LEN + 4].copy_from_slice(&[0, 68, 0, 67]);
+        assert_eq!(from_ip_packet(&request), None);
+        let mut short_header = packet.clone();
+        short_header[0] = 0x42;
+        assert_eq!(from_ip_packet(&short_header), None);
+    }
+}
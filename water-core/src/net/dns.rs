@@ -0,0 +1,74 @@
+//! DNS wire format helpers
+
+use core::net::Ipv4Addr;
+
+pub const PORT: u16 = 53;
+
+pub const HEADER_LEN: usize = 12;
+pub const TYPE_A: u16 = 1;
+pub const CLASS_IN: u16 = 1;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+const OPCODE_MASK: u16 = 0x7800;
+
+pub fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*buf.get(pos)?, *buf.get(pos + 1)?]))
+}
+
+/// End of an uncompressed name starting at `pos`
+fn skip_name(buf: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            // Compression pointer ends the name
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l if l > 63 => return None,
+            l => pos += 1 + l,
+        }
+    }
+}
+
+/// Answer a standard query with `ip` for every A question
+///
+/// Other question types get an empty answer. This is what a captive portal
+/// needs: whatever the client looks up resolves to the portal itself.
+pub fn captive_response(query: &[u8], ip: Ipv4Addr, out: &mut [u8]) -> Option<usize> {
+    let flags = read_u16(query, 2)?;
+    let questions = read_u16(query, 4)?;
+    if flags & FLAG_RESPONSE != 0 || flags & OPCODE_MASK != 0 || questions != 1 {
+        return None;
+    }
+
+    let name_end = skip_name(query, HEADER_LEN)?;
+    let qtype = read_u16(query, name_end)?;
+    let question_end = name_end + 4;
+    if question_end > query.len() {
+        return None;
+    }
+
+    let answers: u16 = if qtype == TYPE_A { 1 } else { 0 };
+    let answer_len = if answers > 0 { 16 } else { 0 };
+    let out = out.get_mut(..question_end + answer_len)?;
+
+    out[..question_end].copy_from_slice(&query[..question_end]);
+    let reply_flags = FLAG_RESPONSE | FLAG_RECURSION_AVAILABLE | (flags & FLAG_RECURSION_DESIRED);
+    out[2..4].copy_from_slice(&reply_flags.to_be_bytes());
+    out[6..8].copy_from_slice(&answers.to_be_bytes());
+    out[8..12].fill(0);
+
+    if answers > 0 {
+        let answer = &mut out[question_end..];
+        // Pointer to the name in the question
+        answer[0..2].copy_from_slice(&(0xc000u16 | HEADER_LEN as u16).to_be_bytes());
+        answer[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+        answer[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        answer[6..10].copy_from_slice(&60u32.to_be_bytes());
+        answer[10..12].copy_from_slice(&4u16.to_be_bytes());
+        answer[12..16].copy_from_slice(&ip.octets());
+    }
+
+    Some(question_end + answer_len)
+}
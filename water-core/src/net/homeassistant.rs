@@ -0,0 +1,317 @@
+//! Home Assistant MQTT discovery configs
+//!
+//! Every entity reads the shared status JSON through a value template, so
+//! the telemetry payload stays the single source of truth.
+//!
+//! Home Assistant can't sign commands, so with a command key set the
+//! controls are left out and only the sensors get discovered.
+
+use core::fmt::Write;
+use heapless::String;
+use serde::Serialize;
+
+use crate::watering::BOARD_ZONES;
+
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+pub const CONFIG_TOPIC_LEN: usize = 96;
+pub const CONFIG_LEN: usize = 768;
+/// Length of the cycle started by the "Water" button
+pub const MANUAL_WATERING_SECS: u32 = 30;
+
+const MANUFACTURER: &str = "rayslava";
+const MODEL: &str = "ESP32 watering machine";
+
+const OBJECT_ID_LEN: usize = 24;
+const TEMPLATE_LEN: usize = 96;
+
+pub struct Device<'a> {
+    /// Unique among all devices on the broker
+    pub id: &'a str,
+    pub name: &'a str,
+    pub sw_version: &'a str,
+    pub state_topic: &'a str,
+    /// `None` while commands must be signed, dropping the controls
+    pub command_topic: Option<&'a str>,
+    pub availability_topic: &'a str,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Entity {
+    Humidity(u8),
+    HumidityRaw(u8),
+    HumidityLimit(u8),
+    Water(u8),
+    Pumping(u8),
+    Charge,
+    ChargeRaw,
+    Latency,
+}
+
+impl Entity {
+    pub fn all() -> impl Iterator<Item = Entity> {
+        let per_zone = (0..BOARD_ZONES as u8).flat_map(|zone| {
+            [
+                Entity::Humidity(zone),
+                Entity::HumidityRaw(zone),
+                Entity::HumidityLimit(zone),
+                Entity::Water(zone),
+                Entity::Pumping(zone),
+            ]
+        });
+        per_zone.chain([Entity::Charge, Entity::ChargeRaw, Entity::Latency])
+    }
+
+    fn component(&self) -> &'static str {
+        match self {
+            Entity::HumidityLimit(_) => "number",
+            Entity::Water(_) => "button",
+            Entity::Pumping(_) => "binary_sensor",
+            _ => "sensor",
+        }
+    }
+
+    fn object_id(&self) -> String<OBJECT_ID_LEN> {
+        let mut id = String::new();
+        match self {
+            Entity::Humidity(zone) => write!(id, "humidity_{}", zone),
+            Entity::HumidityRaw(zone) => write!(id, "humidity_raw_{}", zone),
+            Entity::HumidityLimit(zone) => write!(id, "low_humidity_limit_{}", zone),
+            Entity::Water(zone) => write!(id, "water_{}", zone),
+            Entity::Pumping(zone) => write!(id, "pumping_{}", zone),
+            Entity::Charge => write!(id, "charge"),
+            Entity::ChargeRaw => write!(id, "charge_raw"),
+            Entity::Latency => write!(id, "latency"),
+        }
+        .ok();
+        id
+    }
+
+    fn name(&self) -> String<OBJECT_ID_LEN> {
+        let mut name = String::new();
+        match self {
+            Entity::Humidity(zone) => write!(name, "Humidity {}", zone),
+            Entity::HumidityRaw(zone) => write!(name, "Humidity {} raw", zone),
+            Entity::HumidityLimit(zone) => write!(name, "Humidity {} limit", zone),
+            Entity::Water(zone) => write!(name, "Water {}", zone),
+            Entity::Pumping(zone) => write!(name, "Pump {}", zone),
+            Entity::Charge => write!(name, "Battery"),
+            Entity::ChargeRaw => write!(name, "Battery raw"),
+            Entity::Latency => write!(name, "Latency"),
+        }
+        .ok();
+        name
+    }
+
+    fn value_template(&self) -> Option<String<TEMPLATE_LEN>> {
+        let mut template = String::new();
+        match self {
+            Entity::Humidity(zone) => {
+                write!(template, "{{{{ value_json.zones[{}].humidity }}}}", zone)
+            }
+            Entity::HumidityRaw(zone) => {
+                write!(
+                    template,
+                    "{{{{ value_json.zones[{}].humidity_raw }}}}",
+                    zone
+                )
+            }
+            Entity::HumidityLimit(zone) => write!(
+                template,
+                "{{{{ value_json.zones[{}].low_humidity_limit }}}}",
+                zone
+            ),
+            Entity::Pumping(zone) => write!(
+                template,
+                "{{{{ 'ON' if value_json.zones[{}].pumping else 'OFF' }}}}",
+                zone
+            ),
+            Entity::Charge => write!(template, "{{{{ value_json.charge }}}}"),
+            Entity::ChargeRaw => write!(template, "{{{{ value_json.charge_raw }}}}"),
+            Entity::Latency => write!(template, "{{{{ value_json.latency_ms }}}}"),
+            Entity::Water(_) => return None,
+        }
+        .ok()?;
+        Some(template)
+    }
+
+    /// Template or payload producing the matching [`crate::command::Command`]
+    fn command(&self) -> Option<String<TEMPLATE_LEN>> {
+        let mut command = String::new();
+        match self {
+            Entity::HumidityLimit(zone) => write!(
+                command,
+                "{{\"SetHumidityTrigger\":{{\"zone\":{},\"value\":{{{{ value | int }}}}}}}}",
+                zone
+            ),
+            Entity::Water(zone) => write!(
+                command,
+                "{{\"StartWatering\":{{\"zone\":{},\"secs\":{}}}}}",
+                zone, MANUAL_WATERING_SECS
+            ),
+            _ => return None,
+        }
+        .ok()?;
+        Some(command)
+    }
+}
+
+#[derive(Serialize)]
+struct DeviceConfig<'a> {
+    identifiers: [&'a str; 1],
+    name: &'a str,
+    sw_version: &'a str,
+    model: &'a str,
+    manufacturer: &'a str,
+}
+
+#[derive(Serialize)]
+struct EntityConfig<'a> {
+    name: &'a str,
+    unique_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_topic: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_template: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_topic: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_template: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_press: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_class: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<u16>,
+    availability_topic: &'a str,
+    device: DeviceConfig<'a>,
+}
+
+/// `homeassistant/<component>/<device id>/<object id>/config`
+pub fn config_topic(device: &Device, entity: Entity) -> String<CONFIG_TOPIC_LEN> {
+    let mut topic = String::new();
+    write!(
+        topic,
+        "{}/{}/{}/{}/config",
+        DISCOVERY_PREFIX,
+        entity.component(),
+        device.id,
+        entity.object_id()
+    )
+    .ok();
+    topic
+}
+
+/// Serialize the discovery config into `out`, returning its length
+///
+/// `None` for the controls of a device without a command topic.
+pub fn config_payload(device: &Device, entity: Entity, out: &mut [u8]) -> Option<usize> {
+    let command = entity.command();
+    if command.is_some() && device.command_topic.is_none() {
+        return None;
+    }
+    let name = entity.name();
+    let mut unique_id: String<{ OBJECT_ID_LEN * 2 }> = String::new();
+    write!(unique_id, "{}_{}", device.id, entity.object_id()).ok()?;
+    let value_template = entity.value_template();
+
+    let (unit, device_class, state_class, category) = match entity {
+        Entity::Humidity(_) => (Some("%"), Some("moisture"), Some("measurement"), None),
+        Entity::Charge => (Some("%"), Some("battery"), Some("measurement"), None),
+        Entity::HumidityLimit(_) => (Some("%"), None, None, Some("config")),
+        Entity::Latency => (
+            Some("ms"),
+            Some("duration"),
+            Some("measurement"),
+            Some("diagnostic"),
+        ),
+        Entity::HumidityRaw(_) | Entity::ChargeRaw => {
+            (None, None, Some("measurement"), Some("diagnostic"))
+        }
+        Entity::Pumping(_) => (None, Some("running"), None, None),
+        Entity::Water(_) => (None, None, None, None),
+    };
+    let (min, max) = match entity {
+        Entity::HumidityLimit(_) => (Some(0), Some(100)),
+        _ => (None, None),
+    };
+    let is_button = matches!(entity, Entity::Water(_));
+
+    let config = EntityConfig {
+        name: &name,
+        unique_id: &unique_id,
+        state_topic: (!is_button).then_some(device.state_topic),
+        value_template: value_template.as_deref(),
+        command_topic: device.command_topic.filter(|_| command.is_some()),
+        command_template: command.as_deref().filter(|_| !is_button),
+        payload_press: command.as_deref().filter(|_| is_button),
+        unit_of_measurement: unit,
+        device_class,
+        state_class,
+        entity_category: category,
+        min,
+        max,
+        availability_topic: device.availability_topic,
+        device: DeviceConfig {
+            identifiers: [device.id],
+            name: device.name,
+            sw_version: device.sw_version,
+            model: MODEL,
+            manufacturer: MANUFACTURER,
+        },
+    };
+
+    serde_json_core::to_slice(&config, out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(command_topic: Option<&str>) -> Device<'_> {
+        Device {
+            id: "water_a0b1c2d3e4f5",
+            name: "Watering",
+            sw_version: "1.0.0",
+            state_topic: "water/a0b1c2d3e4f5/status",
+            command_topic,
+            availability_topic: "water/a0b1c2d3e4f5/availability",
+        }
+    }
+
+    fn payload(device: &Device, entity: Entity) -> Option<std::string::String> {
+        let mut out = [0u8; CONFIG_LEN];
+        let len = config_payload(device, entity, &mut out)?;
+        Some(std::string::String::from_utf8(out[..len].to_vec()).unwrap())
+    }
+
+    #[test]
+    fn advertises_controls() {
+        let device = device(Some("water/a0b1c2d3e4f5/control"));
+        let button = payload(&device, Entity::Water(0)).unwrap();
+        assert!(button.contains("\"command_topic\":\"water/a0b1c2d3e4f5/control\""));
+        assert!(button.contains("\"payload_press\""));
+        assert!(!button.contains("state_topic"));
+        let limit = payload(&device, Entity::HumidityLimit(1)).unwrap();
+        assert!(limit.contains("\"command_template\""));
+        let sensor = payload(&device, Entity::Humidity(0)).unwrap();
+        assert!(!sensor.contains("command_topic"));
+    }
+
+    #[test]
+    fn drops_controls_without_command_topic() {
+        let device = device(None);
+        assert_eq!(payload(&device, Entity::Water(0)), None);
+        assert_eq!(payload(&device, Entity::HumidityLimit(0)), None);
+        let sensor = payload(&device, Entity::Humidity(0)).unwrap();
+        assert!(sensor.contains("\"state_topic\":\"water/a0b1c2d3e4f5/status\""));
+        assert!(!sensor.contains("command_topic"));
+    }
+}
@@ -0,0 +1,294 @@
+//! Minimal HTTP/1.1 request parsing and response helpers
+//!
+//! Only what small embedded servers need: a single request per connection,
+//! bodies delimited by `Content-Length`, no chunked transfer encoding.
+
+use core::fmt::Write;
+use heapless::String;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request<'a> {
+    pub method: Method,
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub content_type: Option<&'a str>,
+    pub body: &'a [u8],
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// More data is needed
+    Incomplete,
+    BadRequest,
+    UnsupportedMethod,
+    /// The request doesn't fit in the buffer
+    TooLarge,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Status(pub u16, pub &'static str);
+
+impl Status {
+    pub const OK: Status = Status(200, "OK");
+    pub const SEE_OTHER: Status = Status(303, "See Other");
+    pub const BAD_REQUEST: Status = Status(400, "Bad Request");
+    pub const FORBIDDEN: Status = Status(403, "Forbidden");
+    pub const NOT_FOUND: Status = Status(404, "Not Found");
+    pub const METHOD_NOT_ALLOWED: Status = Status(405, "Method Not Allowed");
+    pub const PAYLOAD_TOO_LARGE: Status = Status(413, "Payload Too Large");
+    pub const UNPROCESSABLE: Status = Status(422, "Unprocessable Entity");
+    pub const INTERNAL_ERROR: Status = Status(500, "Internal Server Error");
+    pub const SERVICE_UNAVAILABLE: Status = Status(503, "Service Unavailable");
+}
+
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+/// Parse a request from the start of `buf`
+///
+/// Returns [`ParseError::Incomplete`] until the headers and the whole body
+/// announced by `Content-Length` are received. `capacity` is the size of the
+/// receive buffer, requests which can never fit yield [`ParseError::TooLarge`].
+pub fn parse_request(buf: &[u8], capacity: usize) -> Result<Request<'_>, ParseError> {
+    let Some(head_len) = buf
+        .windows(HEADER_END.len())
+        .position(|window| window == HEADER_END)
+    else {
+        return Err(if buf.len() >= capacity {
+            ParseError::TooLarge
+        } else {
+            ParseError::Incomplete
+        });
+    };
+
+    let head = core::str::from_utf8(&buf[..head_len]).map_err(|_| ParseError::BadRequest)?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let method = match request_line.next() {
+        Some("GET") => Method::Get,
+        Some("HEAD") => Method::Head,
+        Some("POST") => Method::Post,
+        Some("PUT") => Method::Put,
+        Some("") | None => return Err(ParseError::BadRequest),
+        Some(_) => return Err(ParseError::UnsupportedMethod),
+    };
+    let target = request_line.next().ok_or(ParseError::BadRequest)?;
+    if !request_line
+        .next()
+        .is_some_and(|version| version.starts_with("HTTP/1."))
+    {
+        return Err(ParseError::BadRequest);
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+    if !path.starts_with('/') {
+        return Err(ParseError::BadRequest);
+    }
+
+    let mut content_length = 0usize;
+    let mut content_type = None;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(ParseError::BadRequest)?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse().map_err(|_| ParseError::BadRequest)?;
+        } else if name.eq_ignore_ascii_case("content-type") {
+            content_type = Some(value);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(ParseError::BadRequest);
+        }
+    }
+
+    let body_start = head_len + HEADER_END.len();
+    // The length comes from the client, it may be anything up to `usize::MAX`
+    if content_length > capacity.saturating_sub(body_start) {
+        return Err(ParseError::TooLarge);
+    }
+    let body = buf
+        .get(body_start..body_start + content_length)
+        .ok_or(ParseError::Incomplete)?;
+
+    Ok(Request {
+        method,
+        path,
+        query,
+        content_type,
+        body,
+    })
+}
+
+pub const RESPONSE_HEAD_LEN: usize = 192;
+
+/// Status line and headers of a response closing the connection
+pub fn response_head(
+    status: Status,
+    content_type: &str,
+    content_length: usize,
+) -> String<RESPONSE_HEAD_LEN> {
+    let mut head = String::new();
+    write!(
+        head,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status.0, status.1, content_type, content_length
+    )
+    .ok();
+    head
+}
+
+/// Redirect to `location`, used to bounce clients to the main page
+pub fn redirect_head(location: &str) -> String<RESPONSE_HEAD_LEN> {
+    let mut head = String::new();
+    write!(
+        head,
+        "HTTP/1.1 {} {}\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        Status::SEE_OTHER.0,
+        Status::SEE_OTHER.1,
+        location
+    )
+    .ok();
+    head
+}
+
+/// Iterate over the raw `key=value` pairs of a query string or form body
+pub fn form_pairs(form: &str) -> impl Iterator<Item = (&str, &str)> {
+    form.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+}
+
+/// Decode a `application/x-www-form-urlencoded` value into `out`
+pub fn url_decode<const N: usize>(value: &str, out: &mut String<N>) -> Result<(), ParseError> {
+    out.clear();
+    let mut bytes = value.bytes();
+    let mut decoded: heapless::Vec<u8, N> = heapless::Vec::new();
+
+    while let Some(byte) = bytes.next() {
+        let byte = match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [
+                    bytes.next().ok_or(ParseError::BadRequest)?,
+                    bytes.next().ok_or(ParseError::BadRequest)?,
+                ];
+                // `from_str_radix` would take a sign as well
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return Err(ParseError::BadRequest);
+                }
+                let hex = core::str::from_utf8(&hex).map_err(|_| ParseError::BadRequest)?;
+                u8::from_str_radix(hex, 16).map_err(|_| ParseError::BadRequest)?
+            }
+            byte => byte,
+        };
+        decoded.push(byte).map_err(|_| ParseError::TooLarge)?;
+    }
+
+    let text = core::str::from_utf8(&decoded).map_err(|_| ParseError::BadRequest)?;
+    out.push_str(text).map_err(|_| ParseError::TooLarge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAPACITY: usize = 256;
+
+    #[test]
+    fn parses_request_with_body() {
+        let buf = b"POST /api/command?x=1 HTTP/1.1\r\nContent-Type: application/json\r\n\
+            Content-Length: 4\r\n\r\nbody";
+        let request = parse_request(buf, CAPACITY).unwrap();
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.path, "/api/command");
+        assert_eq!(request.query, Some("x=1"));
+        assert_eq!(request.content_type, Some("application/json"));
+        assert_eq!(request.body, b"body");
+    }
+
+    #[test]
+    fn waits_for_headers_and_body() {
+        assert_eq!(
+            parse_request(b"GET / HTTP/1.1\r\nHost: x\r\n", CAPACITY),
+            Err(ParseError::Incomplete)
+        );
+        assert_eq!(
+            parse_request(b"PUT / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc", CAPACITY),
+            Err(ParseError::Incomplete)
+        );
+    }
+
+    #[test]
+    fn rejects_what_never_fits() {
+        let head = [b'a'; CAPACITY];
+        assert_eq!(parse_request(&head, CAPACITY), Err(ParseError::TooLarge));
+        assert_eq!(
+            parse_request(b"PUT / HTTP/1.1\r\nContent-Length: 300\r\n\r\n", CAPACITY),
+            Err(ParseError::TooLarge)
+        );
+        // Would overflow when added to the header length
+        assert_eq!(
+            parse_request(
+                b"PUT / HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n",
+                CAPACITY
+            ),
+            Err(ParseError::TooLarge)
+        );
+        assert_eq!(
+            parse_request(
+                b"PUT / HTTP/1.1\r\nContent-Length: 99999999999999999999\r\n\r\n",
+                CAPACITY
+            ),
+            Err(ParseError::BadRequest)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert_eq!(
+            parse_request(
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nbody",
+                CAPACITY
+            ),
+            Err(ParseError::BadRequest)
+        );
+        assert_eq!(
+            parse_request(b"DELETE / HTTP/1.1\r\n\r\n", CAPACITY),
+            Err(ParseError::UnsupportedMethod)
+        );
+        assert_eq!(
+            parse_request(b"GET index HTTP/1.1\r\n\r\n", CAPACITY),
+            Err(ParseError::BadRequest)
+        );
+        assert_eq!(
+            parse_request(b"GET / HTTP/2\r\n\r\n", CAPACITY),
+            Err(ParseError::BadRequest)
+        );
+        assert_eq!(
+            parse_request(b"GET / HTTP/1.1\r\nno colon\r\n\r\n", CAPACITY),
+            Err(ParseError::BadRequest)
+        );
+    }
+
+    #[test]
+    fn decodes_form_values() {
+        let mut out: String<16> = String::new();
+        url_decode("a+b%3Dc%C3%A9", &mut out).unwrap();
+        assert_eq!(out, "a b=c\u{e9}");
+        assert_eq!(url_decode("%4", &mut out), Err(ParseError::BadRequest));
+        assert_eq!(url_decode("%+1", &mut out), Err(ParseError::BadRequest));
+        assert_eq!(url_decode("%ff", &mut out), Err(ParseError::BadRequest));
+        let mut short: String<2> = String::new();
+        assert_eq!(url_decode("abc", &mut short), Err(ParseError::TooLarge));
+        let pairs: heapless::Vec<_, 4> = form_pairs("a=1&&b&c=").collect();
+        assert_eq!(pairs, [("a", "1"), ("b", ""), ("c", "")]);
+    }
+}
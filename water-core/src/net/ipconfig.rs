@@ -0,0 +1,151 @@
+//! IPv4 settings of the station interface
+//!
+//! DHCP is the default. A stored static address replaces it, or serves as
+//! the fallback when no lease arrives within the DHCP timeout. Without one
+//! the fallback is a link-local address derived from the MAC. DHCP is tried
+//! again every [`DHCP_RETRY_INTERVAL`] until a lease replaces the fallback.
+
+use core::net::Ipv4Addr;
+use embassy_time::Duration;
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+/// Longest host name embassy-net sends in DHCP option 12
+pub const HOSTNAME_LEN: usize = 32;
+/// What fits the stack's static configuration
+pub const MAX_DNS_SERVERS: usize = 3;
+pub const DEFAULT_DHCP_TIMEOUT_SECS: u32 = 60;
+/// How often DHCP is tried again once on the fallback address
+pub const DHCP_RETRY_INTERVAL: Duration = Duration::from_secs(15 * 60);
+pub const LINK_LOCAL_PREFIX_LEN: u8 = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticIp {
+    pub address: [u8; 4],
+    pub prefix_len: u8,
+    pub gateway: Option<[u8; 4]>,
+    pub dns_servers: Vec<[u8; 4], MAX_DNS_SERVERS>,
+    /// Only used once DHCP timed out
+    pub fallback: bool,
+}
+
+fn parse_cidr(cidr: &str) -> Option<([u8; 4], u8)> {
+    let (address, prefix_len) = cidr.split_once('/')?;
+    let address: Ipv4Addr = address.parse().ok()?;
+    let prefix_len: u8 = prefix_len.parse().ok()?;
+    (1..=32)
+        .contains(&prefix_len)
+        .then_some((address.octets(), prefix_len))
+}
+
+impl StaticIp {
+    /// Parse `<address>/<prefix length> [gateway] [dns server...]`
+    pub fn parse(cidr: &str, rest: &[&str], fallback: bool) -> Option<Self> {
+        let (address, prefix_len) = parse_cidr(cidr)?;
+        let (gateway, dns) = match rest.split_first() {
+            Some((gateway, dns)) => (Some(gateway.parse::<Ipv4Addr>().ok()?.octets()), dns),
+            None => (None, rest),
+        };
+        let mut dns_servers = Vec::new();
+        for server in dns {
+            let server: Ipv4Addr = server.parse().ok()?;
+            dns_servers.push(server.octets()).ok()?;
+        }
+        Some(StaticIp {
+            address,
+            prefix_len,
+            gateway,
+            dns_servers,
+            fallback,
+        })
+    }
+
+    pub fn address(&self) -> Ipv4Addr {
+        self.address.into()
+    }
+
+    pub fn gateway(&self) -> Option<Ipv4Addr> {
+        self.gateway.map(Ipv4Addr::from)
+    }
+
+    pub fn dns_servers(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.dns_servers.iter().map(|&server| server.into())
+    }
+
+    pub fn is_valid(&self) -> bool {
+        (1..=32).contains(&self.prefix_len) && !self.address().is_unspecified()
+    }
+}
+
+/// RFC 1123 label: letters, digits and inner hyphens
+pub fn is_valid_hostname(name: &str) -> bool {
+    (1..=HOSTNAME_LEN).contains(&name.len())
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+/// Address in 169.254.1.0 - 169.254.254.255, stable for a MAC
+///
+/// Collisions aren't probed for (RFC 3927), a network without DHCP rarely
+/// has many hosts.
+pub fn link_local(mac: [u8; 6]) -> Ipv4Addr {
+    Ipv4Addr::new(169, 254, 1 + mac[4] % 254, mac[5])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_static_config() {
+        let ip = StaticIp::parse(
+            "192.168.1.20/24",
+            &["192.168.1.1", "1.1.1.1", "9.9.9.9"],
+            false,
+        )
+        .unwrap();
+        assert_eq!(ip.address(), Ipv4Addr::new(192, 168, 1, 20));
+        assert_eq!(ip.prefix_len, 24);
+        assert_eq!(ip.gateway(), Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(ip.dns_servers().count(), 2);
+        assert!(ip.is_valid());
+
+        let bare = StaticIp::parse("10.0.0.5/8", &[], true).unwrap();
+        assert_eq!(bare.gateway(), None);
+        assert!(bare.fallback);
+
+        assert_eq!(StaticIp::parse("10.0.0.5", &[], false), None);
+        assert_eq!(StaticIp::parse("10.0.0.5/33", &[], false), None);
+        assert_eq!(StaticIp::parse("10.0.0.5/8", &["gw"], false), None);
+        let dns = ["10.0.0.1", "1.1.1.1", "1.0.0.1", "8.8.8.8", "8.8.4.4"];
+        assert_eq!(StaticIp::parse("10.0.0.5/8", &dns, false), None);
+    }
+
+    #[test]
+    fn hostnames() {
+        assert!(is_valid_hostname("water-a0b1c2d3e4f5"));
+        assert!(!is_valid_hostname(""));
+        assert!(!is_valid_hostname("-water"));
+        assert!(!is_valid_hostname("water.local"));
+        assert!(!is_valid_hostname("a-very-long-host-name-for-a-pump1"));
+    }
+
+    #[test]
+    fn link_local_range() {
+        assert_eq!(
+            link_local([0, 0, 0, 0, 0, 0]),
+            Ipv4Addr::new(169, 254, 1, 0)
+        );
+        assert_eq!(
+            link_local([0, 0, 0, 0, 0xff, 0xff]),
+            Ipv4Addr::new(169, 254, 2, 255)
+        );
+        assert_eq!(
+            link_local([0, 0, 0, 0, 0xfd, 7]),
+            Ipv4Addr::new(169, 254, 254, 7)
+        );
+    }
+}
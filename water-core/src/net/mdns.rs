@@ -0,0 +1,637 @@
+//! mDNS and DNS-SD wire format (RFC 6762, RFC 6763)
+//!
+//! Records are written with uncompressed names, which costs a few bytes per
+//! record but keeps the encoder trivial. Names in received packets may be
+//! compressed.
+
+use core::net::Ipv4Addr;
+use heapless::{String, Vec};
+
+use super::dns::{CLASS_IN, HEADER_LEN, TYPE_A, read_u16};
+
+pub const PORT: u16 = 5353;
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const NAME_LEN: usize = 128;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+
+const SERVICES: &str = "_services._dns-sd._udp.local";
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const OPCODE_MASK: u16 = 0x7800;
+// Top bit of the class: unicast response in questions, cache flush in records
+const CLASS_TOP: u16 = 0x8000;
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+/// Longest TTL given to legacy resolvers (RFC 6762 section 6.7)
+const LEGACY_TTL: u32 = 10;
+const MAX_POINTERS: usize = 8;
+const MAX_RECORDS: usize = 16;
+
+pub type Name = String<NAME_LEN>;
+
+/// Read the possibly compressed name at `pos`, returns where the name ends
+/// in place
+pub fn read_name(buf: &[u8], mut pos: usize, name: &mut Name) -> Option<usize> {
+    name.clear();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *buf.get(pos)? as usize;
+        match len {
+            0 => return Some(end.unwrap_or(pos + 1)),
+            l if l & 0xc0 == 0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(pos + 2);
+                pos = (read_u16(buf, pos)? & 0x3fff) as usize;
+            }
+            l if l > 63 => return None,
+            l => {
+                let label = core::str::from_utf8(buf.get(pos + 1..pos + 1 + l)?).ok()?;
+                if !name.is_empty() {
+                    name.push('.').ok()?;
+                }
+                name.push_str(label).ok()?;
+                pos += 1 + l;
+            }
+        }
+    }
+}
+
+/// Case-insensitive comparison of `name` with `parts` joined by dots
+fn name_is(name: &str, parts: &[&str]) -> bool {
+    let mut rest = name;
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            match rest.strip_prefix('.') {
+                Some(tail) => rest = tail,
+                None => return false,
+            }
+        }
+        match rest.get(..part.len()) {
+            Some(head) if head.eq_ignore_ascii_case(part) => rest = &rest[part.len()..],
+            _ => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+/// Whether `name` is an instance of `service`, e.g. `Broker._mqtt._tcp.local`
+fn is_instance_of(name: &str, service: &str) -> bool {
+    let Some(split) = name.len().checked_sub(service.len() + 1) else {
+        return false;
+    };
+    split > 0
+        && name.as_bytes()[split] == b'.'
+        && name
+            .get(split + 1..)
+            .is_some_and(|tail| tail.eq_ignore_ascii_case(service))
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    /// Records for a resolver that doesn't speak mDNS
+    legacy: bool,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Writer {
+            buf,
+            len: 0,
+            legacy: false,
+        }
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> Option<()> {
+        self.buf
+            .get_mut(self.len..self.len + data.len())?
+            .copy_from_slice(data);
+        self.len += data.len();
+        Some(())
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// `parts` joined by dots, a part may hold several labels
+    fn name(&mut self, parts: &[&str]) -> Option<()> {
+        for label in parts.iter().flat_map(|part| part.split('.')) {
+            if label.is_empty() || label.len() > 63 {
+                return None;
+            }
+            self.bytes(&[label.len() as u8])?;
+            self.bytes(label.as_bytes())?;
+        }
+        self.bytes(&[0])
+    }
+
+    fn header(&mut self, id: u16, flags: u16, counts: [u16; 4]) -> Option<()> {
+        self.u16(id)?;
+        self.u16(flags)?;
+        counts.iter().try_for_each(|&count| self.u16(count))
+    }
+
+    fn record(
+        &mut self,
+        name: &[&str],
+        rtype: u16,
+        flush: bool,
+        ttl: u32,
+        data: impl FnOnce(&mut Self) -> Option<()>,
+    ) -> Option<()> {
+        self.name(name)?;
+        self.u16(rtype)?;
+        self.u16(if flush && !self.legacy {
+            CLASS_IN | CLASS_TOP
+        } else {
+            CLASS_IN
+        })?;
+        self.u32(if self.legacy {
+            ttl.min(LEGACY_TTL)
+        } else {
+            ttl
+        })?;
+        let len_pos = self.len;
+        self.u16(0)?;
+        data(self)?;
+        let data_len = (self.len - len_pos - 2) as u16;
+        self.buf[len_pos..len_pos + 2].copy_from_slice(&data_len.to_be_bytes());
+        Some(())
+    }
+}
+
+/// A service announced by this host, `name` is e.g. `_http._tcp`
+pub struct Service<'a> {
+    pub name: &'a str,
+    pub port: u16,
+    /// `key=value` entries of the TXT record
+    pub txt: &'a [&'a str],
+}
+
+/// Records a responder publishes, the services are instances named after
+/// the host
+pub struct Host<'a> {
+    /// Host label without `.local`
+    pub name: &'a str,
+    pub ip: Ipv4Addr,
+    pub services: &'a [Service<'a>],
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Record {
+    Address,
+    /// Service type listed under `_services._dns-sd._udp.local`
+    ServiceType(usize),
+    /// Service type pointing to our instance
+    Instance(usize),
+    Srv(usize),
+    Txt(usize),
+}
+
+/// ID and question section of a query from a legacy resolver, which only
+/// accepts replies echoing them
+struct Echo<'a> {
+    id: u16,
+    count: u16,
+    questions: &'a [u8],
+}
+
+/// Reply to a query, `unicast` if the querier asked for a direct answer
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub len: usize,
+    pub unicast: bool,
+}
+
+impl Host<'_> {
+    fn write(&self, out: &mut Writer, record: Record) -> Option<()> {
+        let host = [self.name, "local"];
+        match record {
+            Record::Address => out.record(&host, TYPE_A, true, HOST_TTL, |out| {
+                out.bytes(&self.ip.octets())
+            }),
+            Record::ServiceType(i) => {
+                let service = self.services.get(i)?;
+                out.record(&[SERVICES], TYPE_PTR, false, SERVICE_TTL, |out| {
+                    out.name(&[service.name, "local"])
+                })
+            }
+            Record::Instance(i) => {
+                let service = self.services.get(i)?;
+                let name = [service.name, "local"];
+                out.record(&name, TYPE_PTR, false, SERVICE_TTL, |out| {
+                    out.name(&[self.name, service.name, "local"])
+                })
+            }
+            Record::Srv(i) => {
+                let service = self.services.get(i)?;
+                let name = [self.name, service.name, "local"];
+                out.record(&name, TYPE_SRV, true, HOST_TTL, |out| {
+                    // Priority and weight
+                    out.u32(0)?;
+                    out.u16(service.port)?;
+                    out.name(&host)
+                })
+            }
+            Record::Txt(i) => {
+                let service = self.services.get(i)?;
+                let name = [self.name, service.name, "local"];
+                out.record(&name, TYPE_TXT, true, SERVICE_TTL, |out| {
+                    if service.txt.is_empty() {
+                        // An empty TXT record still holds one empty string
+                        return out.bytes(&[0]);
+                    }
+                    service.txt.iter().try_for_each(|entry| {
+                        out.bytes(&[u8::try_from(entry.len()).ok()?])?;
+                        out.bytes(entry.as_bytes())
+                    })
+                })
+            }
+        }
+    }
+
+    fn response(
+        &self,
+        answers: &[Record],
+        additional: &[Record],
+        echo: Option<Echo>,
+        out: &mut [u8],
+    ) -> Option<usize> {
+        let mut out = Writer::new(out);
+        let (id, questions) = echo.as_ref().map_or((0, 0), |echo| (echo.id, echo.count));
+        let counts = [questions, answers.len() as u16, 0, additional.len() as u16];
+        out.header(id, FLAG_RESPONSE | FLAG_AUTHORITATIVE, counts)?;
+        if let Some(echo) = echo {
+            // Copied to the same offset, compressed names still point right
+            out.bytes(echo.questions)?;
+            out.legacy = true;
+        }
+        answers
+            .iter()
+            .chain(additional)
+            .try_for_each(|&record| self.write(&mut out, record))?;
+        Some(out.len)
+    }
+
+    /// Records answering one question, and the ones a querier will ask next
+    fn answer(
+        &self,
+        name: &str,
+        qtype: u16,
+        answers: &mut Vec<Record, MAX_RECORDS>,
+        additional: &mut Vec<Record, MAX_RECORDS>,
+    ) {
+        let wants = |rtype| qtype == rtype || qtype == TYPE_ANY;
+        let add = |list: &mut Vec<Record, MAX_RECORDS>, record| {
+            if !list.contains(&record) {
+                list.push(record).ok();
+            }
+        };
+
+        if name_is(name, &[self.name, "local"]) && wants(TYPE_A) {
+            add(answers, Record::Address);
+        }
+        for (i, service) in self.services.iter().enumerate() {
+            if name_is(name, &[SERVICES]) && wants(TYPE_PTR) {
+                add(answers, Record::ServiceType(i));
+            }
+            if name_is(name, &[service.name, "local"]) && wants(TYPE_PTR) {
+                add(answers, Record::Instance(i));
+                add(additional, Record::Srv(i));
+                add(additional, Record::Txt(i));
+                add(additional, Record::Address);
+            }
+            if name_is(name, &[self.name, service.name, "local"]) {
+                if wants(TYPE_SRV) {
+                    add(answers, Record::Srv(i));
+                    add(additional, Record::Address);
+                }
+                if wants(TYPE_TXT) {
+                    add(answers, Record::Txt(i));
+                }
+            }
+        }
+    }
+
+    /// Answer the questions in `query` that are about this host
+    ///
+    /// A `legacy` querier sent from a port other than 5353 and gets a plain
+    /// DNS answer echoing its ID and questions.
+    pub fn respond(&self, query: &[u8], legacy: bool, out: &mut [u8]) -> Option<Reply> {
+        let flags = read_u16(query, 2)?;
+        let questions = read_u16(query, 4)?;
+        if flags & FLAG_RESPONSE != 0 || flags & OPCODE_MASK != 0 {
+            return None;
+        }
+
+        let mut answers = Vec::new();
+        let mut additional = Vec::new();
+        let mut unicast = false;
+        let mut name = Name::new();
+        let mut pos = HEADER_LEN;
+        for _ in 0..questions {
+            pos = read_name(query, pos, &mut name)?;
+            let qtype = read_u16(query, pos)?;
+            let qclass = read_u16(query, pos + 2)?;
+            pos += 4;
+
+            let before = answers.len();
+            self.answer(&name, qtype, &mut answers, &mut additional);
+            if answers.len() > before && qclass & CLASS_TOP != 0 {
+                unicast = true;
+            }
+        }
+        if answers.is_empty() {
+            return None;
+        }
+        additional.retain(|record| !answers.contains(record));
+
+        let echo = legacy.then(|| Echo {
+            id: read_u16(query, 0).unwrap_or(0),
+            count: questions,
+            questions: &query[HEADER_LEN..pos],
+        });
+        let len = self.response(&answers, &additional, echo, out)?;
+        Some(Reply {
+            len,
+            unicast: unicast || legacy,
+        })
+    }
+
+    /// Unsolicited response with every record, sent when joining the network
+    pub fn announce(&self, out: &mut [u8]) -> Option<usize> {
+        let mut records: Vec<Record, MAX_RECORDS> = Vec::new();
+        records.push(Record::Address).ok()?;
+        for i in 0..self.services.len() {
+            for record in [
+                Record::ServiceType(i),
+                Record::Instance(i),
+                Record::Srv(i),
+                Record::Txt(i),
+            ] {
+                records.push(record).ok()?;
+            }
+        }
+        self.response(&records, &[], None, out)
+    }
+}
+
+/// One-shot query for `name`, asking for a unicast reply
+pub fn query(name: &str, qtype: u16, out: &mut [u8]) -> Option<usize> {
+    let mut out = Writer::new(out);
+    out.header(0, 0, [1, 0, 0, 0])?;
+    out.name(&[name])?;
+    out.u16(qtype)?;
+    out.u16(CLASS_IN | CLASS_TOP)?;
+    Some(out.len)
+}
+
+/// A resource record of a received response
+struct Resource<'a> {
+    name: Name,
+    rtype: u16,
+    /// Offset of the data in the packet, names in it may point backwards
+    data_pos: usize,
+    data: &'a [u8],
+}
+
+/// Offset of the first resource record and the number of records
+fn skip_questions(packet: &[u8]) -> Option<(usize, usize)> {
+    let flags = read_u16(packet, 2)?;
+    if flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    let questions = read_u16(packet, 4)?;
+    let records = (6..12)
+        .step_by(2)
+        .map(|pos| read_u16(packet, pos).map(usize::from))
+        .sum::<Option<usize>>()?;
+
+    let mut name = Name::new();
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        pos = read_name(packet, pos, &mut name)? + 4;
+    }
+    Some((pos, records))
+}
+
+/// All resource records of a response
+fn resources(packet: &[u8]) -> impl Iterator<Item = Resource<'_>> {
+    let (mut pos, records) = skip_questions(packet).unwrap_or((packet.len(), 0));
+
+    (0..records).map_while(move |_| {
+        let mut name = Name::new();
+        let end = read_name(packet, pos, &mut name)?;
+        let rtype = read_u16(packet, end)?;
+        let len = read_u16(packet, end + 8)? as usize;
+        let data_pos = end + 10;
+        let data = packet.get(data_pos..data_pos + len)?;
+        pos = data_pos + len;
+        Some(Resource {
+            name,
+            rtype,
+            data_pos,
+            data,
+        })
+    })
+}
+
+/// Address of `host` (e.g. `broker.local`) in a response
+pub fn find_address(response: &[u8], host: &str) -> Option<Ipv4Addr> {
+    resources(response)
+        .filter(|record| record.rtype == TYPE_A && name_is(&record.name, &[host]))
+        .find_map(|record| Some(Ipv4Addr::from(<[u8; 4]>::try_from(record.data).ok()?)))
+}
+
+/// A service instance found through DNS-SD
+#[derive(Debug, PartialEq, Eq)]
+pub struct Instance {
+    /// Target host, e.g. `broker.local`
+    pub host: Name,
+    pub port: u16,
+    /// Present when the responder included the address record
+    pub ip: Option<Ipv4Addr>,
+}
+
+/// First instance of `service` (e.g. `_mqtt._tcp.local`) in a response
+pub fn find_instance(response: &[u8], service: &str) -> Option<Instance> {
+    let (host, port) = resources(response)
+        .filter(|record| record.rtype == TYPE_SRV && is_instance_of(&record.name, service))
+        .find_map(|record| {
+            let port = read_u16(record.data, 4)?;
+            let mut host = Name::new();
+            read_name(response, record.data_pos + 6, &mut host)?;
+            Some((host, port))
+        })?;
+    let ip = find_address(response, &host);
+    Some(Instance { host, port, ip })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVICES: [Service; 2] = [
+        Service {
+            name: "_water._tcp",
+            port: 80,
+            txt: &["id=a0b1c2d3e4f5"],
+        },
+        Service {
+            name: "_http._tcp",
+            port: 80,
+            txt: &[],
+        },
+    ];
+    const HOST: Host = Host {
+        name: "water-a0b1c2d3e4f5",
+        ip: Ipv4Addr::new(192, 168, 1, 20),
+        services: &SERVICES,
+    };
+
+    #[test]
+    fn names() {
+        let mut out = [0u8; 64];
+        let len = query("Water-A0B1C2D3E4F5.local", TYPE_A, &mut out).unwrap();
+        let mut name = Name::new();
+        assert_eq!(read_name(&out, HEADER_LEN, &mut name), Some(len - 4));
+        assert!(name_is(&name, &[HOST.name, "local"]));
+        assert!(!name_is("water.local", &[HOST.name, "local"]));
+        assert!(is_instance_of(
+            "Broker._mqtt._tcp.local",
+            "_mqtt._tcp.local"
+        ));
+        assert!(!is_instance_of("_mqtt._tcp.local", "_mqtt._tcp.local"));
+
+        // Pointer loops end instead of hanging
+        let mut looped = [0u8; HEADER_LEN + 2];
+        looped[HEADER_LEN..].copy_from_slice(&[0xc0, HEADER_LEN as u8]);
+        assert_eq!(read_name(&looped, HEADER_LEN, &mut name), None);
+    }
+
+    #[test]
+    fn answers_host_query() {
+        let mut query_buf = [0u8; 64];
+        let len = query("water-a0b1c2d3e4f5.local", TYPE_A, &mut query_buf).unwrap();
+        let mut out = [0u8; 512];
+        let reply = HOST.respond(&query_buf[..len], false, &mut out).unwrap();
+        assert!(reply.unicast);
+        let response = &out[..reply.len];
+        assert_eq!(read_u16(response, 6), Some(1));
+        assert_eq!(
+            find_address(response, "water-a0b1c2d3e4f5.local"),
+            Some(HOST.ip)
+        );
+
+        let len = query("other.local", TYPE_A, &mut query_buf).unwrap();
+        assert_eq!(HOST.respond(&query_buf[..len], false, &mut out), None);
+    }
+
+    #[test]
+    fn answers_legacy_resolver() {
+        // A plain DNS query for the host, the name compressed against itself
+        let mut query_buf = std::vec![0x12, 0x34, 0x01, 0, 0, 2, 0, 0, 0, 0, 0, 0];
+        query_buf.extend_from_slice(b"\x12water-a0b1c2d3e4f5\x05local\x00\x00\x01\x00\x01");
+        query_buf.extend_from_slice(&[0xc0, HEADER_LEN as u8, 0, 16, 0, 1]);
+        let questions = &query_buf[HEADER_LEN..];
+
+        // Recursion desired is no opcode, the reply still goes out
+        let mut out = [0u8; 512];
+        let reply = HOST.respond(&query_buf, true, &mut out).unwrap();
+        assert!(reply.unicast);
+        let response = &out[..reply.len];
+        assert_eq!(read_u16(response, 0), Some(0x1234));
+        assert_eq!(read_u16(response, 4), Some(2));
+        assert_eq!(
+            &response[HEADER_LEN..HEADER_LEN + questions.len()],
+            questions
+        );
+        assert_eq!(
+            find_address(response, "water-a0b1c2d3e4f5.local"),
+            Some(HOST.ip)
+        );
+        // No cache flush bit and a short TTL
+        let end = HEADER_LEN + questions.len() + 1 + 18 + 1 + 5 + 1;
+        assert_eq!(read_u16(response, end + 2), Some(CLASS_IN));
+        assert_eq!(read_u16(response, end + 6), Some(LEGACY_TTL as u16));
+
+        // Multicast queriers get the plain mDNS form
+        let mut query_buf = [0u8; 64];
+        let len = query("water-a0b1c2d3e4f5.local", TYPE_A, &mut query_buf).unwrap();
+        let reply = HOST.respond(&query_buf[..len], false, &mut out).unwrap();
+        assert_eq!(read_u16(&out, 0), Some(0));
+        assert_eq!(read_u16(&out, 4), Some(0));
+        assert_eq!(reply.len, HEADER_LEN + 1 + 18 + 1 + 5 + 1 + 10 + 4);
+    }
+
+    #[test]
+    fn browses_own_service() {
+        let mut query_buf = [0u8; 64];
+        let len = query("_water._tcp.local", TYPE_PTR, &mut query_buf).unwrap();
+        let mut out = [0u8; 512];
+        let reply = HOST.respond(&query_buf[..len], false, &mut out).unwrap();
+        let response = &out[..reply.len];
+        // PTR answer, then SRV, TXT and A as additional records
+        assert_eq!(read_u16(response, 6), Some(1));
+        assert_eq!(read_u16(response, 10), Some(3));
+
+        let instance = find_instance(response, "_water._tcp.local").unwrap();
+        assert_eq!(instance.host.as_str(), "water-a0b1c2d3e4f5.local");
+        assert_eq!(instance.port, 80);
+        assert_eq!(instance.ip, Some(HOST.ip));
+        let txt = resources(response)
+            .find(|record| record.rtype == TYPE_TXT)
+            .unwrap();
+        assert_eq!(txt.data, b"\x0fid=a0b1c2d3e4f5");
+    }
+
+    #[test]
+    fn announces_everything() {
+        let mut out = [0u8; 1024];
+        let len = HOST.announce(&mut out).unwrap();
+        assert_eq!(resources(&out[..len]).count(), 1 + 4 * SERVICES.len());
+        assert!(find_instance(&out[..len], "_http._tcp.local").is_some());
+        assert_eq!(HOST.announce(&mut out[..64]), None);
+    }
+
+    #[test]
+    fn compressed_broker_response() {
+        // Answer to a PTR query for _mqtt._tcp.local as sent by Avahi
+        let mut packet = std::vec::Vec::new();
+        packet.extend_from_slice(&[0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 2]);
+        // PTR _mqtt._tcp.local -> Broker._mqtt._tcp.local
+        let service_pos = packet.len();
+        packet.extend_from_slice(b"\x05_mqtt\x04_tcp\x05local\x00");
+        packet.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0x11, 0x94, 0, 9]);
+        let instance_pos = packet.len();
+        packet.extend_from_slice(b"\x06Broker\xc0");
+        packet.push(service_pos as u8);
+        // SRV Broker._mqtt._tcp.local -> pi.local:1883
+        packet.extend_from_slice(&[0xc0, instance_pos as u8, 0, 33, 0x80, 1, 0, 0, 0, 120]);
+        packet.extend_from_slice(&[0, 11, 0, 0, 0, 0, 0x07, 0x5b]);
+        let host_pos = packet.len();
+        packet.extend_from_slice(b"\x02pi\xc0");
+        packet.push((service_pos + 11) as u8);
+        // A pi.local
+        packet.extend_from_slice(&[0xc0, host_pos as u8, 0, 1, 0x80, 1, 0, 0, 0, 120]);
+        packet.extend_from_slice(&[0, 4, 10, 0, 0, 2]);
+
+        let instance = find_instance(&packet, "_mqtt._tcp.local").unwrap();
+        assert_eq!(instance.host.as_str(), "pi.local");
+        assert_eq!(instance.port, 1883);
+        assert_eq!(instance.ip, Some(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(find_instance(&packet, "_http._tcp.local"), None);
+    }
+}
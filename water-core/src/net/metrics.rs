@@ -0,0 +1,207 @@
+//! Prometheus text exposition of the telemetry, served as `GET /metrics`
+
+use core::fmt::{Result, Write};
+use heapless::Vec;
+
+use crate::watering::BOARD_ZONES;
+
+/// Response size the rendered metrics must fit into
+pub const METRICS_LEN: usize = 2560;
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+pub struct ZoneMetrics {
+    pub humidity: u32,
+    pub humidity_raw: u16,
+    pub pump_runtime_secs: u64,
+    pub cycles: u32,
+}
+
+pub struct Metrics {
+    pub zones: Vec<ZoneMetrics, BOARD_ZONES>,
+    pub charge: u32,
+    pub charge_raw: u16,
+    /// Absent until the first ping went through
+    pub latency_ms: Option<u64>,
+    pub wifi_reconnects: u32,
+    /// Absent until the first NTP sync
+    pub ntp_sync_age_secs: Option<u64>,
+    pub watchdog_enabled: bool,
+    pub watchdog_feeds: u32,
+    /// Subsystem label and whether it is healthy
+    pub health: [(&'static str, bool); 4],
+}
+
+fn header(out: &mut impl Write, name: &str, kind: &str, help: &str) -> Result {
+    write!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n")
+}
+
+/// One sample per zone, labelled with its index
+fn per_zone<T: core::fmt::Display>(
+    out: &mut impl Write,
+    name: &str,
+    zones: &[ZoneMetrics],
+    value: impl Fn(&ZoneMetrics) -> T,
+) -> Result {
+    zones.iter().enumerate().try_for_each(|(zone, metrics)| {
+        writeln!(out, "{name}{{zone=\"{zone}\"}} {}", value(metrics))
+    })
+}
+
+impl Metrics {
+    pub fn render(&self, out: &mut impl Write) -> Result {
+        let zones = self.zones.as_slice();
+
+        header(out, "water_humidity_percent", "gauge", "Soil humidity")?;
+        per_zone(out, "water_humidity_percent", zones, |zone| zone.humidity)?;
+        header(
+            out,
+            "water_humidity_raw",
+            "gauge",
+            "Raw humidity sensor reading",
+        )?;
+        per_zone(out, "water_humidity_raw", zones, |zone| zone.humidity_raw)?;
+        header(
+            out,
+            "water_pump_runtime_seconds_total",
+            "counter",
+            "Time the pump ran since boot",
+        )?;
+        per_zone(out, "water_pump_runtime_seconds_total", zones, |zone| {
+            zone.pump_runtime_secs
+        })?;
+        header(
+            out,
+            "water_watering_cycles_total",
+            "counter",
+            "Pump starts since boot",
+        )?;
+        per_zone(out, "water_watering_cycles_total", zones, |zone| {
+            zone.cycles
+        })?;
+
+        header(
+            out,
+            "water_battery_charge_percent",
+            "gauge",
+            "Battery charge",
+        )?;
+        writeln!(out, "water_battery_charge_percent {}", self.charge)?;
+        header(out, "water_battery_raw", "gauge", "Raw battery reading")?;
+        writeln!(out, "water_battery_raw {}", self.charge_raw)?;
+
+        if let Some(ms) = self.latency_ms {
+            header(
+                out,
+                "water_ping_latency_seconds",
+                "gauge",
+                "Round trip time to the gateway",
+            )?;
+            writeln!(
+                out,
+                "water_ping_latency_seconds {}.{:03}",
+                ms / 1000,
+                ms % 1000
+            )?;
+        }
+
+        header(
+            out,
+            "water_wifi_reconnects_total",
+            "counter",
+            "WiFi connections after the first one",
+        )?;
+        writeln!(out, "water_wifi_reconnects_total {}", self.wifi_reconnects)?;
+
+        if let Some(secs) = self.ntp_sync_age_secs {
+            header(
+                out,
+                "water_ntp_sync_age_seconds",
+                "gauge",
+                "Time since the last successful NTP sync",
+            )?;
+            writeln!(out, "water_ntp_sync_age_seconds {secs}")?;
+        }
+
+        header(out, "water_watchdog_enabled", "gauge", "Watchdog armed")?;
+        writeln!(
+            out,
+            "water_watchdog_enabled {}",
+            self.watchdog_enabled as u8
+        )?;
+        header(
+            out,
+            "water_watchdog_feeds_total",
+            "counter",
+            "Watchdog feeds since boot",
+        )?;
+        writeln!(out, "water_watchdog_feeds_total {}", self.watchdog_feeds)?;
+
+        header(
+            out,
+            "water_subsystem_healthy",
+            "gauge",
+            "Subsystem heartbeat within its timeout",
+        )?;
+        self.health.iter().try_for_each(|(subsystem, healthy)| {
+            writeln!(
+                out,
+                "water_subsystem_healthy{{subsystem=\"{subsystem}\"}} {}",
+                *healthy as u8
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::String;
+
+    fn zone(humidity: u32) -> ZoneMetrics {
+        ZoneMetrics {
+            humidity,
+            humidity_raw: 2048,
+            pump_runtime_secs: 95,
+            cycles: 3,
+        }
+    }
+
+    #[test]
+    fn exposition() {
+        let mut zones = Vec::new();
+        for humidity in [40, 55].into_iter().take(BOARD_ZONES) {
+            zones.push(zone(humidity)).ok();
+        }
+        let metrics = Metrics {
+            zones,
+            charge: 80,
+            charge_raw: 3100,
+            latency_ms: Some(1042),
+            wifi_reconnects: 2,
+            ntp_sync_age_secs: None,
+            watchdog_enabled: true,
+            watchdog_feeds: 1234,
+            health: [
+                ("wifi", true),
+                ("mqtt", false),
+                ("display", true),
+                ("adc", true),
+            ],
+        };
+        let mut out: String<METRICS_LEN> = String::new();
+        metrics.render(&mut out).unwrap();
+
+        assert!(out.contains("# TYPE water_humidity_percent gauge\n"));
+        assert!(out.contains("water_humidity_percent{zone=\"1\"} 55\n"));
+        assert!(out.contains("water_pump_runtime_seconds_total{zone=\"0\"} 95\n"));
+        assert!(out.contains("water_ping_latency_seconds 1.042\n"));
+        assert!(out.contains("water_subsystem_healthy{subsystem=\"mqtt\"} 0\n"));
+        assert!(!out.contains("ntp"));
+        // Every sample follows its own HELP and TYPE lines
+        assert!(
+            out.lines()
+                .all(|line| line.starts_with("# ") || line.starts_with("water_"))
+        );
+        assert!(out.ends_with('\n'));
+    }
+}
@@ -0,0 +1,11 @@
+pub mod api;
+pub mod connection;
+pub mod dhcp;
+pub mod dns;
+pub mod homeassistant;
+pub mod http;
+pub mod ipconfig;
+pub mod mdns;
+pub mod metrics;
+pub mod sntp;
+pub mod topics;
@@ -0,0 +1,221 @@
+//! SNTP packets and the choice among samples (RFC 4330)
+//!
+//! Times are microseconds since the Unix epoch, local ones are read from
+//! the RTC right before sending and right after receiving.
+
+use core::net::IpAddr;
+use heapless::Vec;
+use jiff::Timestamp;
+use serde::Serialize;
+
+pub const PORT: u16 = 123;
+pub const PACKET_LEN: usize = 48;
+pub const NTP_HOST_LEN: usize = 64;
+/// Configured server names
+pub const MAX_SERVERS: usize = 3;
+/// Servers asked per sync
+pub const MAX_SAMPLES: usize = 4;
+/// Longer round trips say little about the offset
+pub const MAX_DELAY_US: i64 = 1_000_000;
+/// Samples further from the median offset are false tickers
+pub const MAX_SPREAD_US: i64 = 250_000;
+
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+const MAX_STRATUM: u8 = 15;
+// Seconds from 1900 to 1970
+const UNIX_OFFSET: u64 = 2_208_988_800;
+const ERA_SECS: u64 = 1 << 32;
+
+fn to_ntp(micros: u64) -> [u8; 8] {
+    let secs = (micros / 1_000_000 + UNIX_OFFSET) % ERA_SECS;
+    // Rounded up to read back the same microsecond
+    let fraction = ((micros % 1_000_000) << 32).div_ceil(1_000_000);
+    (secs << 32 | fraction).to_be_bytes()
+}
+
+fn from_ntp(bytes: &[u8]) -> Option<i64> {
+    let value = u64::from_be_bytes(bytes.try_into().ok()?);
+    let mut secs = value >> 32;
+    // The era wrapping in 2036 starts over with the top bit clear
+    if secs < ERA_SECS / 2 {
+        secs += ERA_SECS;
+    }
+    let micros = ((value & 0xffff_ffff) * 1_000_000) >> 32;
+    Some(((secs - UNIX_OFFSET) * 1_000_000 + micros) as i64)
+}
+
+/// Build a client request sent at `transmit`
+pub fn request(transmit: u64, out: &mut [u8; PACKET_LEN]) {
+    out.fill(0);
+    out[0] = VERSION << 3 | MODE_CLIENT;
+    out[40..48].copy_from_slice(&to_ntp(transmit));
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Sample {
+    /// Correction for the local clock
+    pub offset_us: i64,
+    /// Round trip without the time the server spent
+    pub delay_us: i64,
+    pub stratum: u8,
+}
+
+/// Check the reply to a request sent at `sent` and received at `received`
+///
+/// Replies of unsynchronized servers, kiss-o'-death packets and answers to
+/// other requests are `None`.
+pub fn parse(packet: &[u8], sent: u64, received: u64) -> Option<Sample> {
+    let packet = packet.get(..PACKET_LEN)?;
+    let leap = packet[0] >> 6;
+    let mode = packet[0] & 0x07;
+    let stratum = packet[1];
+    if mode != MODE_SERVER
+        || leap == LEAP_UNSYNCHRONIZED
+        || !(1..=MAX_STRATUM).contains(&stratum)
+        || packet[24..32] != to_ntp(sent)
+        || packet[40..48].iter().all(|byte| *byte == 0)
+    {
+        return None;
+    }
+
+    let receive = from_ntp(&packet[32..40])?;
+    let transmit = from_ntp(&packet[40..48])?;
+    let (sent, received) = (sent as i64, received as i64);
+    Some(Sample {
+        offset_us: ((receive - sent) + (transmit - received)) / 2,
+        // Rounding on either side can make a quick exchange look negative
+        delay_us: ((received - sent) - (transmit - receive)).max(0),
+        stratum,
+    })
+}
+
+/// Index of the most trustworthy sample
+///
+/// Slow samples are dropped, then the ones far from the median offset. The
+/// rest has to be a majority, of those the quickest round trip wins.
+pub fn select(samples: &[Sample]) -> Option<usize> {
+    let mut plausible: Vec<(usize, Sample), MAX_SAMPLES> = samples
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|(_, sample)| sample.delay_us <= MAX_DELAY_US)
+        .take(MAX_SAMPLES)
+        .collect();
+    plausible.sort_unstable_by_key(|(_, sample)| sample.offset_us);
+    let (_, median) = *plausible.get(plausible.len() / 2)?;
+
+    let agreeing = plausible
+        .iter()
+        .filter(|(_, sample)| sample.offset_us.abs_diff(median.offset_us) <= MAX_SPREAD_US as u64);
+    if agreeing.clone().count() * 2 <= plausible.len() {
+        return None;
+    }
+    agreeing
+        .min_by_key(|(_, sample)| sample.delay_us)
+        .map(|(index, _)| *index)
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncStats {
+    /// When the clock was last set
+    pub last_sync: Option<Timestamp>,
+    pub server: Option<IpAddr>,
+    /// Correction applied by the last sync
+    pub offset_us: i64,
+    pub rtt_us: i64,
+    pub stratum: u8,
+    /// Syncs without a usable sample since boot
+    pub failures: u32,
+}
+
+impl SyncStats {
+    pub const fn new() -> Self {
+        SyncStats {
+            last_sync: None,
+            server: None,
+            offset_us: 0,
+            rtt_us: 0,
+            stratum: 0,
+            failures: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2026-01-01T00:00:00Z
+    const NOW: u64 = 1_767_225_600_000_000;
+
+    fn reply(sent: u64, receive: u64, transmit: u64, stratum: u8) -> [u8; PACKET_LEN] {
+        let mut packet = [0u8; PACKET_LEN];
+        packet[0] = VERSION << 3 | MODE_SERVER;
+        packet[1] = stratum;
+        packet[24..32].copy_from_slice(&to_ntp(sent));
+        packet[32..40].copy_from_slice(&to_ntp(receive));
+        packet[40..48].copy_from_slice(&to_ntp(transmit));
+        packet
+    }
+
+    fn sample(offset_ms: i64, delay_ms: i64) -> Sample {
+        Sample {
+            offset_us: offset_ms * 1000,
+            delay_us: delay_ms * 1000,
+            stratum: 2,
+        }
+    }
+
+    #[test]
+    fn offset_from_round_trip() {
+        let mut packet = [0u8; PACKET_LEN];
+        request(NOW, &mut packet);
+        assert_eq!(packet[0], 0x23);
+        assert_eq!(from_ntp(&packet[40..48]), Some(NOW as i64));
+
+        // Local clock 1.5 s behind, 20 ms each way, 2 ms at the server
+        let server = NOW + 1_500_000 + 20_000;
+        let packet = reply(NOW, server, server + 2_000, 2);
+        let sample = parse(&packet, NOW, NOW + 42_000).unwrap();
+        assert_eq!(sample.offset_us, 1_500_000);
+        assert_eq!(sample.delay_us, 40_000);
+        assert_eq!(sample.stratum, 2);
+
+        // Another request, kiss-o'-death and unsynchronized servers
+        assert_eq!(parse(&packet, NOW + 1, NOW + 42_000), None);
+        assert_eq!(parse(&reply(NOW, server, server, 0), NOW, NOW), None);
+        let mut unsynchronized = packet;
+        unsynchronized[0] |= LEAP_UNSYNCHRONIZED << 6;
+        assert_eq!(parse(&unsynchronized, NOW, NOW), None);
+        assert_eq!(parse(&packet[..40], NOW, NOW), None);
+    }
+
+    #[test]
+    fn survives_the_era_rollover() {
+        // 2040-01-01T00:00:00Z, past 2036-02-07
+        let later = 2_208_988_800_000_000;
+        assert_eq!(from_ntp(&to_ntp(later)), Some(later as i64));
+        // An RTC which never got set
+        assert_eq!(from_ntp(&to_ntp(0)), Some(0));
+    }
+
+    #[test]
+    fn selects_among_agreeing_samples() {
+        let samples = [
+            sample(10, 30),
+            sample(12, 8),
+            sample(900, 5),
+            sample(11, 20),
+        ];
+        assert_eq!(select(&samples), Some(1));
+        // Too slow to count
+        let samples = [sample(10, 30), sample(0, 1500)];
+        assert_eq!(select(&samples), Some(0));
+        // No majority
+        assert_eq!(select(&[sample(10, 30), sample(900, 5)]), None);
+        assert_eq!(select(&[]), None);
+    }
+}
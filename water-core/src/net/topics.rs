@@ -0,0 +1,56 @@
+//! MQTT topic layout, `<prefix>/<device id>/<leaf>`
+//!
+//! Every unit gets its own subtree, while `<prefix>/all/control` reaches the
+//! whole fleet. Device ids are hex so they never clash with `all`.
+
+use core::fmt::Write;
+use heapless::String;
+
+pub const DEFAULT_PREFIX: &str = "water";
+pub const PREFIX_LEN: usize = 32;
+/// MAC address in hex
+pub const DEVICE_ID_LEN: usize = 12;
+pub const TOPIC_LEN: usize = PREFIX_LEN + DEVICE_ID_LEN + 16;
+
+pub const BROADCAST_ID: &str = "all";
+
+pub struct Topics {
+    pub status: String<TOPIC_LEN>,
+    pub control: String<TOPIC_LEN>,
+    /// rust-mqtt doesn't expose the v5 response topic, so results go to a fixed one
+    pub ack: String<TOPIC_LEN>,
+    /// Retained `online`, replaced by the broker with `offline` once we vanish
+    pub availability: String<TOPIC_LEN>,
+    /// Control topic shared by all units under the prefix
+    pub broadcast: String<TOPIC_LEN>,
+    /// Samples taken while offline, oldest first
+    pub history: String<TOPIC_LEN>,
+    pub log: String<TOPIC_LEN>,
+}
+
+fn topic(prefix: &str, id: &str, leaf: &str) -> String<TOPIC_LEN> {
+    let mut topic = String::new();
+    write!(topic, "{}/{}/{}", prefix, id, leaf).ok();
+    topic
+}
+
+impl Topics {
+    pub fn new(prefix: &str, device_id: &str) -> Self {
+        Topics {
+            status: topic(prefix, device_id, "status"),
+            control: topic(prefix, device_id, "control"),
+            ack: topic(prefix, device_id, "control/ack"),
+            availability: topic(prefix, device_id, "availability"),
+            broadcast: topic(prefix, BROADCAST_ID, "control"),
+            history: topic(prefix, device_id, "history"),
+            log: topic(prefix, device_id, "log"),
+        }
+    }
+}
+
+/// One or more non-empty levels without wildcards
+pub fn is_valid_prefix(prefix: &str) -> bool {
+    prefix.len() <= PREFIX_LEN
+        && prefix.split('/').all(|level| !level.is_empty())
+        && !prefix.contains(['+', '#', '\0'])
+}
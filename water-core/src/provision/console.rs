@@ -0,0 +1,244 @@
+//! Line-oriented provisioning console
+//!
+//! Arguments are separated by spaces, double quotes keep spaces inside a
+//! single argument: `wifi "My network" secret`.
+
+use heapless::Vec;
+
+use crate::command::auth::{CommandKey, parse_hex};
+use crate::net::ipconfig::{StaticIp, is_valid_hostname};
+use crate::net::sntp::MAX_SERVERS;
+use crate::net::topics::is_valid_prefix;
+
+pub const MAX_LINE_LEN: usize = 160;
+const MAX_ARGS: usize = 7;
+// Above the network stored with `wifi`
+const DEFAULT_NETWORK_PRIORITY: u8 = 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConsoleCommand<'a> {
+    Help,
+    Show,
+    Reboot,
+    /// Drop the station and bring up the setup access point
+    Portal,
+    Wifi {
+        ssid: &'a str,
+        password: &'a str,
+    },
+    /// Another network to pick from, preferred by higher priority
+    AddNetwork {
+        ssid: &'a str,
+        password: &'a str,
+        priority: u8,
+    },
+    RemoveNetwork(&'a str),
+    Mqtt {
+        user: &'a str,
+        password: &'a str,
+    },
+    /// `None` host finds the broker through mDNS
+    Broker {
+        host: Option<&'a str>,
+        tls: bool,
+    },
+    /// First levels of all MQTT topics
+    Prefix(&'a str),
+    /// Require commands signed with this key, `None` accepts unsigned ones
+    CommandKey(Option<CommandKey>),
+    /// Static or fallback address, `None` for plain DHCP
+    Ip(Option<StaticIp>),
+    /// Seconds to wait for a DHCP lease, `0` waits forever
+    DhcpTimeout(u32),
+    /// `None` restores `water-<id>`
+    Hostname(Option<&'a str>),
+    /// NTP server names or addresses, `None` restores `pool.ntp.org`
+    NtpServers(Option<Vec<&'a str, MAX_SERVERS>>),
+    /// Forget all stored credentials
+    Forget,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    UnknownCommand,
+    BadArguments,
+    UnterminatedQuote,
+}
+
+pub const HELP: &str = "Commands:
+  wifi <ssid> <password>  store WiFi credentials
+  network add <ssid> <password> [priority]
+                          store another WiFi network, priority 1 by
+                          default, the `wifi` one has 0
+  network del <ssid>      drop a stored WiFi network
+  mqtt <user> <password>  store MQTT credentials
+  broker <host> [tls]     store MQTT broker, `tls` uses port 8883
+                          `auto` looks it up through mDNS
+  prefix <topic>          store MQTT topic prefix, `water` by default
+  cmdkey <hex>|off        require commands signed with a 32 byte key
+  ip dhcp                 get the address through DHCP
+  ip static <a.b.c.d/n> [gateway] [dns...]
+                          use a fixed address instead of DHCP
+  ip fallback <a.b.c.d/n> [gateway] [dns...]
+                          use a fixed address if DHCP times out
+  ip timeout <secs>       wait for DHCP, 0 forever, then fall back to
+                          the fixed or a link-local address
+  hostname <name>|default store the DHCP and mDNS host name
+  ntp <server>...|default store up to 3 NTP servers, asked after the
+                          one offered by DHCP
+  show                    print stored settings
+  forget                  drop stored credentials
+  portal                  open the setup access point
+  reboot                  restart to apply changes";
+
+fn split_args(line: &str) -> Result<Vec<&str, MAX_ARGS>, ParseError> {
+    let mut args = Vec::new();
+    let mut rest = line.trim();
+
+    while !rest.is_empty() {
+        let (arg, tail) = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').ok_or(ParseError::UnterminatedQuote)?;
+            (&quoted[..end], &quoted[end + 1..])
+        } else {
+            rest.split_once(' ').unwrap_or((rest, ""))
+        };
+        args.push(arg).map_err(|_| ParseError::BadArguments)?;
+        rest = tail.trim_start();
+    }
+
+    Ok(args)
+}
+
+fn broker_host(host: &str) -> Option<&str> {
+    (host != "auto").then_some(host)
+}
+
+pub fn parse_line(line: &str) -> Result<ConsoleCommand<'_>, ParseError> {
+    let args = split_args(line)?;
+
+    match args.as_slice() {
+        [] => Err(ParseError::Empty),
+        ["help"] | ["?"] => Ok(ConsoleCommand::Help),
+        ["show"] => Ok(ConsoleCommand::Show),
+        ["reboot"] => Ok(ConsoleCommand::Reboot),
+        ["portal"] => Ok(ConsoleCommand::Portal),
+        ["forget"] => Ok(ConsoleCommand::Forget),
+        ["wifi", ssid, password] if !ssid.is_empty() => Ok(ConsoleCommand::Wifi { ssid, password }),
+        // Open networks have no password
+        ["wifi", ssid] if !ssid.is_empty() => Ok(ConsoleCommand::Wifi { ssid, password: "" }),
+        ["network", "add", ssid, password, rest @ ..] if !ssid.is_empty() => {
+            let priority = match rest {
+                [] => DEFAULT_NETWORK_PRIORITY,
+                [priority] => priority.parse().map_err(|_| ParseError::BadArguments)?,
+                _ => return Err(ParseError::BadArguments),
+            };
+            Ok(ConsoleCommand::AddNetwork {
+                ssid,
+                password,
+                priority,
+            })
+        }
+        ["network", "del", ssid] => Ok(ConsoleCommand::RemoveNetwork(ssid)),
+        ["mqtt", user, password] => Ok(ConsoleCommand::Mqtt { user, password }),
+        ["broker", host] if !host.is_empty() => Ok(ConsoleCommand::Broker {
+            host: broker_host(host),
+            tls: false,
+        }),
+        ["broker", host, "tls"] if !host.is_empty() => Ok(ConsoleCommand::Broker {
+            host: broker_host(host),
+            tls: true,
+        }),
+        ["prefix", prefix] if is_valid_prefix(prefix) => Ok(ConsoleCommand::Prefix(prefix)),
+        ["cmdkey", "off"] => Ok(ConsoleCommand::CommandKey(None)),
+        ["cmdkey", key] => parse_hex(key)
+            .map(|key| ConsoleCommand::CommandKey(Some(key)))
+            .ok_or(ParseError::BadArguments),
+        ["ip", "dhcp"] => Ok(ConsoleCommand::Ip(None)),
+        ["ip", mode @ ("static" | "fallback"), cidr, rest @ ..] => {
+            StaticIp::parse(cidr, rest, *mode == "fallback")
+                .map(|ip| ConsoleCommand::Ip(Some(ip)))
+                .ok_or(ParseError::BadArguments)
+        }
+        ["ip", "timeout", secs] => secs
+            .parse()
+            .map(ConsoleCommand::DhcpTimeout)
+            .map_err(|_| ParseError::BadArguments),
+        ["hostname", "default"] => Ok(ConsoleCommand::Hostname(None)),
+        ["hostname", name] if is_valid_hostname(name) => Ok(ConsoleCommand::Hostname(Some(name))),
+        ["ntp", "default"] => Ok(ConsoleCommand::NtpServers(None)),
+        ["ntp", servers @ ..]
+            if !servers.is_empty() && servers.iter().all(|server| !server.is_empty()) =>
+        {
+            Vec::from_slice(servers)
+                .map(|servers| ConsoleCommand::NtpServers(Some(servers)))
+                .map_err(|_| ParseError::BadArguments)
+        }
+        ["wifi", ..]
+        | ["network", ..]
+        | ["mqtt", ..]
+        | ["broker", ..]
+        | ["prefix", ..]
+        | ["cmdkey", ..]
+        | ["ip", ..]
+        | ["hostname", ..]
+        | ["ntp", ..] => Err(ParseError::BadArguments),
+        _ => Err(ParseError::UnknownCommand),
+    }
+}
+
+/// Collects bytes from the UART into lines
+pub struct LineBuffer {
+    buf: Vec<u8, MAX_LINE_LEN>,
+    complete: bool,
+    overflow: bool,
+}
+
+impl Default for LineBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineBuffer {
+    pub const fn new() -> Self {
+        LineBuffer {
+            buf: Vec::new(),
+            complete: false,
+            overflow: false,
+        }
+    }
+
+    /// Returns the line once a line terminator is received
+    ///
+    /// Overlong lines and lines which are not valid UTF-8 are dropped.
+    pub fn push(&mut self, byte: u8) -> Option<&str> {
+        if self.complete {
+            self.buf.clear();
+            self.complete = false;
+        }
+
+        match byte {
+            b'\r' | b'\n' => {
+                let overflow = core::mem::take(&mut self.overflow);
+                if self.buf.is_empty() || overflow {
+                    self.buf.clear();
+                    return None;
+                }
+                self.complete = true;
+                core::str::from_utf8(&self.buf).ok()
+            }
+            // Backspace and DEL
+            0x08 | 0x7f => {
+                self.buf.pop();
+                None
+            }
+            _ => {
+                if self.buf.push(byte).is_err() {
+                    self.overflow = true;
+                }
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Provisioning form submitted from the setup portal
+
+use heapless::String;
+use jiff::tz::TimeZone;
+
+use crate::net::http::{ParseError, form_pairs, url_decode};
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ProvisioningForm {
+    pub ssid: String<32>,
+    pub password: String<64>,
+    /// Empty keeps the current broker
+    pub mqtt_host: String<64>,
+    pub mqtt_user: String<32>,
+    pub mqtt_password: String<64>,
+    /// POSIX TZ string, empty keeps the current time zone
+    pub timezone: String<48>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FormError {
+    Malformed,
+    FieldTooLong,
+    MissingSsid,
+    /// WPA2 passphrases are 8 to 63 characters, empty for open networks
+    BadPassword,
+    BadHost,
+    BadTimezone,
+}
+
+impl FormError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            FormError::Malformed => "Malformed form",
+            FormError::FieldTooLong => "A field is too long",
+            FormError::MissingSsid => "SSID is required",
+            FormError::BadPassword => "WiFi password must be 8 to 63 characters or empty",
+            FormError::BadHost => "MQTT host must be a host name or an IPv4 address",
+            FormError::BadTimezone => "Time zone must be a POSIX TZ string like JST-9",
+        }
+    }
+}
+
+impl From<ParseError> for FormError {
+    fn from(err: ParseError) -> Self {
+        match err {
+            ParseError::TooLarge => FormError::FieldTooLong,
+            _ => FormError::Malformed,
+        }
+    }
+}
+
+fn valid_host(host: &str) -> bool {
+    host.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    })
+}
+
+pub fn parse_form(body: &[u8]) -> Result<ProvisioningForm, FormError> {
+    let body = core::str::from_utf8(body).map_err(|_| FormError::Malformed)?;
+    let mut form = ProvisioningForm::default();
+
+    for (key, value) in form_pairs(body) {
+        match key {
+            "ssid" => url_decode(value, &mut form.ssid)?,
+            "password" => url_decode(value, &mut form.password)?,
+            "mqtt_host" => url_decode(value, &mut form.mqtt_host)?,
+            "mqtt_user" => url_decode(value, &mut form.mqtt_user)?,
+            "mqtt_password" => url_decode(value, &mut form.mqtt_password)?,
+            "tz" => url_decode(value, &mut form.timezone)?,
+            // Unknown fields are ignored so the page may grow
+            _ => {}
+        }
+    }
+
+    // Browsers love to add stray spaces when autocompleting
+    form.mqtt_host = form
+        .mqtt_host
+        .trim()
+        .try_into()
+        .map_err(|_| FormError::Malformed)?;
+    form.timezone = form
+        .timezone
+        .trim()
+        .try_into()
+        .map_err(|_| FormError::Malformed)?;
+
+    if form.ssid.is_empty() {
+        return Err(FormError::MissingSsid);
+    }
+    if !form.password.is_empty() && !(8..=63).contains(&form.password.len()) {
+        return Err(FormError::BadPassword);
+    }
+    if !form.mqtt_host.is_empty() && !valid_host(&form.mqtt_host) {
+        return Err(FormError::BadHost);
+    }
+    if !form.timezone.is_empty() && TimeZone::posix(&form.timezone).is_err() {
+        return Err(FormError::BadTimezone);
+    }
+
+    Ok(form)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_fields() {
+        let form = parse_form(
+            b"ssid=My+Net%21&password=12345678&mqtt_host=+broker.local&\
+              mqtt_user=me&mqtt_password=p%26ss&tz=JST-9&unknown=1",
+        )
+        .unwrap();
+        assert_eq!(form.ssid, "My Net!");
+        assert_eq!(form.password, "12345678");
+        assert_eq!(form.mqtt_host, "broker.local");
+        assert_eq!(form.mqtt_user, "me");
+        assert_eq!(form.mqtt_password, "p&ss");
+        assert_eq!(form.timezone, "JST-9");
+
+        // Open networks and the current broker and time zone
+        let form = parse_form(b"ssid=open&password=&mqtt_host=&tz=").unwrap();
+        assert!(form.password.is_empty() && form.mqtt_host.is_empty());
+    }
+
+    #[test]
+    fn rejects_missing_and_oversized_fields() {
+        assert_eq!(
+            parse_form(b"password=12345678"),
+            Err(FormError::MissingSsid)
+        );
+        assert_eq!(parse_form(b"ssid="), Err(FormError::MissingSsid));
+        let long = [b"ssid=".as_slice(), &[b'a'; 33]].concat();
+        assert_eq!(parse_form(&long), Err(FormError::FieldTooLong));
+        // Encoded characters count once decoded
+        let encoded = [b"ssid=".as_slice(), &b"%41".repeat(32)].concat();
+        assert_eq!(parse_form(&encoded).unwrap().ssid.len(), 32);
+        assert_eq!(parse_form(b"ssid=%4"), Err(FormError::Malformed));
+        assert_eq!(parse_form(b"ssid=%ff"), Err(FormError::Malformed));
+        assert_eq!(parse_form(b"ssid=\xff"), Err(FormError::Malformed));
+    }
+
+    #[test]
+    fn validates_fields() {
+        assert_eq!(
+            parse_form(b"ssid=x&password=1234567"),
+            Err(FormError::BadPassword)
+        );
+        let long = [b"ssid=x&password=".as_slice(), &[b'a'; 64]].concat();
+        assert_eq!(parse_form(&long), Err(FormError::BadPassword));
+        assert_eq!(
+            parse_form(b"ssid=x&mqtt_host=broker..local"),
+            Err(FormError::BadHost)
+        );
+        assert_eq!(
+            parse_form(b"ssid=x&mqtt_host=-broker"),
+            Err(FormError::BadHost)
+        );
+        assert_eq!(
+            parse_form(b"ssid=x&mqtt_host=192.168.1.10")
+                .unwrap()
+                .mqtt_host,
+            "192.168.1.10"
+        );
+        assert_eq!(
+            parse_form(b"ssid=x&tz=garbage!!"),
+            Err(FormError::BadTimezone)
+        );
+    }
+}
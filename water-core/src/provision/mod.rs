@@ -0,0 +1,33 @@
+pub mod console;
+pub mod form;
+
+use heapless::String;
+
+/// WPA2 wants at least 8 characters
+pub const PORTAL_PASSWORD_LEN: usize = 10;
+// 32 symbols, without the easily confused 0, 1, l and o
+const PASSWORD_ALPHABET: &[u8; 32] = b"23456789abcdefghijkmnpqrstuvwxyz";
+
+/// Setup access point password made of random bytes, 5 bits each
+pub fn portal_password(random: [u8; PORTAL_PASSWORD_LEN]) -> String<PORTAL_PASSWORD_LEN> {
+    random
+        .iter()
+        .map(|byte| PASSWORD_ALPHABET[(byte % 32) as usize] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn portal_password_is_readable() {
+        let password = portal_password([0, 1, 7, 8, 31, 32, 255, 100, 200, 42]);
+        assert_eq!(password, "239az2z6ac");
+        assert!(
+            portal_password([0x5a; PORTAL_PASSWORD_LEN])
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric())
+        );
+    }
+}
@@ -0,0 +1,2 @@
+/// Longest POSIX TZ string kept
+pub const TZ_LEN: usize = 48;
@@ -0,0 +1,563 @@
+//! Hardware-agnostic watering state machine
+//!
+//! The controller knows nothing about GPIOs, ADCs or timers: it is fed with
+//! sensor samples and the time elapsed since the previous step, and answers
+//! with the desired pump state and the delay until the next step.
+
+use core::ops::RangeInclusive;
+use embassy_time::Duration;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_ON_TIME_SECS: RangeInclusive<u32> = 1..=600;
+pub const HYSTERESIS: RangeInclusive<u32> = 0..=50;
+pub const COOLDOWN_SECS: RangeInclusive<u32> = 0..=3600;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Tunables {
+    /// Hard limit for a single watering cycle
+    pub max_on_time: Duration,
+    /// Polling interval while nothing happens
+    pub poll_idle: Duration,
+    /// Polling interval while the pump is running
+    pub poll_active: Duration,
+    /// Humidity above the limit (percent) required to stop early
+    pub hysteresis: u32,
+    /// Debounce before starting cycle
+    pub consecutive_triggers: u8,
+    /// Debounce before stopping early
+    pub consecutive_clear: u8,
+    /// Pause after each cycle to let the water soak in
+    pub cooldown: Duration,
+}
+
+impl Default for Tunables {
+    fn default() -> Self {
+        Tunables {
+            max_on_time: Duration::from_secs(30),
+            poll_idle: Duration::from_millis(800),
+            poll_active: Duration::from_millis(250),
+            hysteresis: 2,
+            consecutive_triggers: 2,
+            consecutive_clear: 3,
+            cooldown: Duration::from_secs(3),
+        }
+    }
+}
+
+/// Remotely adjustable part of [`Tunables`], also the persisted form
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunableSettings {
+    pub max_on_time_secs: u32,
+    pub hysteresis: u32,
+    pub cooldown_secs: u32,
+}
+
+impl TunableSettings {
+    pub fn is_valid(&self) -> bool {
+        MAX_ON_TIME_SECS.contains(&self.max_on_time_secs)
+            && HYSTERESIS.contains(&self.hysteresis)
+            && COOLDOWN_SECS.contains(&self.cooldown_secs)
+    }
+
+    pub fn apply(&self, tunables: Tunables) -> Tunables {
+        Tunables {
+            max_on_time: Duration::from_secs(self.max_on_time_secs as u64),
+            hysteresis: self.hysteresis,
+            cooldown: Duration::from_secs(self.cooldown_secs as u64),
+            ..tunables
+        }
+    }
+}
+
+impl From<Tunables> for TunableSettings {
+    fn from(tunables: Tunables) -> Self {
+        TunableSettings {
+            max_on_time_secs: tunables.max_on_time.as_secs() as u32,
+            hysteresis: tunables.hysteresis,
+            cooldown_secs: tunables.cooldown.as_secs() as u32,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum State {
+    Idle,
+    /// Humidity is below the limit, waiting for the debounce to pass
+    Triggering {
+        below: u8,
+    },
+    /// Watering cycle is running
+    Watering(Cycle),
+    /// Cycle is over, the pump is locked until `remaining` expires
+    Cooldown {
+        remaining: Duration,
+    },
+    /// Button is held, `cycle` keeps the progress of an interrupted cycle
+    ManualOverride {
+        cycle: Option<Cycle>,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Cycle {
+    pub elapsed: Duration,
+    /// Consecutive samples above the stop threshold
+    pub clear: u8,
+    /// Scheduled cycle length, automatic cycles use `max_on_time` instead
+    pub fixed: Option<Duration>,
+}
+
+impl Cycle {
+    const fn new(fixed: Option<Duration>) -> Self {
+        Cycle {
+            elapsed: Duration::MIN,
+            clear: 0,
+            fixed,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Sample {
+    /// Current humidity in percent
+    pub humidity: u32,
+    /// Low humidity limit in percent
+    pub limit: u32,
+    /// Manual override button is pressed
+    pub button: bool,
+    /// Humidity-triggered watering is permitted by the schedule
+    pub allowed: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    Started { humidity: u32, limit: u32 },
+    EarlyStop { humidity: u32, limit: u32 },
+    MaxOnTime,
+    FixedFinished,
+    OverrideStarted,
+    OverrideFinished,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub pump: bool,
+    pub next_poll: Duration,
+    pub event: Option<Event>,
+}
+
+pub struct WateringController {
+    tunables: Tunables,
+    state: State,
+}
+
+impl WateringController {
+    pub const fn new(tunables: Tunables) -> Self {
+        WateringController {
+            tunables,
+            state: State::Idle,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn tunables(&self) -> Tunables {
+        self.tunables
+    }
+
+    pub fn set_tunables(&mut self, tunables: Tunables) {
+        self.tunables = tunables;
+    }
+
+    pub fn pump_on(&self) -> bool {
+        matches!(
+            self.state,
+            State::Watering(_) | State::ManualOverride { .. }
+        )
+    }
+
+    /// Start a scheduled cycle of fixed length
+    ///
+    /// Returns `false` if the pump is already busy or cooling down.
+    pub fn start_fixed(&mut self, duration: Duration) -> bool {
+        match self.state {
+            State::Idle | State::Triggering { .. } => {
+                self.state = State::Watering(Cycle::new(Some(duration)));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Abort a running cycle, the usual cooldown follows
+    ///
+    /// Returns `false` if there is no cycle or the button holds the pump.
+    pub fn stop(&mut self) -> bool {
+        match self.state {
+            State::Watering(_) => {
+                self.state = State::Cooldown {
+                    remaining: self.tunables.cooldown,
+                };
+                true
+            }
+            State::Triggering { .. } => {
+                self.state = State::Idle;
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Advance the state machine
+    ///
+    /// `elapsed` is the time passed since the previous call.
+    pub fn step(&mut self, sample: Sample, elapsed: Duration) -> Step {
+        let t = self.tunables;
+
+        if sample.button {
+            let (cycle, event) = match self.state {
+                State::Watering(cycle) => (
+                    Some(Cycle {
+                        elapsed: cycle.elapsed + elapsed,
+                        ..cycle
+                    }),
+                    Some(Event::OverrideStarted),
+                ),
+                // Keep counting the interrupted cycle time
+                State::ManualOverride { cycle } => (
+                    cycle.map(|cycle| Cycle {
+                        elapsed: cycle.elapsed + elapsed,
+                        ..cycle
+                    }),
+                    None,
+                ),
+                _ => (None, Some(Event::OverrideStarted)),
+            };
+            self.state = State::ManualOverride { cycle };
+            return self.output(t.poll_active, event);
+        }
+
+        let mut elapsed = elapsed;
+        let mut event = None;
+        loop {
+            match self.state {
+                State::ManualOverride { cycle: None } => {
+                    event = Some(Event::OverrideFinished);
+                    self.state = State::Idle;
+                    elapsed = Duration::MIN;
+                }
+                State::ManualOverride { cycle: Some(cycle) } => {
+                    event = Some(Event::OverrideFinished);
+                    self.state = State::Watering(Cycle { clear: 0, ..cycle });
+                    elapsed = Duration::MIN;
+                }
+                State::Idle | State::Triggering { .. } => {
+                    if !sample.allowed || sample.humidity >= sample.limit {
+                        self.state = State::Idle;
+                        return self.output(t.poll_idle, event);
+                    }
+
+                    let below = match self.state {
+                        State::Triggering { below } => below.saturating_add(1),
+                        _ => 1,
+                    };
+                    if below >= t.consecutive_triggers {
+                        self.state = State::Watering(Cycle::new(None));
+                        return self.output(
+                            t.poll_active,
+                            Some(Event::Started {
+                                humidity: sample.humidity,
+                                limit: sample.limit,
+                            }),
+                        );
+                    }
+                    self.state = State::Triggering { below };
+                    return self.output(t.poll_idle, event);
+                }
+                State::Watering(cycle) => {
+                    let done = cycle.elapsed + elapsed;
+                    if let Some(fixed) = cycle.fixed {
+                        if done >= fixed {
+                            self.state = State::Cooldown {
+                                remaining: t.cooldown,
+                            };
+                            return self.output(t.cooldown, Some(Event::FixedFinished));
+                        }
+                        self.state = State::Watering(Cycle {
+                            elapsed: done,
+                            ..cycle
+                        });
+                        return self.output(t.poll_active.min(fixed - done), event);
+                    }
+
+                    if done >= t.max_on_time {
+                        self.state = State::Cooldown {
+                            remaining: t.cooldown,
+                        };
+                        return self.output(t.cooldown, Some(Event::MaxOnTime));
+                    }
+
+                    let clear = if sample.humidity >= sample.limit.saturating_add(t.hysteresis) {
+                        cycle.clear.saturating_add(1)
+                    } else {
+                        0
+                    };
+                    if clear >= t.consecutive_clear {
+                        self.state = State::Cooldown {
+                            remaining: t.cooldown,
+                        };
+                        return self.output(
+                            t.cooldown,
+                            Some(Event::EarlyStop {
+                                humidity: sample.humidity,
+                                limit: sample.limit,
+                            }),
+                        );
+                    }
+
+                    self.state = State::Watering(Cycle {
+                        elapsed: done,
+                        clear,
+                        fixed: None,
+                    });
+                    return self.output(t.poll_active, event);
+                }
+                State::Cooldown { remaining } => match remaining.checked_sub(elapsed) {
+                    Some(remaining) if remaining > Duration::MIN => {
+                        self.state = State::Cooldown { remaining };
+                        return self.output(remaining, event);
+                    }
+                    _ => {
+                        self.state = State::Idle;
+                        elapsed = Duration::MIN;
+                    }
+                },
+            }
+        }
+    }
+
+    fn output(&self, next_poll: Duration, event: Option<Event>) -> Step {
+        Step {
+            pump: self.pump_on(),
+            next_poll,
+            event,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(250);
+
+    fn sample(humidity: u32) -> Sample {
+        Sample {
+            humidity,
+            limit: 40,
+            button: false,
+            allowed: true,
+        }
+    }
+
+    fn pressed() -> Sample {
+        Sample {
+            button: true,
+            ..sample(50)
+        }
+    }
+
+    /// A controller which just started an automatic cycle
+    fn watering() -> WateringController {
+        let mut controller = WateringController::new(Tunables::default());
+        assert_eq!(controller.step(sample(30), TICK).event, None);
+        let step = controller.step(sample(30), TICK);
+        assert_eq!(
+            step.event,
+            Some(Event::Started {
+                humidity: 30,
+                limit: 40
+            })
+        );
+        assert!(step.pump);
+        controller
+    }
+
+    #[test]
+    fn debounces_the_trigger() {
+        let mut controller = WateringController::new(Tunables::default());
+        controller.step(sample(30), TICK);
+        assert_eq!(controller.state(), State::Triggering { below: 1 });
+        // A single dry reading is not enough
+        controller.step(sample(45), TICK);
+        assert_eq!(controller.state(), State::Idle);
+        controller.step(sample(30), TICK);
+        assert_eq!(controller.state(), State::Triggering { below: 1 });
+
+        // Outside the schedule nothing starts
+        let mut controller = WateringController::new(Tunables::default());
+        let blocked = Sample {
+            allowed: false,
+            ..sample(10)
+        };
+        for _ in 0..5 {
+            assert!(!controller.step(blocked, TICK).pump);
+        }
+        assert_eq!(controller.state(), State::Idle);
+    }
+
+    #[test]
+    fn stops_early_once_wet() {
+        let mut controller = watering();
+        // Within the hysteresis the pump keeps running
+        for _ in 0..5 {
+            assert!(controller.step(sample(41), TICK).pump);
+        }
+        assert_eq!(controller.step(sample(42), TICK).event, None);
+        assert_eq!(controller.step(sample(42), TICK).event, None);
+        // A dip resets the debounce
+        controller.step(sample(38), TICK);
+        controller.step(sample(42), TICK);
+        controller.step(sample(42), TICK);
+        let step = controller.step(sample(42), TICK);
+        assert_eq!(
+            step.event,
+            Some(Event::EarlyStop {
+                humidity: 42,
+                limit: 40
+            })
+        );
+        assert!(!step.pump);
+        assert_eq!(step.next_poll, Tunables::default().cooldown);
+    }
+
+    #[test]
+    fn cuts_off_at_max_on_time() {
+        let tunables = Tunables::default();
+        let mut controller = watering();
+        let mut watered = Duration::MIN;
+        loop {
+            let step = controller.step(sample(10), TICK);
+            watered += TICK;
+            if step.event == Some(Event::MaxOnTime) {
+                assert!(!step.pump);
+                break;
+            }
+            assert!(step.pump && watered < tunables.max_on_time);
+        }
+        assert_eq!(watered, tunables.max_on_time);
+
+        // Still dry, but the cooldown has to pass first
+        let step = controller.step(sample(10), Duration::from_secs(1));
+        assert!(!step.pump);
+        assert_eq!(step.next_poll, Duration::from_secs(2));
+        controller.step(sample(10), Duration::from_secs(2));
+        assert_eq!(controller.state(), State::Triggering { below: 1 });
+    }
+
+    #[test]
+    fn button_overrides_mid_cycle() {
+        let tunables = Tunables::default();
+        let mut controller = watering();
+        controller.step(sample(10), Duration::from_secs(10));
+
+        let step = controller.step(pressed(), Duration::from_secs(5));
+        assert_eq!(step.event, Some(Event::OverrideStarted));
+        assert!(step.pump);
+        // Wet soil doesn't stop a held button
+        for _ in 0..10 {
+            let step = controller.step(pressed(), Duration::from_secs(1));
+            assert!(step.pump && step.event.is_none());
+        }
+        assert!(matches!(
+            controller.state(),
+            State::ManualOverride { cycle: Some(cycle) } if cycle.elapsed == Duration::from_secs(25)
+        ));
+
+        // Released, the cycle resumes with the held time counted
+        let step = controller.step(sample(10), TICK);
+        assert_eq!(step.event, Some(Event::OverrideFinished));
+        assert!(step.pump);
+        let step = controller.step(sample(10), Duration::from_secs(5));
+        assert_eq!(step.event, Some(Event::MaxOnTime));
+        assert!(!step.pump);
+        assert_eq!(
+            controller.state(),
+            State::Cooldown {
+                remaining: tunables.cooldown
+            }
+        );
+    }
+
+    #[test]
+    fn button_outside_a_cycle() {
+        let mut controller = WateringController::new(Tunables::default());
+        assert_eq!(
+            controller.step(pressed(), TICK).event,
+            Some(Event::OverrideStarted)
+        );
+        assert_eq!(controller.state(), State::ManualOverride { cycle: None });
+        let step = controller.step(sample(50), TICK);
+        assert_eq!(step.event, Some(Event::OverrideFinished));
+        assert!(!step.pump);
+        assert_eq!(controller.state(), State::Idle);
+    }
+
+    #[test]
+    fn runs_fixed_cycles() {
+        let mut controller = WateringController::new(Tunables::default());
+        assert!(controller.start_fixed(Duration::from_secs(1)));
+        assert!(!controller.start_fixed(Duration::from_secs(1)));
+        // Wet soil doesn't end a scheduled cycle
+        let step = controller.step(sample(90), Duration::from_millis(900));
+        assert!(step.pump);
+        assert_eq!(step.next_poll, Duration::from_millis(100));
+        let step = controller.step(sample(90), Duration::from_millis(100));
+        assert_eq!(step.event, Some(Event::FixedFinished));
+        assert!(!step.pump);
+        // No restart while cooling down
+        assert!(!controller.start_fixed(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn stop_aborts_cycles() {
+        let mut controller = watering();
+        assert!(controller.stop());
+        assert!(!controller.pump_on());
+        assert!(!controller.stop());
+
+        let mut controller = WateringController::new(Tunables::default());
+        controller.step(pressed(), TICK);
+        assert!(!controller.stop());
+        assert!(controller.pump_on());
+    }
+
+    #[test]
+    fn tunable_settings_ranges() {
+        let settings = TunableSettings::from(Tunables::default());
+        assert!(settings.is_valid());
+        assert_eq!(settings.apply(Tunables::default()), Tunables::default());
+        for invalid in [
+            TunableSettings {
+                max_on_time_secs: 0,
+                ..settings
+            },
+            TunableSettings {
+                max_on_time_secs: 601,
+                ..settings
+            },
+            TunableSettings {
+                hysteresis: 51,
+                ..settings
+            },
+            TunableSettings {
+                cooldown_secs: 3601,
+                ..settings
+            },
+        ] {
+            assert!(!invalid.is_valid());
+        }
+    }
+}
@@ -0,0 +1,10 @@
+pub mod controller;
+pub mod schedule;
+
+/// Pots on the board, each with its own sensor and pump
+///
+/// Fixed by the wiring rather than configurable: sensors on GPIO34 and
+/// GPIO35, pumps on GPIO25 and GPIO26. Every ADC pin has a type of its
+/// own, so another layout means listing its pins in `io::gpio` and in
+/// `main` as well.
+pub const BOARD_ZONES: usize = 2;
@@ -0,0 +1,330 @@
+//! Time-of-day watering windows
+//!
+//! A window starts at a local wall clock time on selected weekdays and either
+//! forces a fixed-duration watering or opens a period during which
+//! humidity-triggered watering is allowed. Without any `Allow` windows the
+//! humidity trigger works around the clock.
+
+use jiff::{Timestamp, ToSpan, civil::Date, tz::TimeZone};
+use serde::{Deserialize, Serialize};
+
+pub const MAX_WINDOWS: usize = 4;
+pub const MAX_FIXED_DURATION_SECS: u32 = 600;
+
+/// Weekday mask with every day of the week set, bit 0 is Monday
+pub const EVERY_DAY: u8 = 0x7f;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WindowMode {
+    /// Run the pump for `duration_secs` when the window starts
+    Fixed { duration_secs: u32 },
+    /// Allow humidity-triggered watering for `length_mins` after the start
+    Allow { length_mins: u16 },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Window {
+    pub hour: u8,
+    pub minute: u8,
+    pub weekdays: u8,
+    pub mode: WindowMode,
+}
+
+impl Window {
+    pub fn is_valid(&self) -> bool {
+        let mode_valid = match self.mode {
+            WindowMode::Fixed { duration_secs } => {
+                duration_secs > 0 && duration_secs <= MAX_FIXED_DURATION_SECS
+            }
+            WindowMode::Allow { length_mins } => length_mins > 0 && length_mins <= 24 * 60,
+        };
+        self.hour < 24 && self.minute < 60 && self.weekdays & EVERY_DAY != 0 && mode_valid
+    }
+
+    fn runs_on(&self, date: Date) -> bool {
+        let day = date.weekday().to_monday_zero_offset();
+        self.weekdays & (1 << day) != 0
+    }
+
+    /// Window start on the given local date, if the window runs on that day
+    fn start_on(&self, date: Date, tz: &TimeZone) -> Option<Timestamp> {
+        if !self.runs_on(date) {
+            return None;
+        }
+        let start = date.at(self.hour as i8, self.minute as i8, 0, 0);
+        start
+            .to_zoned(tz.clone())
+            .ok()
+            .map(|zoned| zoned.timestamp())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub windows: [Option<Window>; MAX_WINDOWS],
+}
+
+impl Schedule {
+    pub const fn new() -> Self {
+        Schedule {
+            windows: [None; MAX_WINDOWS],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.iter().all(Option::is_none)
+    }
+
+    fn active(&self) -> impl Iterator<Item = &Window> {
+        self.windows.iter().flatten()
+    }
+
+    /// Closest window start strictly after `now`
+    pub fn next_occurrence(&self, now: Timestamp, tz: &TimeZone) -> Option<Timestamp> {
+        let today = now.to_zoned(tz.clone()).date();
+        (0..=7)
+            .filter_map(|offset| today.checked_add(offset.days()).ok())
+            .flat_map(|date| self.active().filter_map(move |w| w.start_on(date, tz)))
+            .filter(|start| *start > now)
+            .min()
+    }
+
+    /// Whether humidity-triggered watering may start at `now`
+    pub fn humidity_allowed(&self, now: Timestamp, tz: &TimeZone) -> bool {
+        let mut restricted = false;
+        let today = now.to_zoned(tz.clone()).date();
+
+        for window in self.active() {
+            let WindowMode::Allow { length_mins } = window.mode else {
+                continue;
+            };
+            restricted = true;
+
+            // Windows may span midnight, so yesterday's start counts too
+            let open = [today.yesterday().ok(), Some(today)]
+                .into_iter()
+                .flatten()
+                .filter_map(|date| window.start_on(date, tz))
+                .any(|start| {
+                    start <= now
+                        && start
+                            .checked_add((length_mins as i64).minutes())
+                            .is_ok_and(|end| now < end)
+                });
+            if open {
+                return true;
+            }
+        }
+
+        !restricted
+    }
+
+    /// Fixed watering duration of a window that started within `(since, now]`
+    pub fn fixed_due(&self, since: Timestamp, now: Timestamp, tz: &TimeZone) -> Option<u32> {
+        let today = now.to_zoned(tz.clone()).date();
+
+        self.active()
+            .filter_map(|window| match window.mode {
+                WindowMode::Fixed { duration_secs } => Some((window, duration_secs)),
+                WindowMode::Allow { .. } => None,
+            })
+            .filter(|(window, _)| {
+                [today.yesterday().ok(), Some(today)]
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|date| window.start_on(date, tz))
+                    .any(|start| since < start && start <= now)
+            })
+            .map(|(_, duration_secs)| duration_secs)
+            .max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MONDAY: u8 = 1 << 0;
+    const SATURDAY: u8 = 1 << 5;
+    const WEEKDAYS: u8 = 0x1f;
+
+    fn at(timestamp: &str) -> Timestamp {
+        timestamp.parse().unwrap()
+    }
+
+    fn schedule(windows: &[Window]) -> Schedule {
+        let mut schedule = Schedule::new();
+        for (slot, window) in schedule.windows.iter_mut().zip(windows) {
+            *slot = Some(*window);
+        }
+        schedule
+    }
+
+    fn fixed(hour: u8, minute: u8, weekdays: u8) -> Window {
+        Window {
+            hour,
+            minute,
+            weekdays,
+            mode: WindowMode::Fixed { duration_secs: 120 },
+        }
+    }
+
+    fn berlin() -> TimeZone {
+        TimeZone::posix("CET-1CEST,M3.5.0,M10.5.0/3").unwrap()
+    }
+
+    /// Poll like the watering task, how often the window fired
+    fn runs(schedule: &Schedule, from: Timestamp, to: Timestamp, tz: &TimeZone) -> usize {
+        let mut since = from;
+        let mut runs = 0;
+        while since < to {
+            let now = since.checked_add(10.seconds()).unwrap();
+            runs += schedule.fixed_due(since, now, tz).iter().count();
+            since = now;
+        }
+        runs
+    }
+
+    #[test]
+    fn empty_schedule() {
+        let schedule = Schedule::new();
+        let now = at("2025-01-13T12:00:00Z");
+        assert!(schedule.is_empty());
+        assert_eq!(schedule.next_occurrence(now, &TimeZone::UTC), None);
+        assert_eq!(
+            schedule.fixed_due(at("2025-01-13T00:00:00Z"), now, &TimeZone::UTC),
+            None
+        );
+        assert!(schedule.humidity_allowed(now, &TimeZone::UTC));
+    }
+
+    #[test]
+    fn honours_weekday_masks() {
+        // A Friday morning, after the day's window
+        let friday = at("2025-01-10T08:00:00Z");
+        let weekdays = schedule(&[fixed(7, 0, WEEKDAYS)]);
+        assert_eq!(
+            weekdays.next_occurrence(friday, &TimeZone::UTC),
+            Some(at("2025-01-13T07:00:00Z"))
+        );
+        let saturdays = schedule(&[fixed(7, 0, SATURDAY)]);
+        assert_eq!(
+            saturdays.next_occurrence(friday, &TimeZone::UTC),
+            Some(at("2025-01-11T07:00:00Z"))
+        );
+        // The earliest of several windows wins
+        let both = schedule(&[fixed(7, 0, SATURDAY), fixed(6, 0, WEEKDAYS)]);
+        assert_eq!(
+            both.next_occurrence(friday, &TimeZone::UTC),
+            Some(at("2025-01-11T07:00:00Z"))
+        );
+        assert_eq!(
+            saturdays.fixed_due(
+                at("2025-01-10T06:59:00Z"),
+                at("2025-01-10T07:01:00Z"),
+                &TimeZone::UTC
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn allow_window_crosses_midnight() {
+        let night = schedule(&[Window {
+            hour: 22,
+            minute: 0,
+            weekdays: MONDAY,
+            mode: WindowMode::Allow { length_mins: 240 },
+        }]);
+        let allowed = |timestamp| night.humidity_allowed(at(timestamp), &TimeZone::UTC);
+        assert!(!allowed("2025-01-13T21:59:59Z"));
+        assert!(allowed("2025-01-13T22:00:00Z"));
+        assert!(allowed("2025-01-13T23:30:00Z"));
+        // Tuesday is not in the mask, Monday's window still runs into it
+        assert!(allowed("2025-01-14T01:59:59Z"));
+        assert!(!allowed("2025-01-14T02:00:00Z"));
+        assert!(!allowed("2025-01-14T22:30:00Z"));
+        assert!(!allowed("2025-01-15T01:00:00Z"));
+    }
+
+    #[test]
+    fn fixed_windows_only_leave_humidity_alone() {
+        let morning = schedule(&[fixed(7, 0, WEEKDAYS)]);
+        assert!(morning.humidity_allowed(at("2025-01-11T03:00:00Z"), &TimeZone::UTC));
+    }
+
+    #[test]
+    fn fixed_fires_once_per_start() {
+        let morning = schedule(&[fixed(6, 30, EVERY_DAY)]);
+        let utc = &TimeZone::UTC;
+        assert_eq!(
+            morning.fixed_due(at("2025-01-13T06:29:59Z"), at("2025-01-13T06:30:00Z"), utc),
+            Some(120)
+        );
+        // Later polls in the same minute don't start it again
+        assert_eq!(
+            morning.fixed_due(at("2025-01-13T06:30:00Z"), at("2025-01-13T06:30:59Z"), utc),
+            None
+        );
+        assert_eq!(
+            runs(
+                &morning,
+                at("2025-01-13T06:00:00Z"),
+                at("2025-01-13T07:00:00Z"),
+                utc
+            ),
+            1
+        );
+        // Started just before midnight, polled just after
+        let late = schedule(&[fixed(23, 59, MONDAY)]);
+        assert_eq!(
+            late.fixed_due(at("2025-01-13T23:58:30Z"), at("2025-01-14T00:00:30Z"), utc),
+            Some(120)
+        );
+    }
+
+    #[test]
+    fn dst_gap_moves_the_start() {
+        // 02:30 doesn't exist on 2025-03-30, it runs at 03:30 CEST instead
+        let night = schedule(&[fixed(2, 30, EVERY_DAY)]);
+        let tz = berlin();
+        assert_eq!(
+            night.next_occurrence(at("2025-03-30T00:00:00Z"), &tz),
+            Some(at("2025-03-30T01:30:00Z"))
+        );
+        assert_eq!(
+            runs(
+                &night,
+                at("2025-03-29T23:00:00Z"),
+                at("2025-03-30T03:00:00Z"),
+                &tz
+            ),
+            1
+        );
+    }
+
+    #[test]
+    fn dst_overlap_runs_once() {
+        // 02:30 happens twice on 2025-10-26, only the first one counts
+        let night = schedule(&[fixed(2, 30, EVERY_DAY)]);
+        let tz = berlin();
+        assert_eq!(
+            night.next_occurrence(at("2025-10-25T23:00:00Z"), &tz),
+            Some(at("2025-10-26T00:30:00Z"))
+        );
+        assert_eq!(
+            runs(
+                &night,
+                at("2025-10-25T23:00:00Z"),
+                at("2025-10-26T03:00:00Z"),
+                &tz
+            ),
+            1
+        );
+        // The next one is a day later, not an hour
+        assert_eq!(
+            night.next_occurrence(at("2025-10-26T00:30:00Z"), &tz),
+            Some(at("2025-10-27T01:30:00Z"))
+        );
+    }
+}